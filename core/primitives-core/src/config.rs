@@ -100,6 +100,30 @@ impl VMConfig {
         s.finish()
     }
 
+    /// Like [`Self::non_crypto_hash`], but only covers the parameters which affect how contracts
+    /// are prepared and compiled, so that it can be used as a key for compiled contracts.
+    ///
+    /// The codegen-relevant parameters are:
+    /// * whether `regular_op_cost` is zero, which disables gas metering (the cost itself is applied
+    ///   at runtime);
+    /// * `grow_mem_cost`, which is injected by gas metering;
+    /// * `limit_config.max_stack_height`, which is injected by stack height metering;
+    /// * `limit_config.initial_memory_pages` and `limit_config.max_memory_pages`, which are
+    ///   checked against the memory import;
+    /// * `limit_config.max_functions_number_per_contract`, which decides whether preparation fails.
+    ///
+    /// Everything else, including all of `ext_costs`, is only used at runtime.
+    pub fn codegen_non_crypto_hash(&self) -> u64 {
+        let mut s = DefaultHasher::new();
+        (self.regular_op_cost == 0).hash(&mut s);
+        self.grow_mem_cost.hash(&mut s);
+        self.limit_config.max_stack_height.hash(&mut s);
+        self.limit_config.initial_memory_pages.hash(&mut s);
+        self.limit_config.max_memory_pages.hash(&mut s);
+        self.limit_config.max_functions_number_per_contract.hash(&mut s);
+        s.finish()
+    }
+
     pub fn free() -> Self {
         Self {
             ext_costs: ExtCostsConfig::free(),
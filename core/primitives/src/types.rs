@@ -939,6 +939,56 @@ pub enum TransactionOrReceiptId {
 pub trait CompiledContractCache: Send + Sync {
     fn put(&self, key: &[u8], value: &[u8]) -> Result<(), std::io::Error>;
     fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error>;
+
+    /// Removes the value stored under `key`, if any.
+    ///
+    /// The default implementation does nothing, so stale entries are only dropped by backends
+    /// which support removal.
+    fn remove(&self, _key: &[u8]) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    /// Checks whether a value is stored under `key`.
+    ///
+    /// Backends which can answer this without loading the value should override the default
+    /// implementation.
+    fn contains_key(&self, key: &[u8]) -> Result<bool, std::io::Error> {
+        self.get(key).map(|value| value.is_some())
+    }
+
+    /// Stores `value` under `key` unless a value is already stored there, and returns whether
+    /// `value` was stored.
+    ///
+    /// The default implementation checks and writes non-atomically, so backends which can do
+    /// better should override it.
+    fn put_if_absent(&self, key: &[u8], value: &[u8]) -> Result<bool, std::io::Error> {
+        if self.contains_key(key)? {
+            return Ok(false);
+        }
+        self.put(key, value)?;
+        Ok(true)
+    }
+
+    /// Returns the values stored under `keys`, in the same order.
+    ///
+    /// The default implementation calls `get` for every key, backends which can fetch many values
+    /// at once, e.g. in a single network round trip, should override it.
+    fn get_many(&self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>, std::io::Error> {
+        keys.iter().map(|key| self.get(key)).collect()
+    }
+
+    /// Returns the number of stored entries, or `None` if the backend can't count them cheaply.
+    fn approximate_len(&self) -> Option<usize> {
+        None
+    }
+
+    /// Records that the value stored under `key` was just used, for backends which evict the
+    /// least recently used entries. Called after every cache hit.
+    ///
+    /// The default implementation does nothing.
+    fn touch(&self, _key: &[u8]) -> Result<(), std::io::Error> {
+        Ok(())
+    }
 }
 
 /// Provides information about current epoch validators.
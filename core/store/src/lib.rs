@@ -520,6 +520,16 @@ impl CompiledContractCache for StoreCompiledContractCache {
     fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error> {
         self.store.get(DBCol::ColCachedContractCode, key)
     }
+
+    fn remove(&self, key: &[u8]) -> Result<(), std::io::Error> {
+        let mut store_update = self.store.store_update();
+        store_update.delete(DBCol::ColCachedContractCode, key);
+        store_update.commit()
+    }
+
+    fn contains_key(&self, key: &[u8]) -> Result<bool, std::io::Error> {
+        self.store.exists(DBCol::ColCachedContractCode, key)
+    }
 }
 
 #[cfg(test)]
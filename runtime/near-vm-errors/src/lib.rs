@@ -73,6 +73,14 @@ pub enum CacheError {
     WriteError,
     DeserializationError,
     SerializationError { hash: [u8; 32] },
+    UnsupportedRecordVersion { found: u8 },
+    UnsupportedRecordFormat { found: u8 },
+    ChecksumMismatch,
+    MissingCachedArtifact,
+    RecordTooLarge { len: u64, max: u64 },
+    VmHashMismatch { expected: u64, found: u64 },
+    /// A compilation error is cached where a compiled module was expected.
+    CachedCompilationError,
 }
 /// A kind of a trap happened during execution of a binary
 #[cfg_attr(feature = "deepsize_feature", derive(deepsize::DeepSizeOf))]
@@ -268,6 +276,12 @@ impl From<PrepareError> for VMError {
     }
 }
 
+impl From<CacheError> for VMError {
+    fn from(err: CacheError) -> Self {
+        VMError::CacheError(err)
+    }
+}
+
 impl From<VMLogicError> for VMError {
     fn from(err: VMLogicError) -> Self {
         match err {
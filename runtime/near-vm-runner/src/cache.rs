@@ -1,18 +1,51 @@
-use crate::errors::ContractPrecompilatonResult;
+use crate::errors::{ContractPrecompilatonError, ContractPrecompilatonResult};
+use crate::metrics;
 use crate::prepare;
 use crate::vm_kind::VMKind;
-use crate::wasmer2_runner::{default_wasmer2_store, wasmer2_vm_hash};
+#[cfg(feature = "wasmer2_vm")]
+use crate::wasmer2_runner::{
+    default_wasmer2_store, wasmer2_store_for_target, wasmer2_store_vm_hash, wasmer2_target_vm_hash,
+    wasmer2_vm_hash,
+};
+#[cfg(feature = "wasmer0_vm")]
 use crate::wasmer_runner::wasmer0_vm_hash;
-use crate::wasmtime_runner::wasmtime_vm_hash;
+#[cfg(feature = "wasmtime_vm")]
+use crate::wasmtime_runner::{default_wasmtime_engine, wasmtime_vm_hash};
+#[cfg(feature = "async_cache")]
+use async_trait::async_trait;
 use borsh::{BorshDeserialize, BorshSerialize};
 use near_primitives::contract::ContractCode;
 use near_primitives::hash::CryptoHash;
 use near_primitives::types::CompiledContractCache;
 use near_vm_errors::{CacheError, CompilationError, FunctionCallError, VMError};
 use near_vm_logic::{ProtocolVersion, VMConfig};
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
 use std::fmt;
-use std::sync::{Arc, Mutex};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use threadpool::ThreadPool;
+
+// Stand-ins for the types of VMs which aren't compiled in. They are uninhabited, so that the
+// in-memory caches and the precompilation paths keep their shape in every build, but never hold
+// anything for those VMs.
+#[cfg(feature = "wasmer0_vm")]
+type Wasmer0Module = wasmer_runtime::Module;
+#[cfg(not(feature = "wasmer0_vm"))]
+type Wasmer0Module = std::convert::Infallible;
+#[cfg(feature = "wasmer2_vm")]
+type Wasmer2Module = wasmer::Module;
+#[cfg(not(feature = "wasmer2_vm"))]
+type Wasmer2Module = std::convert::Infallible;
+#[cfg(feature = "wasmer2_vm")]
+type Wasmer2Store = wasmer::Store;
+#[cfg(not(feature = "wasmer2_vm"))]
+type Wasmer2Store = std::convert::Infallible;
 
 #[derive(Debug, Clone, BorshSerialize)]
 enum ContractCacheKey {
@@ -25,403 +58,4475 @@ enum ContractCacheKey {
         vm_kind: VMKind,
         vm_hash: u64,
     },
+    /// Like `Version4`, but for caches in a non-default namespace.
+    Version5 {
+        namespace: String,
+        code_hash: CryptoHash,
+        vm_config_non_crypto_hash: u64,
+        vm_kind: VMKind,
+        vm_hash: u64,
+    },
+    /// Like `Version5`, but scoped to the protocol version which produced the artifact.
+    Version6 {
+        namespace: String,
+        protocol_version: ProtocolVersion,
+        code_hash: CryptoHash,
+        vm_config_non_crypto_hash: u64,
+        vm_kind: VMKind,
+        vm_hash: u64,
+    },
+    /// Like `Version6`, but in a non-default cache epoch, see [`CacheOptions::epoch`].
+    Version7 {
+        epoch: u32,
+        namespace: String,
+        protocol_version: Option<ProtocolVersion>,
+        code_hash: CryptoHash,
+        vm_config_non_crypto_hash: u64,
+        vm_kind: VMKind,
+        vm_hash: u64,
+    },
+}
+
+/// Compression algorithm applied to serialized modules in the persistent cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, BorshDeserialize, BorshSerialize)]
+pub enum CompressionAlgo {
+    Zstd,
 }
 
 #[derive(Debug, Clone, BorshDeserialize, BorshSerialize)]
-enum CacheRecord {
+pub(crate) enum CacheRecord {
     CompileModuleError(CompilationError),
     Code(Vec<u8>),
+    CompressedCode {
+        algo: CompressionAlgo,
+        data: Vec<u8>,
+    },
+    /// Like `CompileModuleError`, but treated as absent once `expires_at_unix` has passed, so
+    /// that transient failures are eventually retried.
+    ErrorWithExpiry {
+        error: CompilationError,
+        expires_at_unix: u64,
+    },
+    /// A serialized module, compressed with `compression` if set, along with the
+    /// [`current_vm_hash`] of the VM which produced it.
+    CodeForVm {
+        vm_hash: u64,
+        compression: Option<CompressionAlgo>,
+        data: Vec<u8>,
+    },
 }
 
-fn vm_hash(vm_kind: VMKind) -> u64 {
-    match vm_kind {
-        VMKind::Wasmer0 => wasmer0_vm_hash(),
-        VMKind::Wasmer2 => wasmer2_vm_hash(),
-        VMKind::Wasmtime => wasmtime_vm_hash(),
-    }
-}
-
-pub fn get_contract_cache_key(
-    code: &ContractCode,
-    vm_kind: VMKind,
-    config: &VMConfig,
-) -> CryptoHash {
-    let _span = tracing::debug_span!(target: "vm", "get_key").entered();
-    let key = ContractCacheKey::Version4 {
-        code_hash: *code.hash(),
-        vm_config_non_crypto_hash: config.non_crypto_hash(),
-        vm_kind,
-        vm_hash: vm_hash(vm_kind),
-    };
-    near_primitives::hash::hash(&key.try_to_vec().unwrap())
-}
+/// Borsh tag of `CacheRecord::ErrorWithExpiry`, used to check for expired records without
+/// deserializing whole artifacts.
+const ERROR_WITH_EXPIRY_TAG: u8 = 3;
 
-fn cache_error(
-    error: &CompilationError,
-    key: &CryptoHash,
-    cache: &dyn CompiledContractCache,
-) -> Result<(), CacheError> {
-    let record = CacheRecord::CompileModuleError(error.clone());
-    let record = record.try_to_vec().unwrap();
-    cache.put(&key.0, &record).map_err(|_io_err| CacheError::ReadError)?;
-    Ok(())
-}
+/// Encoding of the [`CacheRecord`] envelope. Serialized modules inside records are opaque bytes
+/// to it.
+pub(crate) trait RecordCodec {
+    /// Written in front of every record encoded with this codec, to pick the codec on read.
+    const FORMAT: u8;
 
-pub fn into_vm_result<T>(
-    res: Result<Result<T, CompilationError>, CacheError>,
-) -> Result<T, VMError> {
-    match res {
-        Ok(Ok(it)) => Ok(it),
-        Ok(Err(err)) => Err(VMError::FunctionCallError(FunctionCallError::CompilationError(err))),
-        Err(cache_error) => Err(VMError::CacheError(cache_error)),
-    }
+    fn encode(record: &CacheRecord, out: &mut Vec<u8>);
+    fn decode(payload: &[u8]) -> Result<CacheRecord, CacheError>;
 }
 
-#[derive(Default)]
-pub struct MockCompiledContractCache {
-    store: Arc<Mutex<HashMap<Vec<u8>, Vec<u8>>>>,
-}
+/// The default [`RecordCodec`], and the only encoding of records written before codecs were
+/// introduced.
+pub(crate) struct BorshCodec;
 
-impl MockCompiledContractCache {
-    pub fn len(&self) -> usize {
-        self.store.lock().unwrap().len()
-    }
-}
+impl RecordCodec for BorshCodec {
+    const FORMAT: u8 = 0;
 
-impl CompiledContractCache for MockCompiledContractCache {
-    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), std::io::Error> {
-        self.store.lock().unwrap().insert(key.to_vec(), value.to_vec());
-        Ok(())
+    fn encode(record: &CacheRecord, out: &mut Vec<u8>) {
+        BorshSerialize::serialize(record, out).unwrap();
     }
 
-    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error> {
-        let res = self.store.lock().unwrap().get(key).cloned();
-        Ok(res)
+    fn decode(payload: &[u8]) -> Result<CacheRecord, CacheError> {
+        CacheRecord::try_from_slice(payload).map_err(|_e| CacheError::DeserializationError)
     }
 }
 
-impl fmt::Debug for MockCompiledContractCache {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let guard = self.store.lock().unwrap();
-        let hm: &HashMap<_, _> = &*guard;
-        fmt::Debug::fmt(hm, f)
-    }
-}
+/// Version byte of borsh records written without a checksum.
+const CACHE_RECORD_VERSION_UNCHECKED: u8 = 0x80;
 
-#[cfg(not(feature = "no_cache"))]
-const CACHE_SIZE: usize = 128;
+/// Version byte of borsh records, followed by a little-endian CRC32 of the record.
+const CACHE_RECORD_VERSION_CHECKED: u8 = 0x81;
 
-#[cfg(not(feature = "no_cache"))]
-pub static WASMER_CACHE: once_cell::sync::Lazy<
-    near_cache::SyncLruCache<
-        CryptoHash,
-        Result<Result<wasmer_runtime::Module, CompilationError>, CacheError>,
-    >,
-> = once_cell::sync::Lazy::new(|| near_cache::SyncLruCache::new(CACHE_SIZE));
+/// Version byte of records with a little-endian CRC32 of the rest, the [`RecordCodec::FORMAT`]
+/// byte and the encoded record.
+const CACHE_RECORD_VERSION_WITH_FORMAT: u8 = 0x82;
 
-#[cfg(not(feature = "no_cache"))]
-pub static WASMER2_CACHE: once_cell::sync::Lazy<
-    near_cache::SyncLruCache<
-        CryptoHash,
-        Result<Result<wasmer::Module, CompilationError>, CacheError>,
-    >,
-> = once_cell::sync::Lazy::new(|| near_cache::SyncLruCache::new(CACHE_SIZE));
+/// Version byte written in front of every [`CacheRecord`], followed by a little-endian CRC32 of
+/// the format byte and the record, the little-endian unix time the record was written to a cache
+/// at, zero if it wasn't, the [`RecordCodec::FORMAT`] byte and the encoded record. Leaving the
+/// time out of the checksum keeps the checksum of a record a function of its contents.
+///
+/// Records written before versioning was introduced start directly with the borsh tag of their
+/// variant, which is always smaller, and are still read, as are records with older versions.
+/// Larger versions come from newer nodes and are rejected with
+/// `CacheError::UnsupportedRecordVersion`, see [`supported_record_versions`].
+const CACHE_RECORD_VERSION: u8 = 0x83;
 
-#[cfg(feature = "wasmer0_vm")]
-pub mod wasmer0_cache {
-    use super::*;
-    use near_vm_errors::CompilationError;
-    use wasmer_runtime::{compiler_for_backend, Backend};
-    use wasmer_runtime_core::cache::Artifact;
-    use wasmer_runtime_core::load_cache_with;
+/// Returns the version bytes of the record envelopes this node can read, oldest first, for
+/// diagnosing `CacheError::UnsupportedRecordVersion`. Records written before versioning was
+/// introduced carry no version byte and are always readable.
+pub fn supported_record_versions() -> &'static [u8] {
+    &[
+        CACHE_RECORD_VERSION_UNCHECKED,
+        CACHE_RECORD_VERSION_CHECKED,
+        CACHE_RECORD_VERSION_WITH_FORMAT,
+        CACHE_RECORD_VERSION,
+    ]
+}
 
-    pub(crate) fn compile_module(
-        code: &[u8],
-        config: &VMConfig,
-    ) -> Result<wasmer_runtime::Module, CompilationError> {
-        let _span = tracing::debug_span!(target: "vm", "compile_module").entered();
+/// A serialized record taken apart, see [`CacheRecord::split`].
+struct RawRecord<'a> {
+    /// Checksum of `checked`, if the record has one.
+    checksum: Option<u32>,
+    checked: &'a [u8],
+    format: u8,
+    /// Unset for records which weren't written to a cache, or before creation times were
+    /// recorded.
+    created_at_unix: Option<u64>,
+    payload: &'a [u8],
+}
 
-        let prepared_code =
-            prepare::prepare_contract(code, config).map_err(CompilationError::PrepareError)?;
-        wasmer_runtime::compile(&prepared_code).map_err(|err| match err {
-            wasmer_runtime::error::CompileError::ValidationError { .. } => {
-                CompilationError::WasmerCompileError { msg: err.to_string() }
-            }
-            // NOTE: Despite the `InternalError` name, this failure occurs if
-            // the input `code` is invalid wasm.
-            wasmer_runtime::error::CompileError::InternalError { .. } => {
-                CompilationError::WasmerCompileError { msg: err.to_string() }
-            }
-        })
+impl CacheRecord {
+    /// Serializes the record with the default codec, prefixed with [`CACHE_RECORD_VERSION`] and
+    /// a checksum. The same record always serializes to the same bytes.
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        self.to_bytes_with::<BorshCodec>(None)
     }
 
-    pub(crate) fn compile_and_serialize_wasmer(
-        wasm_code: &[u8],
-        config: &VMConfig,
-        key: &CryptoHash,
-        cache: &dyn CompiledContractCache,
-    ) -> Result<Result<wasmer_runtime::Module, CompilationError>, CacheError> {
-        let _span = tracing::debug_span!(target: "vm", "compile_and_serialize_wasmer").entered();
-
-        let module = match compile_module(wasm_code, config) {
-            Ok(module) => module,
-            Err(err) => {
-                cache_error(&err, key, cache)?;
-                return Ok(Err(err));
-            }
-        };
-
-        let code = module
-            .cache()
-            .and_then(|it| it.serialize())
-            .map_err(|_e| CacheError::SerializationError { hash: key.0 })?;
-        let serialized = CacheRecord::Code(code).try_to_vec().unwrap();
-        cache.put(key.as_ref(), &serialized).map_err(|_io_err| CacheError::WriteError)?;
-        Ok(Ok(module))
+    /// Like [`CacheRecord::to_bytes`], but for a record about to be written to a cache, which
+    /// stores the current time for [`record_age`].
+    pub(crate) fn to_cached_bytes(&self) -> Vec<u8> {
+        self.to_bytes_with::<BorshCodec>(Some(unix_now()))
     }
 
-    /// Deserializes contract or error from the binary data. Signature means that we could either
-    /// return module or cached error, which both considered to be `Ok()`, or encounter an error during
-    /// the deserialization process.
-    fn deserialize_wasmer(
-        serialized: &[u8],
-    ) -> Result<Result<wasmer_runtime::Module, CompilationError>, CacheError> {
-        let _span = tracing::debug_span!(target: "vm", "deserialize_wasmer").entered();
+    /// Like [`CacheRecord::to_bytes`], but encodes the record with `C` and stores
+    /// `created_at_unix`, if set, as its creation time.
+    pub(crate) fn to_bytes_with<C: RecordCodec>(&self, created_at_unix: Option<u64>) -> Vec<u8> {
+        let mut res = vec![CACHE_RECORD_VERSION, 0, 0, 0, 0];
+        res.extend_from_slice(&created_at_unix.unwrap_or(0).to_le_bytes());
+        res.push(C::FORMAT);
+        C::encode(self, &mut res);
+        let checksum = crc32fast::hash(&res[13..]);
+        res[1..5].copy_from_slice(&checksum.to_le_bytes());
+        res
+    }
 
-        let record = CacheRecord::try_from_slice(serialized)
-            .map_err(|_e| CacheError::DeserializationError)?;
-        let serialized_artifact = match record {
-            CacheRecord::CompileModuleError(err) => return Ok(Err(err)),
-            CacheRecord::Code(code) => code,
-        };
-        let artifact = Artifact::deserialize(serialized_artifact.as_slice())
-            .map_err(|_e| CacheError::DeserializationError)?;
-        unsafe {
-            let compiler = compiler_for_backend(Backend::Singlepass).unwrap();
-            match load_cache_with(artifact, compiler.as_ref()) {
-                Ok(module) => Ok(Ok(module)),
-                Err(_) => Err(CacheError::DeserializationError),
+    /// Deserializes a record written by [`CacheRecord::to_bytes`], or by an older version. Records
+    /// whose checksum doesn't match fail with `CacheError::ChecksumMismatch`. Callers reading from
+    /// a cache are expected to bound the length first, see [`check_record_len`].
+    pub(crate) fn from_bytes(serialized: &[u8]) -> Result<CacheRecord, CacheError> {
+        let raw = CacheRecord::split(serialized)?;
+        if let Some(checksum) = raw.checksum {
+            if crc32fast::hash(raw.checked) != checksum {
+                return Err(CacheError::ChecksumMismatch);
             }
         }
+        match raw.format {
+            BorshCodec::FORMAT => BorshCodec::decode(raw.payload),
+            found => Err(CacheError::UnsupportedRecordFormat { found }),
+        }
     }
 
-    fn compile_module_cached_wasmer_impl(
-        key: CryptoHash,
-        wasm_code: &[u8],
-        config: &VMConfig,
-        cache: Option<&dyn CompiledContractCache>,
-    ) -> Result<Result<wasmer_runtime::Module, CompilationError>, CacheError> {
-        match cache {
-            None => Ok(compile_module(wasm_code, config)),
-            Some(cache) => {
-                let serialized = cache.get(&key.0).map_err(|_io_err| CacheError::ReadError)?;
-                match serialized {
-                    Some(serialized) => deserialize_wasmer(serialized.as_slice()),
-                    None => compile_and_serialize_wasmer(wasm_code, config, &key, cache),
+    /// Splits a serialized record into its checksum, format and encoded payload.
+    fn split(serialized: &[u8]) -> Result<RawRecord<'_>, CacheError> {
+        let borsh = |checksum, payload| RawRecord {
+            checksum,
+            checked: payload,
+            format: BorshCodec::FORMAT,
+            created_at_unix: None,
+            payload,
+        };
+        match serialized.first() {
+            Some(&CACHE_RECORD_VERSION) => {
+                if serialized.len() < 14 {
+                    return Err(CacheError::DeserializationError);
+                }
+                let created_at_unix = u64::from_le_bytes(serialized[5..13].try_into().unwrap());
+                Ok(RawRecord {
+                    checksum: Some(u32::from_le_bytes(serialized[1..5].try_into().unwrap())),
+                    checked: &serialized[13..],
+                    format: serialized[13],
+                    created_at_unix: Some(created_at_unix).filter(|&it| it != 0),
+                    payload: &serialized[14..],
+                })
+            }
+            Some(&CACHE_RECORD_VERSION_WITH_FORMAT) => {
+                if serialized.len() < 6 {
+                    return Err(CacheError::DeserializationError);
+                }
+                Ok(RawRecord {
+                    checksum: Some(u32::from_le_bytes(serialized[1..5].try_into().unwrap())),
+                    checked: &serialized[5..],
+                    format: serialized[5],
+                    created_at_unix: None,
+                    payload: &serialized[6..],
+                })
+            }
+            Some(&CACHE_RECORD_VERSION_CHECKED) => {
+                if serialized.len() < 5 {
+                    return Err(CacheError::DeserializationError);
                 }
+                let checksum = u32::from_le_bytes(serialized[1..5].try_into().unwrap());
+                Ok(borsh(Some(checksum), &serialized[5..]))
+            }
+            Some(&CACHE_RECORD_VERSION_UNCHECKED) => Ok(borsh(None, &serialized[1..])),
+            Some(&found) if found > CACHE_RECORD_VERSION => {
+                tracing::debug!(
+                    target: "vm",
+                    "Unsupported cache record version {:#x}, supported versions: {:x?}",
+                    found,
+                    supported_record_versions()
+                );
+                Err(CacheError::UnsupportedRecordVersion { found })
             }
+            _ => Ok(borsh(None, serialized)),
         }
     }
 
-    #[cfg(not(feature = "no_cache"))]
-    fn memcache_compile_module_cached_wasmer(
-        key: CryptoHash,
-        code: &ContractCode,
-        config: &VMConfig,
-        cache: Option<&dyn CompiledContractCache>,
-    ) -> Result<Result<wasmer_runtime::Module, CompilationError>, CacheError> {
-        WASMER_CACHE.get_or_put(key, |key| {
-            compile_module_cached_wasmer_impl(*key, code.code(), config, cache)
-        })
+    /// Wraps a serialized module into a record, compressing it with `compression` if set.
+    pub(crate) fn code(code: Vec<u8>, compression: Option<CompressionAlgo>) -> CacheRecord {
+        match compression {
+            None => CacheRecord::Code(code),
+            Some(algo) => CacheRecord::CompressedCode { algo, data: compress(algo, &code) },
+        }
     }
 
-    pub(crate) fn compile_module_cached_wasmer0(
-        code: &ContractCode,
-        config: &VMConfig,
-        cache: Option<&dyn CompiledContractCache>,
-    ) -> Result<Result<wasmer_runtime::Module, CompilationError>, CacheError> {
-        let key = get_contract_cache_key(code, VMKind::Wasmer0, config);
-        #[cfg(not(feature = "no_cache"))]
-        return memcache_compile_module_cached_wasmer(key, code, config, cache);
-        #[cfg(feature = "no_cache")]
-        return compile_module_cached_wasmer_impl(key, code.code(), config, cache);
+    /// Like [`CacheRecord::code`], but for a module produced by the current version of `vm_kind`,
+    /// so that it is never loaded by another one, see [`CacheRecord::into_code_for`].
+    pub(crate) fn code_for_vm(
+        code: Vec<u8>,
+        compression: Option<CompressionAlgo>,
+        vm_kind: VMKind,
+    ) -> CacheRecord {
+        CacheRecord::code_for_vm_hash(code, compression, current_vm_hash(vm_kind))
     }
-}
 
-#[cfg(feature = "wasmer2_vm")]
-pub mod wasmer2_cache {
-    use near_primitives::contract::ContractCode;
+    /// Like [`CacheRecord::code_for_vm`], but for a module produced by the VM whose hash is
+    /// `vm_hash`, which needn't be the current one.
+    pub(crate) fn code_for_vm_hash(
+        code: Vec<u8>,
+        compression: Option<CompressionAlgo>,
+        vm_hash: u64,
+    ) -> CacheRecord {
+        let data = match compression {
+            None => code,
+            Some(algo) => compress(algo, &code),
+        };
+        CacheRecord::CodeForVm { vm_hash, compression, data }
+    }
 
-    use super::*;
+    /// Wraps a compilation error into a record, which expires after `ttl` if set, see
+    /// [`CacheOptions::error_record_ttl`].
+    pub(crate) fn error(error: CompilationError, ttl: Option<Duration>) -> CacheRecord {
+        match ttl {
+            None => CacheRecord::CompileModuleError(error),
+            Some(ttl) => CacheRecord::ErrorWithExpiry {
+                error,
+                expires_at_unix: unix_now() + ttl.as_secs().max(1),
+            },
+        }
+    }
 
-    fn compile_module_wasmer2(
-        code: &[u8],
-        config: &VMConfig,
-        store: &wasmer::Store,
-    ) -> Result<wasmer::Module, CompilationError> {
-        let _span = tracing::debug_span!(target: "vm", "compile_module_wasmer2").entered();
+    /// Returns when the record was written to a cache, if it was written by a version which
+    /// recorded it. The checksum isn't verified.
+    fn created_at_unix(serialized: &[u8]) -> Option<u64> {
+        CacheRecord::split(serialized).ok()?.created_at_unix
+    }
 
-        let prepared_code =
-            prepare::prepare_contract(code, config).map_err(CompilationError::PrepareError)?;
-        wasmer::Module::new(store, prepared_code).map_err(|err| match err {
-            wasmer::CompileError::Wasm(_) => {
-                CompilationError::WasmerCompileError { msg: err.to_string() }
-            }
-            wasmer::CompileError::Codegen(_) => {
-                CompilationError::WasmerCompileError { msg: err.to_string() }
-            }
-            wasmer::CompileError::Validate(_) => {
-                CompilationError::WasmerCompileError { msg: err.to_string() }
-            }
-            wasmer::CompileError::UnsupportedFeature(_) => {
-                CompilationError::WasmerCompileError { msg: err.to_string() }
-            }
-            wasmer::CompileError::UnsupportedTarget(_) => {
-                CompilationError::WasmerCompileError { msg: err.to_string() }
-            }
-            wasmer::CompileError::Resource(_) => {
-                CompilationError::WasmerCompileError { msg: err.to_string() }
+    /// Whether `serialized` is an error record whose expiry time has passed.
+    fn is_expired(serialized: &[u8]) -> bool {
+        // The checksum isn't verified here, an expired record gets overwritten anyway.
+        if check_record_len(serialized.len(), DEFAULT_MAX_RECORD_BYTES).is_err() {
+            return false;
+        }
+        let body = match CacheRecord::split(serialized) {
+            Ok(raw) if raw.format == BorshCodec::FORMAT => raw.payload,
+            _ => return false,
+        };
+        if body.first() != Some(&ERROR_WITH_EXPIRY_TAG) {
+            return false;
+        }
+        match BorshCodec::decode(body) {
+            Ok(CacheRecord::ErrorWithExpiry { expires_at_unix, .. }) => {
+                expires_at_unix <= unix_now()
             }
-        })
+            _ => false,
+        }
     }
 
-    pub(crate) fn compile_and_serialize_wasmer2(
-        wasm_code: &[u8],
-        key: &CryptoHash,
-        config: &VMConfig,
-        cache: &dyn CompiledContractCache,
-        store: &wasmer::Store,
-    ) -> Result<Result<wasmer::Module, CompilationError>, CacheError> {
-        let _span = tracing::debug_span!(target: "vm", "compile_and_serialize_wasmer2").entered();
+    /// Whether `serialized` decodes to a cached compilation error, expired or not.
+    fn is_error(serialized: &[u8]) -> bool {
+        matches!(
+            CacheRecord::from_bytes(serialized),
+            Ok(CacheRecord::CompileModuleError(_) | CacheRecord::ErrorWithExpiry { .. })
+        )
+    }
 
-        let module = match compile_module_wasmer2(wasm_code, config, store) {
-            Ok(module) => module,
-            Err(err) => {
-                cache_error(&err, key, cache)?;
-                return Ok(Err(err));
+    /// Returns the serialized module stored in the record, or the cached compilation error.
+    pub(crate) fn into_code(self) -> Result<Result<Vec<u8>, CompilationError>, CacheError> {
+        match self {
+            CacheRecord::CompileModuleError(err)
+            | CacheRecord::ErrorWithExpiry { error: err, .. } => Ok(Err(err)),
+            CacheRecord::Code(code)
+            | CacheRecord::CodeForVm { compression: None, data: code, .. } => Ok(Ok(code)),
+            CacheRecord::CompressedCode { algo, data }
+            | CacheRecord::CodeForVm { compression: Some(algo), data, .. } => {
+                Ok(Ok(decompress(algo, &data)?))
             }
-        };
-
-        let code =
-            module.serialize().map_err(|_e| CacheError::SerializationError { hash: key.0 })?;
-        let serialized = CacheRecord::Code(code).try_to_vec().unwrap();
-        cache.put(key.as_ref(), &serialized).map_err(|_io_err| CacheError::WriteError)?;
-        Ok(Ok(module))
+        }
     }
 
-    fn deserialize_wasmer2(
-        serialized: &[u8],
-        store: &wasmer::Store,
-    ) -> Result<Result<wasmer::Module, CompilationError>, CacheError> {
-        let _span = tracing::debug_span!(target: "vm", "deserialize_wasmer2").entered();
-
-        let record = CacheRecord::try_from_slice(serialized)
-            .map_err(|_e| CacheError::DeserializationError)?;
-        let serialized_module = match record {
-            CacheRecord::CompileModuleError(err) => return Ok(Err(err)),
-            CacheRecord::Code(code) => code,
-        };
-        unsafe {
-            Ok(Ok(wasmer::Module::deserialize(store, serialized_module.as_slice())
-                .map_err(|_e| CacheError::DeserializationError)?))
-        }
+    /// Like [`CacheRecord::into_code`], but fails with `CacheError::VmHashMismatch` if the module
+    /// was produced by another version of `vm_kind` than the current one, as loading it would be
+    /// unsafe. Records written before the VM hash was recorded are trusted.
+    pub(crate) fn into_code_for(
+        self,
+        vm_kind: VMKind,
+    ) -> Result<Result<Vec<u8>, CompilationError>, CacheError> {
+        self.into_code_for_vm_hash(current_vm_hash(vm_kind))
     }
 
-    fn compile_module_cached_wasmer2_impl(
-        key: CryptoHash,
-        code: &ContractCode,
-        config: &VMConfig,
-        cache: Option<&dyn CompiledContractCache>,
-        store: &wasmer::Store,
-    ) -> Result<Result<wasmer::Module, CompilationError>, CacheError> {
-        match cache {
-            None => Ok(compile_module_wasmer2(code.code(), config, store)),
-            Some(cache) => {
-                let serialized = cache.get(&key.0).map_err(|_io_err| CacheError::WriteError)?;
-                match serialized {
-                    Some(serialized) => deserialize_wasmer2(serialized.as_slice(), store),
-                    None => compile_and_serialize_wasmer2(code.code(), &key, config, cache, store),
-                }
+    /// Like [`CacheRecord::into_code_for`], but expects the module to be tagged with `expected`,
+    /// which needn't be the hash of the current VM.
+    pub(crate) fn into_code_for_vm_hash(
+        self,
+        expected: u64,
+    ) -> Result<Result<Vec<u8>, CompilationError>, CacheError> {
+        if let CacheRecord::CodeForVm { vm_hash: found, .. } = self {
+            if found != expected {
+                return Err(CacheError::VmHashMismatch { expected, found });
             }
         }
+        self.into_code()
     }
+}
 
-    #[cfg(not(feature = "no_cache"))]
-    fn memcache_compile_module_cached_wasmer2(
-        key: CryptoHash,
-        code: &ContractCode,
-        config: &VMConfig,
-        cache: Option<&dyn CompiledContractCache>,
-        store: &wasmer::Store,
-    ) -> Result<Result<wasmer::Module, CompilationError>, CacheError> {
-        WASMER2_CACHE.get_or_put(key, |key| {
-            compile_module_cached_wasmer2_impl(*key, code, config, cache, store)
-        })
+fn compress(algo: CompressionAlgo, code: &[u8]) -> Vec<u8> {
+    match algo {
+        CompressionAlgo::Zstd => zstd::encode_all(code, zstd::DEFAULT_COMPRESSION_LEVEL)
+            .expect("in-memory compression cannot fail"),
     }
+}
 
-    pub(crate) fn compile_module_cached_wasmer2(
-        code: &ContractCode,
-        config: &VMConfig,
-        cache: Option<&dyn CompiledContractCache>,
-        store: &wasmer::Store,
-    ) -> Result<Result<wasmer::Module, CompilationError>, CacheError> {
-        let key = get_contract_cache_key(code, VMKind::Wasmer2, config);
-        #[cfg(not(feature = "no_cache"))]
-        return memcache_compile_module_cached_wasmer2(key, code, config, cache, store);
-        #[cfg(feature = "no_cache")]
-        return compile_module_cached_wasmer2_impl(key, code, config, cache, store);
+fn decompress(algo: CompressionAlgo, data: &[u8]) -> Result<Vec<u8>, CacheError> {
+    match algo {
+        CompressionAlgo::Zstd => {
+            zstd::decode_all(data).map_err(|_e| CacheError::DeserializationError)
+        }
     }
 }
 
-pub fn precompile_contract_vm(
+/// Runs `compile` on the thread pool of `semaphore` once a turn to compile is free, giving up with
+/// `ContractPrecompilatonError::Timeout` if it doesn't finish within `timeout` of starting.
+///
+/// A running compilation can't be interrupted: one which timed out still runs to completion in
+/// the background, holding its thread and its turn until then, and its outcome is discarded.
+pub(crate) fn compile_with_timeout<T: Send + 'static>(
+    semaphore: &Arc<CompileSemaphore>,
+    timeout: Duration,
+    compile: impl FnOnce() -> T + Send + 'static,
+) -> Result<T, ContractPrecompilatonError> {
+    let permit = semaphore.acquire();
+    let (tx, rx) = channel();
+    semaphore.pool().execute(move || {
+        let _permit = permit;
+        // The receiver is gone if the compilation timed out.
+        let _ = tx.send(compile());
+    });
+    match rx.recv_timeout(timeout) {
+        Ok(res) => Ok(res),
+        Err(RecvTimeoutError::Timeout) => {
+            Err(ContractPrecompilatonError::Timeout { timeout_ms: timeout.as_millis() as u64 })
+        }
+        // The sender is only dropped without sending if `compile` panicked.
+        Err(RecvTimeoutError::Disconnected) => Err(ContractPrecompilatonError::CompilationPanicked),
+    }
+}
+
+/// Bounds the number of contracts compiled with wasmer2 at the same time, so that a burst of
+/// deploys can't run out of memory. Further compilations wait for one to finish, which doesn't
+/// count toward [`CacheOptions::compile_timeout`]. Shared through
+/// [`CacheOptions::compile_permits`].
+pub struct CompileSemaphore {
+    limit: AtomicUsize,
+    in_use: Mutex<usize>,
+    released: Condvar,
+    /// Threads running compilations with a timeout, as many as there are turns to compile, so
+    /// that a compilation which took a turn never waits for a thread. Started on first use.
+    pool: once_cell::sync::OnceCell<Mutex<ThreadPool>>,
+}
+
+impl CompileSemaphore {
+    /// Allows `limit` compilations at the same time; `0` is treated as `1`.
+    pub fn new(limit: usize) -> CompileSemaphore {
+        CompileSemaphore {
+            limit: AtomicUsize::new(limit.max(1)),
+            in_use: Mutex::new(0),
+            released: Condvar::new(),
+            pool: once_cell::sync::OnceCell::new(),
+        }
+    }
+
+    /// Changes how many compilations may run at the same time; `0` is treated as `1`.
+    pub fn set_limit(&self, limit: usize) {
+        // Under the lock, so that no waiter misses the notification.
+        let _in_use = self.in_use.lock().unwrap();
+        self.limit.store(limit.max(1), Ordering::Relaxed);
+        if let Some(pool) = self.pool.get() {
+            pool.lock().unwrap().set_num_threads(limit.max(1));
+        }
+        self.released.notify_all();
+    }
+
+    /// Blocks until fewer than `limit` permits are held, then takes one until the returned
+    /// permit is dropped.
+    pub(crate) fn acquire(self: &Arc<Self>) -> CompilePermit {
+        let mut in_use = self.in_use.lock().unwrap();
+        while *in_use >= self.limit.load(Ordering::Relaxed) {
+            in_use = self.released.wait(in_use).unwrap();
+        }
+        *in_use += 1;
+        CompilePermit { semaphore: Arc::clone(self) }
+    }
+
+    fn pool(&self) -> std::sync::MutexGuard<'_, ThreadPool> {
+        let pool = self.pool.get_or_init(|| {
+            let limit = self.limit.load(Ordering::Relaxed);
+            Mutex::new(ThreadPool::with_name("compile_with_timeout".to_string(), limit))
+        });
+        pool.lock().unwrap()
+    }
+}
+
+pub(crate) struct CompilePermit {
+    semaphore: Arc<CompileSemaphore>,
+}
+
+impl Drop for CompilePermit {
+    fn drop(&mut self) {
+        *self.semaphore.in_use.lock().unwrap() -= 1;
+        self.semaphore.released.notify_one();
+    }
+}
+
+impl fmt::Debug for CompileSemaphore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CompileSemaphore")
+            .field("limit", &self.limit.load(Ordering::Relaxed))
+            .finish_non_exhaustive()
+    }
+}
+
+/// Semaphores are only equal to themselves, as they hand out distinct turns.
+impl PartialEq for CompileSemaphore {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl Eq for CompileSemaphore {}
+
+/// The default [`CacheOptions::compile_permits`], shared by the whole process.
+static COMPILE_PERMITS: once_cell::sync::Lazy<Arc<CompileSemaphore>> =
+    once_cell::sync::Lazy::new(|| Arc::new(CompileSemaphore::new(num_cpus::get())));
+
+/// Records longer than this are rejected before deserialization, unless changed with
+/// [`CacheOptions::max_record_bytes`]. Far larger than any artifact compiled from a contract within
+/// the protocol's code size limit.
+pub const DEFAULT_MAX_RECORD_BYTES: usize = 256 * 1024 * 1024;
+
+/// Fails with `CacheError::RecordTooLarge` if a record of `len` bytes is over `max`.
+fn check_record_len(len: usize, max: usize) -> Result<(), CacheError> {
+    if len > max {
+        return Err(CacheError::RecordTooLarge { len: len as u64, max: max as u64 });
+    }
+    Ok(())
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |it| it.as_secs())
+}
+
+/// Returns the hash of the given VM's build, as used in contract cache keys. Tools which prime a
+/// shared cache can use it to produce keys compatible with this node.
+///
+/// VMs which aren't compiled into this build have no hash, zero is returned for them. Tools built
+/// without VMs should use [`get_contract_cache_key_with_vm_hash`] instead.
+pub fn current_vm_hash(vm_kind: VMKind) -> u64 {
+    match vm_kind {
+        #[cfg(feature = "wasmer0_vm")]
+        VMKind::Wasmer0 => wasmer0_vm_hash(),
+        #[cfg(feature = "wasmer2_vm")]
+        VMKind::Wasmer2 => wasmer2_vm_hash(),
+        #[cfg(feature = "wasmtime_vm")]
+        VMKind::Wasmtime => wasmtime_vm_hash(),
+        #[allow(unreachable_patterns)] // reachable when some of the VMs are disabled.
+        _ => 0,
+    }
+}
+
+/// Returns the VMs compiled into this build, with their names and [`current_vm_hash`]es, e.g. for
+/// a CLI to let operators confirm what their build supports.
+pub fn available_vm_kinds() -> Vec<(VMKind, &'static str, u64)> {
+    #[allow(unused_mut)]
+    let mut vm_kinds = Vec::new();
+    #[cfg(feature = "wasmer0_vm")]
+    vm_kinds.push((VMKind::Wasmer0, "wasmer0", wasmer0_vm_hash()));
+    #[cfg(feature = "wasmer2_vm")]
+    vm_kinds.push((VMKind::Wasmer2, "wasmer2", wasmer2_vm_hash()));
+    #[cfg(feature = "wasmtime_vm")]
+    vm_kinds.push((VMKind::Wasmtime, "wasmtime", wasmtime_vm_hash()));
+    vm_kinds
+}
+
+/// The inputs hashed into a contract cache key, see [`describe_contract_cache_key`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContractCacheKeyDescription {
+    pub code_hash: CryptoHash,
+    /// Hash of the codegen-relevant part of the config, see [`VMConfig::codegen_non_crypto_hash`].
+    pub vm_config_non_crypto_hash: u64,
+    pub vm_kind: VMKind,
+    pub vm_hash: u64,
+    /// Empty for the default namespace.
+    pub namespace: String,
+    /// Set only for keys scoped to a protocol version, see
+    /// [`get_protocol_versioned_contract_cache_key`].
+    pub protocol_version: Option<ProtocolVersion>,
+    /// Zero for the default epoch, see [`CacheOptions::epoch`].
+    pub epoch: u32,
+}
+
+/// Returns the components [`get_contract_cache_key`] hashes for the given contract, so that keys
+/// computed on different nodes can be compared field by field.
+pub fn describe_contract_cache_key(
+    code: &ContractCode,
+    vm_kind: VMKind,
+    config: &VMConfig,
+) -> ContractCacheKeyDescription {
+    describe_contract_cache_key_for_hash(*code.hash(), vm_kind, config, current_vm_hash(vm_kind))
+}
+
+fn describe_contract_cache_key_for_hash(
+    code_hash: CryptoHash,
+    vm_kind: VMKind,
+    config: &VMConfig,
+    vm_hash: u64,
+) -> ContractCacheKeyDescription {
+    ContractCacheKeyDescription {
+        code_hash,
+        vm_config_non_crypto_hash: vm_config_cache_component(config),
+        vm_kind,
+        vm_hash,
+        namespace: String::new(),
+        protocol_version: None,
+        epoch: 0,
+    }
+}
+
+/// Returns the hash of `config` which enters the cache key, i.e. the
+/// [`ContractCacheKeyDescription::vm_config_non_crypto_hash`]. It only covers the parameters which
+/// affect compilation, see [`VMConfig::codegen_non_crypto_hash`].
+///
+/// When two nodes compute different keys for the same contract, comparing this value tells
+/// whether their configs are to blame.
+pub fn vm_config_cache_component(config: &VMConfig) -> u64 {
+    config.codegen_non_crypto_hash()
+}
+
+/// Derives the cache key from the borsh-serialized key components.
+pub trait CacheKeyHasher {
+    fn hash(&self, serialized_key: &[u8]) -> CryptoHash;
+}
+
+/// The default [`CacheKeyHasher`], which uses the cryptographic [`near_primitives::hash::hash`].
+/// Keys of the persistent cache must always be derived with it.
+pub struct CryptoCacheKeyHasher;
+
+impl CacheKeyHasher for CryptoCacheKeyHasher {
+    fn hash(&self, serialized_key: &[u8]) -> CryptoHash {
+        near_primitives::hash::hash(serialized_key)
+    }
+}
+
+pub fn get_contract_cache_key(
+    code: &ContractCode,
+    vm_kind: VMKind,
+    config: &VMConfig,
+) -> CryptoHash {
+    get_contract_cache_key_with_hasher(code, vm_kind, config, &CryptoCacheKeyHasher)
+}
+
+/// Like [`get_contract_cache_key`], but takes the hash of the contract rather than its code, e.g.
+/// to check whether a contract is cached without loading its code from the state.
+pub fn get_contract_cache_key_from_hash(
+    code_hash: CryptoHash,
+    vm_kind: VMKind,
+    config: &VMConfig,
+) -> CryptoHash {
+    let description =
+        describe_contract_cache_key_for_hash(code_hash, vm_kind, config, current_vm_hash(vm_kind));
+    hash_contract_cache_key(description, &CryptoCacheKeyHasher)
+}
+
+/// Like [`get_contract_cache_key`], but uses the given `vm_hash` rather than the one of the VM
+/// built into this binary, see [`current_vm_hash`]. Tools which only derive keys, e.g. to analyze
+/// a cache dump, can thus do without the VM backends.
+pub fn get_contract_cache_key_with_vm_hash(
+    code: &ContractCode,
+    vm_kind: VMKind,
+    config: &VMConfig,
+    vm_hash: u64,
+) -> CryptoHash {
+    let description = describe_contract_cache_key_for_hash(*code.hash(), vm_kind, config, vm_hash);
+    hash_contract_cache_key(description, &CryptoCacheKeyHasher)
+}
+
+/// Returns the key under which precompiling with [`PrecompileOptions::target`] caches the wasmer2
+/// artifact of the contract compiled for `target`. Keys differ between targets, and for the target
+/// of a node, it is the [`get_contract_cache_key`] the node looks the contract up with.
+#[cfg(feature = "wasmer2_vm")]
+pub fn get_contract_cache_key_for_target(
+    code: &ContractCode,
+    config: &VMConfig,
+    target: &wasmer::Target,
+) -> CryptoHash {
+    let vm_hash = wasmer2_target_vm_hash(target);
+    get_contract_cache_key_with_vm_hash(code, VMKind::Wasmer2, config, vm_hash)
+}
+
+/// Returns the [`get_contract_cache_key`] of every contract in `codes`, in the same order, e.g. for
+/// all the versions of a contract an account deployed over time.
+pub fn cache_keys_for_codes(
+    codes: &[ContractCode],
+    vm_kind: VMKind,
+    config: &VMConfig,
+) -> Vec<CryptoHash> {
+    codes.iter().map(|code| get_contract_cache_key(code, vm_kind, config)).collect()
+}
+
+/// Like [`get_contract_cache_key`], but hashes the key components with `hasher`. Useful for
+/// in-memory caches, where a faster non-cryptographic hash is good enough.
+pub fn get_contract_cache_key_with_hasher(
+    code: &ContractCode,
+    vm_kind: VMKind,
+    config: &VMConfig,
+    hasher: &dyn CacheKeyHasher,
+) -> CryptoHash {
+    let _span = tracing::debug_span!(target: "vm", "get_key").entered();
+    hash_contract_cache_key(describe_contract_cache_key(code, vm_kind, config), hasher)
+}
+
+/// Like [`get_contract_cache_key`], but in `namespace` rather than the default, empty, one, see
+/// [`CacheOptions::namespace`].
+pub fn get_namespaced_contract_cache_key(
+    code: &ContractCode,
+    vm_kind: VMKind,
+    config: &VMConfig,
+    namespace: &str,
+) -> CryptoHash {
+    let description = ContractCacheKeyDescription {
+        namespace: namespace.to_string(),
+        ..describe_contract_cache_key(code, vm_kind, config)
+    };
+    hash_contract_cache_key(description, &CryptoCacheKeyHasher)
+}
+
+/// Like [`get_contract_cache_key`], but in the namespace and epoch of `options`. The functions
+/// taking [`CacheOptions`] store and look up contracts under this key.
+pub fn get_contract_cache_key_with_options(
+    code: &ContractCode,
+    vm_kind: VMKind,
+    config: &VMConfig,
+    options: &CacheOptions,
+) -> CryptoHash {
+    let description = ContractCacheKeyDescription {
+        namespace: options.namespace.clone(),
+        epoch: options.epoch,
+        ..describe_contract_cache_key(code, vm_kind, config)
+    };
+    hash_contract_cache_key(description, &CryptoCacheKeyHasher)
+}
+
+/// Like [`get_contract_cache_key`], but the key is also scoped to `protocol_version`, so that an
+/// artifact is never shared between protocol versions, even if they only differ in semantics
+/// not captured by the [`VMConfig`]. Such keys have to be used consistently for both writing and
+/// looking up contracts.
+pub fn get_protocol_versioned_contract_cache_key(
+    code: &ContractCode,
+    vm_kind: VMKind,
+    config: &VMConfig,
+    protocol_version: ProtocolVersion,
+) -> CryptoHash {
+    let description = ContractCacheKeyDescription {
+        protocol_version: Some(protocol_version),
+        ..describe_contract_cache_key(code, vm_kind, config)
+    };
+    hash_contract_cache_key(description, &CryptoCacheKeyHasher)
+}
+
+fn hash_contract_cache_key(
+    description: ContractCacheKeyDescription,
+    hasher: &dyn CacheKeyHasher,
+) -> CryptoHash {
+    let ContractCacheKeyDescription {
+        code_hash,
+        vm_config_non_crypto_hash,
+        vm_kind,
+        vm_hash,
+        ref namespace,
+        protocol_version,
+        epoch,
+    } = description;
+    // Log every component separately, so that keys computed by different nodes can be compared.
+    tracing::debug!(
+        target: "vm",
+        %code_hash,
+        vm_config_non_crypto_hash,
+        ?vm_kind,
+        vm_hash,
+        namespace = namespace.as_str(),
+        ?protocol_version,
+        epoch,
+        "computing contract cache key"
+    );
+    hasher.hash(&contract_cache_key_bytes(&description))
+}
+
+/// Returns the borsh-serialized cache key with the given components, which is what gets hashed
+/// into the contract cache key. Must stay stable across releases, or all cached contracts are
+/// recompiled.
+pub fn contract_cache_key_bytes(description: &ContractCacheKeyDescription) -> Vec<u8> {
+    let ContractCacheKeyDescription {
+        code_hash,
+        vm_config_non_crypto_hash,
+        vm_kind,
+        vm_hash,
+        ref namespace,
+        protocol_version,
+        epoch,
+    } = *description;
+    let key = match protocol_version {
+        _ if epoch != 0 => ContractCacheKey::Version7 {
+            epoch,
+            namespace: namespace.clone(),
+            protocol_version,
+            code_hash,
+            vm_config_non_crypto_hash,
+            vm_kind,
+            vm_hash,
+        },
+        None if namespace.is_empty() => {
+            ContractCacheKey::Version4 { code_hash, vm_config_non_crypto_hash, vm_kind, vm_hash }
+        }
+        None => ContractCacheKey::Version5 {
+            namespace: namespace.clone(),
+            code_hash,
+            vm_config_non_crypto_hash,
+            vm_kind,
+            vm_hash,
+        },
+        Some(protocol_version) => ContractCacheKey::Version6 {
+            namespace: namespace.clone(),
+            protocol_version,
+            code_hash,
+            vm_config_non_crypto_hash,
+            vm_kind,
+            vm_hash,
+        },
+    };
+    key.try_to_vec().unwrap()
+}
+
+/// What to do when reading a contract from the persistent cache fails, see
+/// [`CacheOptions::on_read_error`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadErrorPolicy {
+    /// Fail with `CacheError::ReadError`.
+    Fail,
+    /// Compile the contract from source, without writing it to the cache.
+    RecompileUncached,
+}
+
+impl Default for ReadErrorPolicy {
+    fn default() -> Self {
+        ReadErrorPolicy::Fail
+    }
+}
+
+/// How contracts are loaded from and written to a persistent [`CompiledContractCache`]. The
+/// options are passed along with the cache, so that caches used side by side in one process can
+/// be handled differently. Running contracts with [`crate::run`] uses the defaults.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheOptions {
+    /// Compile contracts whose cached artifact fails to deserialize again from source, and
+    /// overwrite the record, instead of failing with `CacheError::DeserializationError` or
+    /// `CacheError::ChecksumMismatch`. Disabled by default.
+    pub recompile_on_deserialization_failure: bool,
+    /// Cache compilation errors, in memory and in the persistent cache, so that a contract which
+    /// fails to compile isn't compiled again.
+    /// Enabled by default. When disabled, such a contract is compiled again on every use, so that
+    /// transient failures, e.g. due to resource exhaustion, don't stick.
+    pub cache_compilation_errors: bool,
+    /// Never compile wasmer2 contracts: one missing from both the in-memory and the persistent
+    /// cache is reported as `CacheError::MissingCachedArtifact`. For deployments where contracts
+    /// must only be compiled during a controlled precompilation phase. Disabled by default.
+    pub require_cached: bool,
+    /// Records read from the persistent cache which are longer than this are rejected with
+    /// `CacheError::RecordTooLarge` before deserialization, so that a misbehaving store can't make
+    /// us run out of memory. [`DEFAULT_MAX_RECORD_BYTES`] by default.
+    pub max_record_bytes: usize,
+    /// What happens when loading a wasmer2 module finds the persistent cache unreadable, e.g.
+    /// because of a transient failure of its backing store. [`ReadErrorPolicy::Fail`] by default.
+    pub on_read_error: ReadErrorPolicy,
+    /// Compiled wasmer2 records longer than this aren't written to the persistent cache, so that
+    /// huge artifacts don't bloat a constrained cache. Such contracts are compiled again whenever
+    /// they are missing from the in-memory cache, and precompiling them reports
+    /// [`ContractPrecompilatonResult::SkippedTooLarge`]. `None`, the default, caches everything.
+    pub max_cache_artifact_bytes: Option<usize>,
+    /// Index in which the VM hash of every record written to the persistent cache is recorded, so
+    /// that [`gc_stale_records`] can find them later. `None`, the default, indexes nothing.
+    pub vm_hash_index: Option<Arc<VmHashIndex>>,
+    /// Check every wasmer2 artifact loaded from the persistent cache against a fresh compilation
+    /// of the contract, to catch cache key bugs where the stored artifact silently diverges from
+    /// what the contract compiles to. Mismatches are logged as errors, counted in
+    /// [`CacheStats::inconsistencies`] and replaced with the fresh compilation. Disabled by
+    /// default.
+    ///
+    /// This compiles every contract on every load, so it is only meant for test and staging nodes.
+    pub verify_cache_consistency: bool,
+    /// Rewrite records written in an older format in the current one when they are loaded, so that
+    /// a format change gets migrated gradually by normal traffic. Disabled by default.
+    pub upgrade_records_on_read: bool,
+    /// Namespace folded into the cache keys, so that several logical caches can share one store
+    /// without collisions. Keys in the default, empty, namespace are the same as before namespaces
+    /// were introduced.
+    pub namespace: String,
+    /// Epoch folded into the cache keys. Bumping it invalidates every cached artifact at once, e.g.
+    /// after a miscompilation was discovered, without changing the VM or its config. Keys in the
+    /// default epoch, zero, are the same as before epochs were introduced.
+    pub epoch: u32,
+    /// For how long compilation errors written to the persistent cache are honored. After that the
+    /// contract is compiled again. `None`, the default, caches errors forever.
+    pub error_record_ttl: Option<Duration>,
+    /// Turns to compile contracts with wasmer2. Callers sharing a semaphore don't compile more
+    /// contracts at the same time than its limit. By default, a semaphore shared by the whole
+    /// process, which allows as many compilations as there are CPUs.
+    pub compile_permits: Arc<CompileSemaphore>,
+    /// How long precompiling a contract with wasmer2 may take before it is abandoned with
+    /// `ContractPrecompilatonError::Timeout`. `None`, the default, waits however long it takes.
+    ///
+    /// Whether a contract times out depends on the hardware, so the timeout is never cached, and
+    /// never applies to contracts compiled to be executed: nodes must agree on the outcome of
+    /// function calls. A compilation which timed out can't be interrupted: it keeps running in the
+    /// background, holding its turn to compile, see [`CacheOptions::compile_permits`], until it
+    /// finishes.
+    pub compile_timeout: Option<Duration>,
+    /// Compression applied to modules written to the persistent cache, `None` by default.
+    ///
+    /// Compressed records are decompressed transparently on read regardless of this setting, so it
+    /// can be changed without invalidating existing cache entries.
+    pub artifact_compression: Option<CompressionAlgo>,
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        CacheOptions {
+            recompile_on_deserialization_failure: false,
+            cache_compilation_errors: true,
+            require_cached: false,
+            max_record_bytes: DEFAULT_MAX_RECORD_BYTES,
+            on_read_error: ReadErrorPolicy::default(),
+            max_cache_artifact_bytes: None,
+            vm_hash_index: None,
+            verify_cache_consistency: false,
+            upgrade_records_on_read: false,
+            namespace: String::new(),
+            epoch: 0,
+            error_record_ttl: None,
+            compile_permits: Arc::clone(&COMPILE_PERMITS),
+            compile_timeout: None,
+            artifact_compression: None,
+        }
+    }
+}
+
+/// Falls back to `recompile` if `deserialized` failed to deserialize and
+/// [`CacheOptions::recompile_on_deserialization_failure`] is set. The broken record is removed
+/// first, so that it gets replaced.
+fn or_recompile<M, E: From<CacheError>>(
+    deserialized: Result<Result<M, CompilationError>, CacheError>,
+    key: &CryptoHash,
+    cache: &dyn CompiledContractCache,
+    options: &CacheOptions,
+    recompile: impl FnOnce() -> Result<Result<M, CompilationError>, E>,
+) -> Result<Result<M, CompilationError>, E> {
+    match deserialized {
+        // Unlike a corrupted record, one produced by another VM version is always replaced.
+        Err(err @ CacheError::VmHashMismatch { .. }) => {
+            tracing::warn!(
+                target: "vm",
+                "Cached contract {} was compiled by another VM version, recompiling: {:?}",
+                key,
+                err
+            );
+            cache.remove(&key.0).map_err(|_io_err| CacheError::WriteError)?;
+            recompile()
+        }
+        Err(err @ (CacheError::DeserializationError | CacheError::ChecksumMismatch))
+            if options.recompile_on_deserialization_failure =>
+        {
+            tracing::warn!(
+                target: "vm",
+                "Failed to deserialize cached contract {}, recompiling: {:?}",
+                key,
+                err
+            );
+            cache.remove(&key.0).map_err(|_io_err| CacheError::WriteError)?;
+            recompile()
+        }
+        res => Ok(res?),
+    }
+}
+
+/// Rewrites `serialized`, a record which was just loaded from under `key`, in the current format
+/// if it is in an older one and [`CacheOptions::upgrade_records_on_read`] is set. A failed rewrite
+/// is only logged, the record gets upgraded on a later read.
+fn upgrade_outdated_record(
+    key: &CryptoHash,
+    cache: &dyn CompiledContractCache,
+    options: &CacheOptions,
+    serialized: &[u8],
+) {
+    if !options.upgrade_records_on_read || serialized.first() == Some(&CACHE_RECORD_VERSION) {
+        return;
+    }
+    let record = match CacheRecord::from_bytes(serialized) {
+        Ok(record) => record,
+        Err(_) => return,
+    };
+    if let Err(err) = cache.put(&key.0, &record.to_cached_bytes()) {
+        tracing::debug!(target: "vm", "Failed to upgrade cache record {}: {}", key, err);
+    }
+}
+
+fn cache_error(
+    error: &CompilationError,
+    key: &CryptoHash,
+    vm_kind: VMKind,
+    cache: &dyn CompiledContractCache,
+    options: &CacheOptions,
+) -> Result<(), CacheError> {
+    if !options.cache_compilation_errors {
+        return Ok(());
+    }
+    let record = CacheRecord::error(error.clone(), options.error_record_ttl).to_cached_bytes();
+    cache.put(&key.0, &record).map_err(|_io_err| CacheError::WriteError)?;
+    index_vm_hash(key, vm_kind, options);
+    Ok(())
+}
+
+/// Snapshot of the process-wide compiled contract cache counters, see [`cache_stats`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Number of times a compiled module was served without compiling it, i.e. the sum of
+    /// `memory_hits` and `persistent_hits`.
+    pub hits: u64,
+    /// Number of times a compiled module was served from the in-memory module cache.
+    pub memory_hits: u64,
+    /// Number of times a compiled module was deserialized from the persistent cache.
+    pub persistent_hits: u64,
+    /// Number of times a contract had to be compiled.
+    pub misses: u64,
+    /// Number of failed reads from the persistent cache.
+    pub errors: u64,
+    /// Number of cached artifacts which differed from a fresh compilation of the contract. Only
+    /// checked with [`CacheOptions::verify_cache_consistency`].
+    pub inconsistencies: u64,
+}
+
+struct CacheCounters {
+    memory_hits: AtomicU64,
+    persistent_hits: AtomicU64,
+    misses: AtomicU64,
+    errors: AtomicU64,
+    inconsistencies: AtomicU64,
+}
+
+static CACHE_COUNTERS: CacheCounters = CacheCounters {
+    memory_hits: AtomicU64::new(0),
+    persistent_hits: AtomicU64::new(0),
+    misses: AtomicU64::new(0),
+    errors: AtomicU64::new(0),
+    inconsistencies: AtomicU64::new(0),
+};
+
+/// Returns the number of cache hits, misses and errors since process start, across all VMs and
+/// both the in-memory and the persistent caches.
+pub fn cache_stats() -> CacheStats {
+    let memory_hits = CACHE_COUNTERS.memory_hits.load(Ordering::Relaxed);
+    let persistent_hits = CACHE_COUNTERS.persistent_hits.load(Ordering::Relaxed);
+    CacheStats {
+        hits: memory_hits + persistent_hits,
+        memory_hits,
+        persistent_hits,
+        misses: CACHE_COUNTERS.misses.load(Ordering::Relaxed),
+        errors: CACHE_COUNTERS.errors.load(Ordering::Relaxed),
+        inconsistencies: CACHE_COUNTERS.inconsistencies.load(Ordering::Relaxed),
+    }
+}
+
+#[cfg(not(feature = "no_cache"))]
+fn record_memory_hit() {
+    CACHE_COUNTERS.memory_hits.fetch_add(1, Ordering::Relaxed);
+}
+
+fn record_miss() {
+    CACHE_COUNTERS.misses.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Reads `key` from the persistent cache, recording the outcome in [`cache_stats`] and, on a hit,
+/// the access in the cache itself.
+fn cache_get(
+    cache: &dyn CompiledContractCache,
+    key: &CryptoHash,
+) -> Result<Option<Vec<u8>>, std::io::Error> {
+    let res = finish_lookup(cache.get(&key.0))?;
+    if res.is_some() {
+        // Failing to record the access only makes the entry more likely to be evicted.
+        if let Err(err) = cache.touch(&key.0) {
+            tracing::debug!(target: "vm", "Failed to touch cache record: {}", err);
+        }
+    }
+    Ok(res)
+}
+
+/// Treats expired error records as absent and records the outcome of a persistent cache read in
+/// [`cache_stats`].
+fn finish_lookup(
+    res: Result<Option<Vec<u8>>, std::io::Error>,
+) -> Result<Option<Vec<u8>>, std::io::Error> {
+    let res = res.map(|value| value.filter(|it| !CacheRecord::is_expired(it)));
+    let counter = match &res {
+        Ok(Some(_)) => &CACHE_COUNTERS.persistent_hits,
+        Ok(None) => &CACHE_COUNTERS.misses,
+        Err(_) => &CACHE_COUNTERS.errors,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+    res
+}
+
+pub fn into_vm_result<T, E: Into<VMError>>(
+    res: Result<Result<T, CompilationError>, E>,
+) -> Result<T, VMError> {
+    match res {
+        Ok(Ok(it)) => Ok(it),
+        Ok(Err(err)) => Err(VMError::FunctionCallError(FunctionCallError::CompilationError(err))),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// A [`CompiledContractCache`] kept in memory for the lifetime of the process, optionally bounded
+/// to a number of entries.
+pub struct InMemoryContractCache {
+    /// Evicts the least recently used entry on insertion once full, unless unbounded.
+    store: Arc<Mutex<lru::LruCache<Vec<u8>, Vec<u8>>>>,
+}
+
+/// The name [`InMemoryContractCache`] had when it was meant for tests only.
+pub type MockCompiledContractCache = InMemoryContractCache;
+
+impl Default for InMemoryContractCache {
+    fn default() -> Self {
+        Self { store: Arc::new(Mutex::new(lru::LruCache::unbounded())) }
+    }
+}
+
+impl InMemoryContractCache {
+    /// Creates an unbounded cache.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a cache holding at most `capacity` entries, which evicts the least recently used
+    /// entry on `put` once it is full.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self { store: Arc::new(Mutex::new(lru::LruCache::new(capacity))) }
+    }
+
+    pub fn len(&self) -> usize {
+        self.store.lock().unwrap().len()
+    }
+
+    /// Returns the total length of the stored values, keys not included.
+    pub fn byte_size(&self) -> usize {
+        self.store.lock().unwrap().iter().map(|(_, value)| value.len()).sum()
+    }
+
+    /// Returns a snapshot of all stored keys, in no particular order.
+    pub fn keys(&self) -> Vec<Vec<u8>> {
+        self.store.lock().unwrap().iter().map(|(key, _)| key.clone()).collect()
+    }
+
+    /// Returns a snapshot of all stored key/value pairs, in no particular order.
+    pub fn entries(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let store = self.store.lock().unwrap();
+        store.iter().map(|(key, value)| (key.clone(), value.clone())).collect()
+    }
+
+    /// Removes all cached compilation errors, keeping compiled modules. Returns the number of
+    /// removed records.
+    pub fn purge_errors(&self) -> usize {
+        let mut store = self.store.lock().unwrap();
+        let errors: Vec<Vec<u8>> = store
+            .iter()
+            .filter(|(_, value)| CacheRecord::is_error(value))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &errors {
+            store.pop(key);
+        }
+        errors.len()
+    }
+}
+
+impl CompiledContractCache for InMemoryContractCache {
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), std::io::Error> {
+        self.store.lock().unwrap().put(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error> {
+        Ok(self.store.lock().unwrap().get(&key.to_vec()).cloned())
+    }
+
+    fn get_many(&self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>, std::io::Error> {
+        let mut store = self.store.lock().unwrap();
+        Ok(keys.iter().map(|key| store.get(key).cloned()).collect())
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), std::io::Error> {
+        self.store.lock().unwrap().pop(&key.to_vec());
+        Ok(())
+    }
+
+    fn contains_key(&self, key: &[u8]) -> Result<bool, std::io::Error> {
+        Ok(self.store.lock().unwrap().contains(&key.to_vec()))
+    }
+
+    fn put_if_absent(&self, key: &[u8], value: &[u8]) -> Result<bool, std::io::Error> {
+        // Hold the lock across the check and the insertion, so that only one of several
+        // concurrent callers stores its value.
+        let mut store = self.store.lock().unwrap();
+        let key = key.to_vec();
+        if store.contains(&key) {
+            return Ok(false);
+        }
+        store.put(key, value.to_vec());
+        Ok(true)
+    }
+
+    fn approximate_len(&self) -> Option<usize> {
+        Some(self.len())
+    }
+
+    fn touch(&self, key: &[u8]) -> Result<(), std::io::Error> {
+        // Looking the entry up marks it as the most recently used one.
+        self.store.lock().unwrap().get(&key.to_vec());
+        Ok(())
+    }
+}
+
+/// A [`MockCompiledContractCache`] which simulates a slow and unreliable backing store, for
+/// testing how the compile path copes with one.
+///
+/// Failures are drawn from a pseudo-random generator seeded at construction, so that a test sees
+/// the same sequence of failures on every run.
+pub struct FaultyMockCache {
+    inner: MockCompiledContractCache,
+    /// Added to every operation.
+    latency: Duration,
+    get_failure_probability: f64,
+    put_failure_probability: f64,
+    rng_state: Mutex<u64>,
+}
+
+impl FaultyMockCache {
+    /// Creates a cache which, until configured otherwise, behaves like the mock it wraps.
+    pub fn new(seed: u64) -> Self {
+        FaultyMockCache {
+            inner: MockCompiledContractCache::default(),
+            latency: Duration::ZERO,
+            get_failure_probability: 0.0,
+            put_failure_probability: 0.0,
+            // Xorshift gets stuck at zero.
+            rng_state: Mutex::new(seed.max(1)),
+        }
+    }
+
+    pub fn with_latency(self, latency: Duration) -> Self {
+        FaultyMockCache { latency, ..self }
+    }
+
+    /// Makes reads, i.e. `get`, `get_many`, `contains_key` and `touch`, fail with the given
+    /// probability.
+    pub fn with_get_failure_probability(self, probability: f64) -> Self {
+        FaultyMockCache { get_failure_probability: probability, ..self }
+    }
+
+    /// Makes writes, i.e. `put`, `put_if_absent` and `remove`, fail with the given probability.
+    pub fn with_put_failure_probability(self, probability: f64) -> Self {
+        FaultyMockCache { put_failure_probability: probability, ..self }
+    }
+
+    /// The wrapped cache, which can be inspected without injected faults.
+    pub fn inner(&self) -> &MockCompiledContractCache {
+        &self.inner
+    }
+
+    /// Sleeps for the configured latency, then fails with `probability`.
+    fn simulate_io(&self, probability: f64, op: &str) -> Result<(), std::io::Error> {
+        if !self.latency.is_zero() {
+            std::thread::sleep(self.latency);
+        }
+        let sample = {
+            let mut state = self.rng_state.lock().unwrap();
+            *state ^= *state << 13;
+            *state ^= *state >> 7;
+            *state ^= *state << 17;
+            (*state >> 11) as f64 / (1u64 << 53) as f64
+        };
+        if sample < probability {
+            let msg = format!("injected {} failure", op);
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, msg));
+        }
+        Ok(())
+    }
+}
+
+impl CompiledContractCache for FaultyMockCache {
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), std::io::Error> {
+        self.simulate_io(self.put_failure_probability, "put")?;
+        self.inner.put(key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error> {
+        self.simulate_io(self.get_failure_probability, "get")?;
+        self.inner.get(key)
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), std::io::Error> {
+        self.simulate_io(self.put_failure_probability, "remove")?;
+        self.inner.remove(key)
+    }
+
+    fn contains_key(&self, key: &[u8]) -> Result<bool, std::io::Error> {
+        self.simulate_io(self.get_failure_probability, "contains_key")?;
+        self.inner.contains_key(key)
+    }
+
+    fn put_if_absent(&self, key: &[u8], value: &[u8]) -> Result<bool, std::io::Error> {
+        self.simulate_io(self.put_failure_probability, "put_if_absent")?;
+        self.inner.put_if_absent(key, value)
+    }
+
+    fn get_many(&self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>, std::io::Error> {
+        self.simulate_io(self.get_failure_probability, "get_many")?;
+        self.inner.get_many(keys)
+    }
+
+    fn approximate_len(&self) -> Option<usize> {
+        self.inner.approximate_len()
+    }
+
+    fn touch(&self, key: &[u8]) -> Result<(), std::io::Error> {
+        self.simulate_io(self.get_failure_probability, "touch")?;
+        self.inner.touch(key)
+    }
+}
+
+impl fmt::Debug for InMemoryContractCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.store.lock().unwrap().iter()).finish()
+    }
+}
+
+/// A [`CompiledContractCache`] which stores every record in its own file, named after the hex
+/// encoded key, in a directory. For setups which don't need a full database.
+pub struct FsCompiledContractCache {
+    dir: PathBuf,
+}
+
+impl FsCompiledContractCache {
+    /// Opens the cache in `dir`, creating the directory if needed.
+    pub fn open(dir: impl AsRef<Path>) -> std::io::Result<FsCompiledContractCache> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+        Ok(FsCompiledContractCache { dir })
+    }
+
+    fn path(&self, key: &[u8]) -> PathBuf {
+        let name: String = key.iter().map(|byte| format!("{:02x}", byte)).collect();
+        self.dir.join(name)
+    }
+
+    /// Removes the temporary files left behind by interrupted writes and, with `verify_records`,
+    /// the records which fail their checksum or can't be decoded.
+    ///
+    /// This is a maintenance routine for operators: it must not run while another process writes
+    /// to the cache, whose in-progress writes would be taken for stray temporary files.
+    pub fn compact(&self, verify_records: bool) -> std::io::Result<CompactionReport> {
+        let mut report = CompactionReport::default();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            let path = entry.path();
+            if path.extension().map_or(false, |ext| ext == "tmp") {
+                std::fs::remove_file(&path)?;
+                report.cleaned += 1;
+                continue;
+            }
+            if verify_records {
+                let record = CacheRecord::from_bytes(&std::fs::read(&path)?);
+                // Records from newer nodes aren't corrupt.
+                if matches!(
+                    record,
+                    Err(CacheError::ChecksumMismatch | CacheError::DeserializationError)
+                ) {
+                    std::fs::remove_file(&path)?;
+                    report.corrupt += 1;
+                    continue;
+                }
+            }
+            report.kept += 1;
+        }
+        Ok(report)
+    }
+}
+
+/// What [`FsCompiledContractCache::compact`] did.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactionReport {
+    /// Stray temporary files removed.
+    pub cleaned: usize,
+    /// Files left in place.
+    pub kept: usize,
+    /// Corrupt records removed.
+    pub corrupt: usize,
+}
+
+impl CompiledContractCache for FsCompiledContractCache {
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), std::io::Error> {
+        static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        // Write to a temporary file first and rename it, so that readers never see a partially
+        // written record. The file is synced before the rename and the directory after it, so
+        // that a crash can't leave a truncated record under the final name.
+        let path = self.path(key);
+        let tmp_path = path.with_extension(format!(
+            "{}.{}.tmp",
+            std::process::id(),
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let res = write_synced(&tmp_path, value).and_then(|()| std::fs::rename(&tmp_path, &path));
+        if let Err(err) = res {
+            let _ = std::fs::remove_file(&tmp_path);
+            return Err(err);
+        }
+        sync_dir(&self.dir)
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error> {
+        match std::fs::read(self.path(key)) {
+            Ok(value) => Ok(Some(value)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), std::io::Error> {
+        match std::fs::remove_file(self.path(key)) {
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            res => res,
+        }
+    }
+
+    fn contains_key(&self, key: &[u8]) -> Result<bool, std::io::Error> {
+        match std::fs::metadata(self.path(key)) {
+            Ok(metadata) => Ok(metadata.is_file()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+fn write_synced(path: &Path, value: &[u8]) -> std::io::Result<()> {
+    let mut file = std::fs::File::create(path)?;
+    std::io::Write::write_all(&mut file, value)?;
+    file.sync_all()
+}
+
+/// Persists the entries of `dir`, such as a file renamed into it.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    std::fs::File::open(dir)?.sync_all()
+}
+
+/// Directories can't be opened as files on other platforms, which persist renames on their own.
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// A [`CompiledContractCache`] which puts a small, fast cache in front of a large, slow one.
+///
+/// Lookups go to the fast tier first and fall back to the slow one, copying the record into the
+/// fast tier on a hit. Writes go to both tiers.
+pub struct LayeredCache {
+    fast: Arc<dyn CompiledContractCache>,
+    slow: Arc<dyn CompiledContractCache>,
+}
+
+impl LayeredCache {
+    pub fn new(
+        fast: Arc<dyn CompiledContractCache>,
+        slow: Arc<dyn CompiledContractCache>,
+    ) -> LayeredCache {
+        LayeredCache { fast, slow }
+    }
+}
+
+impl CompiledContractCache for LayeredCache {
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), std::io::Error> {
+        // The slow tier is the source of truth, so write it first: if that fails, the fast tier
+        // doesn't end up with a record the slow one lacks.
+        self.slow.put(key, value)?;
+        self.fast.put(key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error> {
+        if let Some(value) = self.fast.get(key)? {
+            return Ok(Some(value));
+        }
+        let value = self.slow.get(key)?;
+        if let Some(value) = &value {
+            // Failing to promote the record only costs a slower lookup next time.
+            if let Err(err) = self.fast.put(key, value) {
+                tracing::debug!(target: "vm", "Failed to promote cache record: {}", err);
+            }
+        }
+        Ok(value)
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), std::io::Error> {
+        self.fast.remove(key)?;
+        self.slow.remove(key)
+    }
+
+    fn contains_key(&self, key: &[u8]) -> Result<bool, std::io::Error> {
+        Ok(self.fast.contains_key(key)? || self.slow.contains_key(key)?)
+    }
+
+    fn approximate_len(&self) -> Option<usize> {
+        self.slow.approximate_len()
+    }
+
+    fn touch(&self, key: &[u8]) -> Result<(), std::io::Error> {
+        self.fast.touch(key)?;
+        self.slow.touch(key)
+    }
+}
+
+/// A [`CompiledContractCache`] which mirrors writes into a second, best-effort cache, e.g. to
+/// populate a cache shared by a cluster of nodes while keeping a warm local one.
+///
+/// Unlike with [`LayeredCache`], failures of the mirror are only logged: the primary cache alone
+/// determines whether an operation succeeds. Lookups which miss the primary fall back to the
+/// mirror, unless disabled with [`MirroringCache::with_mirror_fallback`].
+pub struct MirroringCache {
+    primary: Arc<dyn CompiledContractCache>,
+    mirror: Arc<dyn CompiledContractCache>,
+    mirror_fallback: bool,
+}
+
+impl MirroringCache {
+    pub fn new(
+        primary: Arc<dyn CompiledContractCache>,
+        mirror: Arc<dyn CompiledContractCache>,
+    ) -> MirroringCache {
+        MirroringCache { primary, mirror, mirror_fallback: true }
+    }
+
+    /// Sets whether lookups which miss the primary cache are retried against the mirror.
+    pub fn with_mirror_fallback(mut self, mirror_fallback: bool) -> MirroringCache {
+        self.mirror_fallback = mirror_fallback;
+        self
+    }
+
+    fn log_mirror_failure(operation: &str, res: Result<(), std::io::Error>) {
+        if let Err(err) = res {
+            tracing::debug!(target: "vm", "Failed to {} mirrored cache record: {}", operation, err);
+        }
+    }
+}
+
+impl CompiledContractCache for MirroringCache {
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), std::io::Error> {
+        self.primary.put(key, value)?;
+        Self::log_mirror_failure("write", self.mirror.put(key, value));
+        Ok(())
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error> {
+        let value = self.primary.get(key)?;
+        if value.is_some() || !self.mirror_fallback {
+            return Ok(value);
+        }
+        match self.mirror.get(key) {
+            Ok(value) => Ok(value),
+            Err(err) => {
+                tracing::debug!(target: "vm", "Failed to read mirrored cache record: {}", err);
+                Ok(None)
+            }
+        }
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), std::io::Error> {
+        self.primary.remove(key)?;
+        Self::log_mirror_failure("remove", self.mirror.remove(key));
+        Ok(())
+    }
+
+    fn contains_key(&self, key: &[u8]) -> Result<bool, std::io::Error> {
+        if self.primary.contains_key(key)? {
+            return Ok(true);
+        }
+        Ok(self.mirror_fallback && self.mirror.contains_key(key).unwrap_or(false))
+    }
+
+    fn put_if_absent(&self, key: &[u8], value: &[u8]) -> Result<bool, std::io::Error> {
+        let inserted = self.primary.put_if_absent(key, value)?;
+        if inserted {
+            Self::log_mirror_failure("write", self.mirror.put(key, value));
+        }
+        Ok(inserted)
+    }
+
+    fn approximate_len(&self) -> Option<usize> {
+        self.primary.approximate_len()
+    }
+
+    fn touch(&self, key: &[u8]) -> Result<(), std::io::Error> {
+        self.primary.touch(key)
+    }
+}
+
+/// A [`CompiledContractCache`] which serves lookups from a frozen, possibly shared, cache and
+/// never writes to it, e.g. for a node replaying historical blocks.
+///
+/// Writes are rejected with an [`io::ErrorKind::PermissionDenied`] error, so compiling a contract
+/// which isn't cached fails with `CacheError::WriteError`. With
+/// [`ReadOnlyCache::with_ignored_writes`], they silently succeed instead: such contracts are then
+/// compiled again on every miss of the in-memory module cache, without being stored.
+pub struct ReadOnlyCache {
+    inner: Arc<dyn CompiledContractCache>,
+    ignore_writes: bool,
+}
+
+impl ReadOnlyCache {
+    pub fn new(inner: Arc<dyn CompiledContractCache>) -> ReadOnlyCache {
+        ReadOnlyCache { inner, ignore_writes: false }
+    }
+
+    /// Sets whether writes silently succeed as no-ops rather than fail.
+    pub fn with_ignored_writes(mut self, ignore_writes: bool) -> ReadOnlyCache {
+        self.ignore_writes = ignore_writes;
+        self
+    }
+
+    fn reject_write(&self) -> Result<(), std::io::Error> {
+        if self.ignore_writes {
+            return Ok(());
+        }
+        Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "the cache is read-only"))
+    }
+}
+
+impl CompiledContractCache for ReadOnlyCache {
+    fn put(&self, _key: &[u8], _value: &[u8]) -> Result<(), std::io::Error> {
+        self.reject_write()
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error> {
+        self.inner.get(key)
+    }
+
+    fn remove(&self, _key: &[u8]) -> Result<(), std::io::Error> {
+        self.reject_write()
+    }
+
+    fn contains_key(&self, key: &[u8]) -> Result<bool, std::io::Error> {
+        self.inner.contains_key(key)
+    }
+
+    fn put_if_absent(&self, key: &[u8], _value: &[u8]) -> Result<bool, std::io::Error> {
+        if self.inner.contains_key(key)? {
+            return Ok(false);
+        }
+        self.reject_write()?;
+        Ok(false)
+    }
+
+    fn approximate_len(&self) -> Option<usize> {
+        self.inner.approximate_len()
+    }
+}
+
+/// A [`CompiledContractCache`] which remembers keys recently found to be absent from the wrapped
+/// cache, so that repeated lookups of them don't reach it until `ttl` passes or the key is written.
+pub struct NegativeCachingWrapper {
+    inner: Arc<dyn CompiledContractCache>,
+    ttl: Duration,
+    /// Keys which weren't found, with the time of the lookup.
+    absent: Mutex<HashMap<Vec<u8>, Instant>>,
+}
+
+impl NegativeCachingWrapper {
+    pub fn new(inner: Arc<dyn CompiledContractCache>, ttl: Duration) -> NegativeCachingWrapper {
+        NegativeCachingWrapper { inner, ttl, absent: Mutex::new(HashMap::new()) }
+    }
+
+    fn is_known_absent(&self, key: &[u8]) -> bool {
+        self.absent.lock().unwrap().get(key).map_or(false, |at| at.elapsed() < self.ttl)
+    }
+
+    fn record_absent(&self, key: &[u8]) {
+        let mut absent = self.absent.lock().unwrap();
+        absent.retain(|_, at| at.elapsed() < self.ttl);
+        absent.insert(key.to_vec(), Instant::now());
+    }
+
+    fn forget_absent(&self, key: &[u8]) {
+        self.absent.lock().unwrap().remove(key);
+    }
+}
+
+impl CompiledContractCache for NegativeCachingWrapper {
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), std::io::Error> {
+        self.forget_absent(key);
+        self.inner.put(key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error> {
+        if self.is_known_absent(key) {
+            return Ok(None);
+        }
+        let value = self.inner.get(key)?;
+        if value.is_none() {
+            self.record_absent(key);
+        }
+        Ok(value)
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), std::io::Error> {
+        self.inner.remove(key)
+    }
+
+    fn contains_key(&self, key: &[u8]) -> Result<bool, std::io::Error> {
+        if self.is_known_absent(key) {
+            return Ok(false);
+        }
+        self.inner.contains_key(key)
+    }
+
+    fn put_if_absent(&self, key: &[u8], value: &[u8]) -> Result<bool, std::io::Error> {
+        self.forget_absent(key);
+        self.inner.put_if_absent(key, value)
+    }
+
+    fn approximate_len(&self) -> Option<usize> {
+        self.inner.approximate_len()
+    }
+
+    fn touch(&self, key: &[u8]) -> Result<(), std::io::Error> {
+        self.inner.touch(key)
+    }
+}
+
+/// A [`CompiledContractCache`] which stops writing to the wrapped cache once `threshold` writes
+/// in a row failed within `window`, e.g. because its disk died, and resumes after `cooldown`.
+/// Meanwhile, writes fail right away without reaching the wrapped cache, so that callers don't
+/// wait on a broken disk but still learn that nothing was stored.
+pub struct CircuitBreakerCache {
+    inner: Arc<dyn CompiledContractCache>,
+    threshold: usize,
+    window: Duration,
+    cooldown: Duration,
+    state: Mutex<CircuitBreakerState>,
+}
+
+#[derive(Default)]
+struct CircuitBreakerState {
+    /// When the current run of failed writes started.
+    first_failure: Option<Instant>,
+    consecutive_failures: usize,
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreakerCache {
+    pub fn new(
+        inner: Arc<dyn CompiledContractCache>,
+        threshold: usize,
+        window: Duration,
+        cooldown: Duration,
+    ) -> CircuitBreakerCache {
+        CircuitBreakerCache {
+            inner,
+            threshold,
+            window,
+            cooldown,
+            state: Mutex::new(CircuitBreakerState::default()),
+        }
+    }
+
+    /// Whether writes are currently skipped.
+    pub fn is_open(&self) -> bool {
+        self.state.lock().unwrap().open_until.map_or(false, |until| Instant::now() < until)
+    }
+
+    /// Runs `write` against the wrapped cache unless the breaker is open, in which case an error
+    /// is returned.
+    fn write<T>(
+        &self,
+        write: impl FnOnce() -> Result<T, std::io::Error>,
+    ) -> Result<T, std::io::Error> {
+        if self.is_open() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "cache writes are suspended after repeated failures",
+            ));
+        }
+        let res = write();
+        let mut state = self.state.lock().unwrap();
+        if res.is_ok() {
+            state.first_failure = None;
+            state.consecutive_failures = 0;
+            return res;
+        }
+        let now = Instant::now();
+        match state.first_failure {
+            Some(first) if now.duration_since(first) <= self.window => {
+                state.consecutive_failures += 1
+            }
+            _ => {
+                state.first_failure = Some(now);
+                state.consecutive_failures = 1;
+            }
+        }
+        if state.consecutive_failures >= self.threshold {
+            tracing::warn!(
+                target: "vm",
+                "{} cache writes failed in a row, suspending them for {:?}",
+                state.consecutive_failures,
+                self.cooldown
+            );
+            metrics::CACHE_CIRCUIT_BREAKER_TRIPS.inc();
+            *state =
+                CircuitBreakerState { open_until: Some(now + self.cooldown), ..Default::default() };
+        }
+        res
+    }
+}
+
+impl CompiledContractCache for CircuitBreakerCache {
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), std::io::Error> {
+        self.write(|| self.inner.put(key, value))
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error> {
+        self.inner.get(key)
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), std::io::Error> {
+        self.inner.remove(key)
+    }
+
+    fn contains_key(&self, key: &[u8]) -> Result<bool, std::io::Error> {
+        self.inner.contains_key(key)
+    }
+
+    fn put_if_absent(&self, key: &[u8], value: &[u8]) -> Result<bool, std::io::Error> {
+        self.write(|| self.inner.put_if_absent(key, value))
+    }
+
+    fn get_many(&self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>, std::io::Error> {
+        self.inner.get_many(keys)
+    }
+
+    fn approximate_len(&self) -> Option<usize> {
+        self.inner.approximate_len()
+    }
+
+    fn touch(&self, key: &[u8]) -> Result<(), std::io::Error> {
+        self.inner.touch(key)
+    }
+}
+
+/// An access to a [`RecordingCache`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheEvent {
+    pub at: SystemTime,
+    pub key: Vec<u8>,
+    pub kind: CacheEventKind,
+    /// Size of the value read or written, zero on a miss.
+    pub value_len: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheEventKind {
+    Get { hit: bool },
+    Put,
+}
+
+/// A [`CompiledContractCache`] which logs the reads and writes of the wrapped cache, keeping the
+/// last `capacity` of them for diagnostics. Failed operations aren't logged.
+pub struct RecordingCache {
+    inner: Arc<dyn CompiledContractCache>,
+    capacity: usize,
+    events: Mutex<VecDeque<CacheEvent>>,
+}
+
+impl RecordingCache {
+    pub fn new(inner: Arc<dyn CompiledContractCache>, capacity: usize) -> RecordingCache {
+        RecordingCache { inner, capacity, events: Mutex::new(VecDeque::new()) }
+    }
+
+    /// Returns the logged events, oldest first.
+    pub fn events(&self) -> Vec<CacheEvent> {
+        self.events.lock().unwrap().iter().cloned().collect()
+    }
+
+    fn record(&self, key: &[u8], kind: CacheEventKind, value_len: usize) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut events = self.events.lock().unwrap();
+        if events.len() == self.capacity {
+            events.pop_front();
+        }
+        events.push_back(CacheEvent { at: SystemTime::now(), key: key.to_vec(), kind, value_len });
+    }
+}
+
+impl CompiledContractCache for RecordingCache {
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), std::io::Error> {
+        self.inner.put(key, value)?;
+        self.record(key, CacheEventKind::Put, value.len());
+        Ok(())
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error> {
+        let value = self.inner.get(key)?;
+        let value_len = value.as_ref().map_or(0, |value| value.len());
+        self.record(key, CacheEventKind::Get { hit: value.is_some() }, value_len);
+        Ok(value)
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), std::io::Error> {
+        self.inner.remove(key)
+    }
+
+    fn contains_key(&self, key: &[u8]) -> Result<bool, std::io::Error> {
+        self.inner.contains_key(key)
+    }
+
+    fn put_if_absent(&self, key: &[u8], value: &[u8]) -> Result<bool, std::io::Error> {
+        let written = self.inner.put_if_absent(key, value)?;
+        if written {
+            self.record(key, CacheEventKind::Put, value.len());
+        }
+        Ok(written)
+    }
+
+    fn approximate_len(&self) -> Option<usize> {
+        self.inner.approximate_len()
+    }
+
+    fn touch(&self, key: &[u8]) -> Result<(), std::io::Error> {
+        self.inner.touch(key)
+    }
+}
+
+/// A [`CompiledContractCache`] which rejects writes of values larger than `max_value_bytes` with an
+/// [`io::ErrorKind::InvalidInput`] error, protecting a shared backing store from pathologically
+/// large artifacts. Everything else is passed through to the wrapped cache.
+pub struct SizeCappedCache {
+    inner: Arc<dyn CompiledContractCache>,
+    max_value_bytes: usize,
+}
+
+impl SizeCappedCache {
+    pub fn new(inner: Arc<dyn CompiledContractCache>, max_value_bytes: usize) -> SizeCappedCache {
+        SizeCappedCache { inner, max_value_bytes }
+    }
+
+    fn check_size(&self, value: &[u8]) -> Result<(), std::io::Error> {
+        if value.len() > self.max_value_bytes {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "value of {} bytes exceeds the cap of {} bytes",
+                    value.len(),
+                    self.max_value_bytes
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl CompiledContractCache for SizeCappedCache {
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), std::io::Error> {
+        self.check_size(value)?;
+        self.inner.put(key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error> {
+        self.inner.get(key)
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), std::io::Error> {
+        self.inner.remove(key)
+    }
+
+    fn contains_key(&self, key: &[u8]) -> Result<bool, std::io::Error> {
+        self.inner.contains_key(key)
+    }
+
+    fn put_if_absent(&self, key: &[u8], value: &[u8]) -> Result<bool, std::io::Error> {
+        self.check_size(value)?;
+        self.inner.put_if_absent(key, value)
+    }
+
+    fn get_many(&self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>, std::io::Error> {
+        self.inner.get_many(keys)
+    }
+
+    fn approximate_len(&self) -> Option<usize> {
+        self.inner.approximate_len()
+    }
+
+    fn touch(&self, key: &[u8]) -> Result<(), std::io::Error> {
+        self.inner.touch(key)
+    }
+}
+
+/// Asynchronous counterpart of [`CompiledContractCache`], for caches backed by a network service
+/// which can't be queried without blocking.
+#[cfg(feature = "async_cache")]
+#[async_trait]
+pub trait AsyncCompiledContractCache: Send + Sync {
+    async fn put(&self, key: &[u8], value: &[u8]) -> Result<(), std::io::Error>;
+    async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error>;
+
+    /// Removes the value stored under `key`, if any.
+    ///
+    /// The default implementation does nothing, like [`CompiledContractCache::remove`].
+    async fn remove(&self, _key: &[u8]) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+}
+
+/// Exposes a synchronous [`CompiledContractCache`] as an [`AsyncCompiledContractCache`]. Every
+/// operation runs on the blocking thread pool of the tokio runtime, so that a slow cache doesn't
+/// stall the executor.
+#[cfg(feature = "async_cache")]
+pub struct AsyncCacheAdapter {
+    inner: Arc<dyn CompiledContractCache>,
+}
+
+#[cfg(feature = "async_cache")]
+impl AsyncCacheAdapter {
+    pub fn new(inner: Arc<dyn CompiledContractCache>) -> AsyncCacheAdapter {
+        AsyncCacheAdapter { inner }
+    }
+}
+
+#[cfg(feature = "async_cache")]
+#[async_trait]
+impl AsyncCompiledContractCache for AsyncCacheAdapter {
+    async fn put(&self, key: &[u8], value: &[u8]) -> Result<(), std::io::Error> {
+        let (inner, key, value) = (Arc::clone(&self.inner), key.to_vec(), value.to_vec());
+        spawn_blocking(move || inner.put(&key, &value)).await
+    }
+
+    async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error> {
+        let (inner, key) = (Arc::clone(&self.inner), key.to_vec());
+        spawn_blocking(move || inner.get(&key)).await
+    }
+
+    async fn remove(&self, key: &[u8]) -> Result<(), std::io::Error> {
+        let (inner, key) = (Arc::clone(&self.inner), key.to_vec());
+        spawn_blocking(move || inner.remove(&key)).await
+    }
+}
+
+/// Runs `f` on the blocking thread pool of the current tokio runtime, resuming its panic if it
+/// panicked.
+#[cfg(feature = "async_cache")]
+async fn spawn_blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    match tokio::task::spawn_blocking(f).await {
+        Ok(res) => res,
+        Err(err) => std::panic::resume_unwind(err.into_panic()),
+    }
+}
+
+const CACHE_ARCHIVE_MAGIC: &[u8; 8] = b"NEARCCA1";
+
+/// Hashes of all VMs, in the order they are written to the header of a cache archive.
+fn cache_archive_vm_hashes() -> [u64; 3] {
+    [
+        current_vm_hash(VMKind::Wasmer0),
+        current_vm_hash(VMKind::Wasmtime),
+        current_vm_hash(VMKind::Wasmer2),
+    ]
+}
+
+/// Writes the records stored under `keys` to `writer`, to be loaded into another cache with
+/// [`import_cache`]. Keys missing from the cache are skipped.
+///
+/// The archive starts with a header holding the hashes of this node's VMs, followed by a key,
+/// a little-endian `u32` length and the record bytes for every entry.
+pub fn export_cache(
+    cache: &dyn CompiledContractCache,
+    keys: &[CryptoHash],
+    mut writer: impl Write,
+) -> io::Result<()> {
+    writer.write_all(CACHE_ARCHIVE_MAGIC)?;
+    for vm_hash in cache_archive_vm_hashes() {
+        writer.write_all(&vm_hash.to_le_bytes())?;
+    }
+    for key in keys {
+        let record = match cache.get(&key.0)? {
+            Some(record) => record,
+            None => continue,
+        };
+        let len = u32::try_from(record.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "cache record too large"))?;
+        writer.write_all(&key.0)?;
+        writer.write_all(&len.to_le_bytes())?;
+        writer.write_all(&record)?;
+    }
+    writer.flush()
+}
+
+/// Loads the records written by [`export_cache`] into `cache`, returning the number of records.
+///
+/// Compiled artifacts only work with the exact VM build which produced them, so archives exported
+/// by a node with different VMs are rejected with [`io::ErrorKind::InvalidData`].
+pub fn import_cache(mut reader: impl Read, cache: &dyn CompiledContractCache) -> io::Result<usize> {
+    let invalid_data = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != CACHE_ARCHIVE_MAGIC {
+        return Err(invalid_data("not a compiled contract cache archive"));
+    }
+    for expected in cache_archive_vm_hashes() {
+        let mut vm_hash = [0u8; 8];
+        reader.read_exact(&mut vm_hash)?;
+        if u64::from_le_bytes(vm_hash) != expected {
+            return Err(invalid_data("cache archive was exported by an incompatible runtime"));
+        }
+    }
+
+    let mut imported = 0;
+    loop {
+        let mut key = [0u8; 32];
+        // A clean end of the archive is only allowed between entries.
+        if reader.read(&mut key[..1])? == 0 {
+            return Ok(imported);
+        }
+        reader.read_exact(&mut key[1..])?;
+        let mut len = [0u8; 4];
+        reader.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len) as usize;
+        // The length comes from the archive, check it before allocating that much.
+        check_record_len(len, DEFAULT_MAX_RECORD_BYTES)
+            .map_err(|err| invalid_data(&VMError::CacheError(err).to_string()))?;
+        let mut record = vec![0u8; len];
+        reader.read_exact(&mut record)?;
+        cache.put(&key, &record)?;
+        imported += 1;
+    }
+}
+
+/// A persistent sidecar to a [`CompiledContractCache`], recording the VM hash every record was
+/// written with. Keys can't be reversed, so this is what lets [`gc_stale_records`] find records
+/// orphaned by a VM upgrade.
+pub struct VmHashIndex {
+    store: Arc<dyn CompiledContractCache>,
+}
+
+impl VmHashIndex {
+    /// `store` must not be the cache being indexed.
+    pub fn new(store: Arc<dyn CompiledContractCache>) -> VmHashIndex {
+        VmHashIndex { store }
+    }
+
+    pub fn record(&self, key: &CryptoHash, vm_hash: u64) -> io::Result<()> {
+        self.store.put(&key.0, &vm_hash.to_le_bytes())
+    }
+
+    /// Returns the VM hash the record under `key` was written with, if known.
+    pub fn vm_hash(&self, key: &CryptoHash) -> io::Result<Option<u64>> {
+        match self.store.get(&key.0)? {
+            None => Ok(None),
+            Some(bytes) => match bytes.as_slice().try_into() {
+                Ok(bytes) => Ok(Some(u64::from_le_bytes(bytes))),
+                Err(_) => {
+                    Err(io::Error::new(io::ErrorKind::InvalidData, "malformed VM hash index entry"))
+                }
+            },
+        }
+    }
+
+    pub fn remove(&self, key: &CryptoHash) -> io::Result<()> {
+        self.store.remove(&key.0)
+    }
+}
+
+impl fmt::Debug for VmHashIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VmHashIndex").finish_non_exhaustive()
+    }
+}
+
+/// Indexes are equal if they are backed by the same store.
+impl PartialEq for VmHashIndex {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::as_ptr(&self.store) as *const u8 == Arc::as_ptr(&other.store) as *const u8
+    }
+}
+
+impl Eq for VmHashIndex {}
+
+/// Records the VM hash of a just written record in [`CacheOptions::vm_hash_index`], if set.
+fn index_vm_hash(key: &CryptoHash, vm_kind: VMKind, options: &CacheOptions) {
+    if let Some(index) = &options.vm_hash_index {
+        // A record missing from the index is merely never garbage collected.
+        if let Err(err) = index.record(key, current_vm_hash(vm_kind)) {
+            tracing::debug!(target: "vm", "Failed to index the VM hash of {}: {}", key, err);
+        }
+    }
+}
+
+/// Removes the records stored under `keys` which, according to `index`, were written with a VM
+/// hash not in `valid_vm_hashes`, and can thus never be looked up again. Records missing from the
+/// index are kept. Returns the number of removed records.
+///
+/// `keys` have to come from a backend which supports enumerating them.
+pub fn gc_stale_records(
+    cache: &dyn CompiledContractCache,
+    index: &VmHashIndex,
+    keys: &[CryptoHash],
+    valid_vm_hashes: &[u64],
+) -> io::Result<usize> {
+    let mut removed = 0;
+    for key in keys {
+        match index.vm_hash(key)? {
+            Some(vm_hash) if !valid_vm_hashes.contains(&vm_hash) => {
+                cache.remove(&key.0)?;
+                index.remove(key)?;
+                removed += 1;
+            }
+            _ => {}
+        }
+    }
+    Ok(removed)
+}
+
+/// Removes the records stored under `keys` which are cached compilation errors, so that the
+/// contracts get compiled again, while keeping compiled modules. Returns the number of removed
+/// records.
+///
+/// `keys` have to come from a backend which supports enumerating them, see also
+/// [`InMemoryContractCache::purge_errors`].
+pub fn purge_errors(cache: &dyn CompiledContractCache, keys: &[CryptoHash]) -> io::Result<usize> {
+    let mut removed = 0;
+    for key in keys {
+        if let Some(serialized) = cache.get(&key.0)? {
+            if CacheRecord::is_error(&serialized) {
+                cache.remove(&key.0)?;
+                removed += 1;
+            }
+        }
+    }
+    Ok(removed)
+}
+
+/// Returns how long ago the record stored under `key` was written, for telemetry on how much the
+/// cache churns. `None` if there is no record, or it has no creation time, because it was written
+/// before creation times were recorded, or stored as is after being compiled outside of a cache.
+pub fn record_age(cache: &dyn CompiledContractCache, key: &CryptoHash) -> Option<Duration> {
+    let serialized = cache.get(&key.0).ok()??;
+    let created_at_unix = CacheRecord::created_at_unix(&serialized)?;
+    Some(Duration::from_secs(unix_now().saturating_sub(created_at_unix)))
+}
+
+/// Returns the serialized module stored under `key`, without the record envelope, e.g. to ship a
+/// single precompiled contract to another node. Returns `None` if nothing is stored, and fails
+/// with `CacheError::CachedCompilationError` if a compilation error is stored instead of a module.
+pub fn extract_artifact(
+    cache: &dyn CompiledContractCache,
+    key: &CryptoHash,
+) -> Result<Option<Vec<u8>>, CacheError> {
+    let record = match cache.get(&key.0).map_err(|_io_err| CacheError::ReadError)? {
+        Some(record) => record,
+        None => return Ok(None),
+    };
+    check_record_len(record.len(), DEFAULT_MAX_RECORD_BYTES)?;
+    match CacheRecord::from_bytes(&record)?.into_code()? {
+        Ok(artifact) => Ok(Some(artifact)),
+        Err(_compilation_error) => Err(CacheError::CachedCompilationError),
+    }
+}
+
+/// Stores a serialized module obtained with [`extract_artifact`] under `key`. The module is
+/// tagged with `vm_hash`, the [`current_vm_hash`] of the node it was extracted from, so that it is
+/// compiled again rather than loaded if this node runs another version of the VM.
+pub fn install_artifact(
+    cache: &dyn CompiledContractCache,
+    key: &CryptoHash,
+    artifact: Vec<u8>,
+    vm_hash: u64,
+    options: &CacheOptions,
+) -> Result<(), CacheError> {
+    let record = CacheRecord::code_for_vm_hash(artifact, options.artifact_compression, vm_hash);
+    cache.put(&key.0, &record.to_cached_bytes()).map_err(|_io_err| CacheError::WriteError)
+}
+
+/// Checks the outcome of serializing the module compiled for `key`. An empty artifact can't hold a
+/// valid module, so it is rejected like a failed serialization, rather than cached and only found
+/// to be broken when the contract is executed.
+pub(crate) fn checked_artifact<E>(
+    serialized: Result<Vec<u8>, E>,
+    key: &CryptoHash,
+) -> Result<Vec<u8>, CacheError> {
+    match serialized {
+        Ok(artifact) if !artifact.is_empty() => Ok(artifact),
+        _ => Err(CacheError::SerializationError { hash: key.0 }),
+    }
+}
+
+/// Number of compiled modules kept in memory across all VMs, unless changed with
+/// [`set_module_cache_capacity`].
+pub const DEFAULT_MODULE_CACHE_CAPACITY: usize = 128;
+
+/// The default maximal number of compilation errors kept in memory, apart from the modules.
+pub const DEFAULT_ERROR_CACHE_CAPACITY: usize = 1024;
+
+/// Changes the number of compiled modules kept in the in-memory caches, across all VMs.
+///
+/// Intended to be called once at process startup, but it is safe to call at any time: shrinking
+/// the caches evicts the least recently used modules. See [`ModuleCacheRegistry::set_budget`].
+pub fn set_module_cache_capacity(capacity: usize) {
+    #[cfg(not(feature = "no_cache"))]
+    registry().set_budget(capacity);
+    #[cfg(feature = "no_cache")]
+    let _ = capacity;
+}
+
+/// Drops all modules from the in-memory caches, so that the next use of every contract goes
+/// through the persistent cache or compiles it again.
+pub fn clear_module_cache() {
+    #[cfg(not(feature = "no_cache"))]
+    registry().clear();
+}
+
+/// Pins the module under `key` in the in-memory caches, so that it is never evicted, e.g. for the
+/// handful of contracts which dominate execution. Pinned modules don't count toward the budgets
+/// of the caches, see [`ModuleCacheRegistry::set_budget`] and
+/// [`ModuleCacheRegistry::set_byte_budget`].
+///
+/// The key can be pinned before the module is compiled, which then stays in memory from its first
+/// use on.
+pub fn pin_module(key: CryptoHash) {
+    #[cfg(not(feature = "no_cache"))]
+    registry().pin(key);
+    #[cfg(feature = "no_cache")]
+    let _ = key;
+}
+
+/// Releases a module pinned with [`pin_module`], making it subject to eviction again.
+pub fn unpin_module(key: &CryptoHash) {
+    #[cfg(not(feature = "no_cache"))]
+    registry().unpin(key);
+    #[cfg(feature = "no_cache")]
+    let _ = key;
+}
+
+/// A compiled module, or the reason it couldn't be compiled, as kept in the in-memory caches.
+type CachedModule<M> = Result<Result<M, CompilationError>, CacheError>;
+
+/// The contracts being compiled for the in-memory caches, so that concurrent requests for the same
+/// contract compile it only once, while different contracts are compiled in parallel.
+#[cfg(not(feature = "no_cache"))]
+pub(crate) struct InFlight<M> {
+    compilations: Mutex<HashMap<CryptoHash, Arc<once_cell::sync::OnceCell<CachedModule<M>>>>>,
+}
+
+#[cfg(not(feature = "no_cache"))]
+impl<M> Default for InFlight<M> {
+    fn default() -> Self {
+        InFlight { compilations: Mutex::new(HashMap::new()) }
+    }
+}
+
+#[cfg(not(feature = "no_cache"))]
+impl<M: Clone> InFlight<M> {
+    /// Runs `compile`, unless the same `key` is already being compiled by another thread, in which
+    /// case its outcome is awaited and returned instead. The lock is only held to look up the
+    /// compilation, not while compiling.
+    pub(crate) fn run(
+        &self,
+        key: CryptoHash,
+        compile: impl FnOnce() -> CachedModule<M>,
+    ) -> CachedModule<M> {
+        let compilation = Arc::clone(self.compilations.lock().unwrap().entry(key).or_default());
+        let res = compilation.get_or_init(compile).clone();
+        let mut compilations = self.compilations.lock().unwrap();
+        // A later compilation of the key might have started already, e.g. after an eviction.
+        if compilations.get(&key).map_or(false, |it| Arc::ptr_eq(it, &compilation)) {
+            compilations.remove(&key);
+        }
+        res
+    }
+}
+
+/// The in-memory caches of compiled modules, one per VM, sharing a single budget.
+///
+/// Around a protocol upgrade which switches VMs, a node uses both the old and the new VM for a
+/// while. Rather than letting each of them hold a full cache, modules of the other VMs are evicted
+/// first when a new module doesn't fit.
+#[cfg(not(feature = "no_cache"))]
+pub struct ModuleCacheRegistry {
+    budget: AtomicUsize,
+    byte_budget: AtomicUsize,
+    pub(crate) wasmer0: near_cache::ByteBudgetCache<CryptoHash, CachedModule<Wasmer0Module>>,
+    pub(crate) wasmer2: near_cache::ByteBudgetCache<CryptoHash, CachedModule<Wasmer2Module>>,
+    pub(crate) wasmer0_in_flight: InFlight<Wasmer0Module>,
+    pub(crate) wasmer2_in_flight: InFlight<Wasmer2Module>,
+    /// Compilation errors across all VMs, bounded on their own so that contracts which fail to
+    /// compile can't crowd out compiled modules.
+    errors: Mutex<lru::LruCache<CryptoHash, CompilationError>>,
+    on_evict: Mutex<Option<Arc<dyn Fn(CryptoHash) + Send + Sync>>>,
+}
+
+#[cfg(not(feature = "no_cache"))]
+impl ModuleCacheRegistry {
+    /// Creates empty caches holding at most `budget` modules across all VMs. The caches used when
+    /// running contracts are returned by [`registry`].
+    pub fn new(budget: usize) -> ModuleCacheRegistry {
+        // The budgets are enforced across both caches by `enforce_budget`.
+        ModuleCacheRegistry {
+            budget: AtomicUsize::new(budget),
+            byte_budget: AtomicUsize::new(usize::MAX),
+            wasmer0: near_cache::ByteBudgetCache::new(usize::MAX),
+            wasmer2: near_cache::ByteBudgetCache::new(usize::MAX),
+            wasmer0_in_flight: InFlight::default(),
+            wasmer2_in_flight: InFlight::default(),
+            errors: Mutex::new(lru::LruCache::new(DEFAULT_ERROR_CACHE_CAPACITY)),
+            on_evict: Mutex::new(None),
+        }
+    }
+
+    /// Sets a hook called with the key of every module evicted to fit into the budgets, e.g. to
+    /// update external metrics. Modules dropped by [`clear_module_cache`] or [`restore_memcache`]
+    /// aren't reported.
+    pub fn set_on_evict(&self, on_evict: Option<Box<dyn Fn(CryptoHash) + Send + Sync>>) {
+        *self.on_evict.lock().unwrap() = on_evict.map(Arc::from);
+    }
+
+    /// The maximal number of modules kept in memory across all VMs.
+    pub fn budget(&self) -> usize {
+        self.budget.load(Ordering::Relaxed)
+    }
+
+    /// The maximal total size of the modules kept in memory across all VMs, if bounded, see
+    /// [`ModuleCacheRegistry::set_byte_budget`].
+    pub fn byte_budget(&self) -> Option<usize> {
+        match self.byte_budget.load(Ordering::Relaxed) {
+            usize::MAX => None,
+            budget => Some(budget),
+        }
+    }
+
+    /// The number of unpinned modules kept in memory for `vm_kind`. Wasmtime modules are never
+    /// cached.
+    pub fn len(&self, vm_kind: VMKind) -> usize {
+        match vm_kind {
+            VMKind::Wasmer0 => self.wasmer0.len(),
+            VMKind::Wasmer2 => self.wasmer2.len(),
+            VMKind::Wasmtime => 0,
+        }
+    }
+
+    /// The number of unpinned modules kept in memory across all VMs.
+    pub fn total_len(&self) -> usize {
+        self.wasmer0.len() + self.wasmer2.len()
+    }
+
+    /// The total size of the unpinned modules kept in memory across all VMs.
+    pub fn total_weight(&self) -> usize {
+        self.wasmer0.weight() + self.wasmer2.weight()
+    }
+
+    /// The number of compilation errors kept in memory across all VMs.
+    pub fn error_len(&self) -> usize {
+        self.errors.lock().unwrap().len()
+    }
+
+    /// Changes the maximal number of compilation errors kept in memory across all VMs, evicting
+    /// the least recently used ones if the cache shrinks.
+    pub fn set_error_budget(&self, budget: usize) {
+        self.errors.lock().unwrap().resize(budget);
+    }
+
+    /// Whether the module under `key` is kept in memory for `vm_kind`.
+    pub fn contains(&self, vm_kind: VMKind, key: &CryptoHash) -> bool {
+        match vm_kind {
+            VMKind::Wasmer0 => self.wasmer0.contains(key),
+            VMKind::Wasmer2 => self.wasmer2.contains(key),
+            VMKind::Wasmtime => false,
+        }
+    }
+
+    /// Whether the module under `key` is pinned for `vm_kind`, see [`pin_module`]. Wasmtime
+    /// modules are never cached.
+    pub fn is_pinned(&self, vm_kind: VMKind, key: &CryptoHash) -> bool {
+        match vm_kind {
+            VMKind::Wasmer0 => self.wasmer0.is_pinned(key),
+            VMKind::Wasmer2 => self.wasmer2.is_pinned(key),
+            VMKind::Wasmtime => false,
+        }
+    }
+
+    pub(crate) fn pin(&self, key: CryptoHash) {
+        // Keys are distinct across VMs, so there is no need to know which VM the module is for.
+        self.wasmer0.pin(key);
+        self.wasmer2.pin(key);
+    }
+
+    pub(crate) fn unpin(&self, key: &CryptoHash) {
+        self.wasmer0.unpin(key);
+        self.wasmer2.unpin(key);
+        self.enforce_budget(VMKind::Wasmer2);
+    }
+
+    /// Drops all unpinned modules, see [`clear_module_cache`]. They aren't reported to the hook
+    /// set with [`ModuleCacheRegistry::set_on_evict`].
+    pub fn clear(&self) {
+        self.wasmer0.clear();
+        self.wasmer2.clear();
+        self.errors.lock().unwrap().clear();
+    }
+
+    /// Captures the modules currently in memory, see [`snapshot_memcache`].
+    pub fn snapshot(&self) -> MemcacheSnapshot {
+        MemcacheSnapshot { wasmer0: self.wasmer0.entries(), wasmer2: self.wasmer2.entries() }
+    }
+
+    /// Replaces the modules in memory with the ones in `snapshot`, see [`restore_memcache`].
+    pub fn restore(&self, snapshot: &MemcacheSnapshot) {
+        self.clear();
+        for (key, module, weight) in &snapshot.wasmer0 {
+            self.wasmer0.put(*key, module.clone(), *weight);
+        }
+        for (key, module, weight) in &snapshot.wasmer2 {
+            self.wasmer2.put(*key, module.clone(), *weight);
+        }
+        self.enforce_budget(VMKind::Wasmer2);
+    }
+
+    /// Changes the maximal number of modules kept in memory across all VMs, evicting the least
+    /// recently used modules if the caches shrink.
+    pub fn set_budget(&self, budget: usize) {
+        self.budget.store(budget, Ordering::Relaxed);
+        self.enforce_budget(VMKind::Wasmer2);
+    }
+
+    /// Bounds the caches by the total size in bytes of the modules' serialized artifacts, on top of
+    /// their number. `None`, the default, only bounds the number of modules.
+    ///
+    /// Artifact sizes vary a lot between contracts, so this keeps the memory used by the caches
+    /// predictable. Modules compiled without a persistent cache are never serialized, their weight
+    /// is the size of their wasm code instead.
+    pub fn set_byte_budget(&self, budget: Option<usize>) {
+        self.byte_budget.store(budget.unwrap_or(usize::MAX), Ordering::Relaxed);
+        self.enforce_budget(VMKind::Wasmer2);
+    }
+
+    /// Looks up the module or the compilation error kept under `key` in `modules`.
+    pub(crate) fn get<M: Clone>(
+        &self,
+        modules: &near_cache::ByteBudgetCache<CryptoHash, CachedModule<M>>,
+        key: &CryptoHash,
+    ) -> Option<CachedModule<M>> {
+        modules
+            .get(key)
+            .or_else(|| self.errors.lock().unwrap().get(key).cloned().map(|err| Ok(Err(err))))
+    }
+
+    /// Keeps `res` in memory if [`keep_in_memory`] allows it: a compiled module in `modules`,
+    /// which belong to `vm_kind`, and a compilation error apart from them. Returns whether `res`
+    /// was kept.
+    pub(crate) fn keep<M: Clone>(
+        &self,
+        modules: &near_cache::ByteBudgetCache<CryptoHash, CachedModule<M>>,
+        vm_kind: VMKind,
+        key: CryptoHash,
+        res: &CachedModule<M>,
+        weight: usize,
+        options: &CacheOptions,
+    ) -> bool {
+        if !keep_in_memory(res, options) {
+            return false;
+        }
+        match res {
+            Ok(Ok(_)) => {
+                modules.put(key, res.clone(), weight);
+                self.enforce_budget(vm_kind);
+            }
+            Ok(Err(err)) => {
+                self.errors.lock().unwrap().put(key, err.clone());
+            }
+            Err(_) => {}
+        }
+        true
+    }
+
+    fn over_budget(&self) -> bool {
+        self.total_len() > self.budget()
+            || self.total_weight() > self.byte_budget.load(Ordering::Relaxed)
+    }
+
+    /// Evicts least recently used modules until the caches fit into the budgets, taking them from
+    /// the VMs other than `used` first.
+    pub(crate) fn enforce_budget(&self, used: VMKind) {
+        while self.over_budget() {
+            let wasmer0 = || self.wasmer0.pop_lru().map(|(key, _)| key);
+            let wasmer2 = || self.wasmer2.pop_lru().map(|(key, _)| key);
+            let evicted = match used {
+                VMKind::Wasmer0 => wasmer2().or_else(wasmer0),
+                VMKind::Wasmer2 | VMKind::Wasmtime => wasmer0().or_else(wasmer2),
+            };
+            let key = match evicted {
+                Some(key) => key,
+                None => break,
+            };
+            // Not called under the lock, so that the hook may use the registry.
+            let on_evict = self.on_evict.lock().unwrap().clone();
+            if let Some(on_evict) = on_evict {
+                on_evict(key);
+            }
+        }
+    }
+}
+
+/// The contents of the in-memory caches, taken with [`snapshot_memcache`].
+///
+/// Compiled modules are reference counted, so a snapshot shares them with the caches rather than
+/// copying or serializing them.
+#[cfg(not(feature = "no_cache"))]
+#[derive(Clone)]
+pub struct MemcacheSnapshot {
+    wasmer0: Vec<(CryptoHash, CachedModule<Wasmer0Module>, usize)>,
+    wasmer2: Vec<(CryptoHash, CachedModule<Wasmer2Module>, usize)>,
+}
+
+#[cfg(not(feature = "no_cache"))]
+impl MemcacheSnapshot {
+    /// The keys of the modules in the snapshot, across all VMs.
+    pub fn keys(&self) -> Vec<CryptoHash> {
+        let wasmer0 = self.wasmer0.iter().map(|(key, _, _)| *key);
+        wasmer0.chain(self.wasmer2.iter().map(|(key, _, _)| *key)).collect()
+    }
+}
+
+/// Captures the modules in the in-memory caches, so that a benchmark can reinstall them with
+/// [`restore_memcache`] before every iteration and always start from the same state.
+#[cfg(not(feature = "no_cache"))]
+pub fn snapshot_memcache() -> MemcacheSnapshot {
+    registry().snapshot()
+}
+
+/// Replaces the modules in the in-memory caches with the ones in `snapshot`. Pinned keys stay
+/// pinned.
+#[cfg(not(feature = "no_cache"))]
+pub fn restore_memcache(snapshot: &MemcacheSnapshot) {
+    registry().restore(snapshot)
+}
+
+/// Whether `res` may be kept in the in-memory caches. Failed cache accesses never are, as they
+/// may be transient and would otherwise stick until evicted. Compilation errors are, unless
+/// `options` disable caching them.
+#[cfg(not(feature = "no_cache"))]
+fn keep_in_memory<M>(res: &CachedModule<M>, options: &CacheOptions) -> bool {
+    match res {
+        Ok(Ok(_)) => true,
+        Ok(Err(_)) => options.cache_compilation_errors,
+        Err(_) => false,
+    }
+}
+
+/// Splits the weight, i.e. the artifact size, off a compiled module. Errors weigh nothing.
+fn split_weight<M>(res: CachedModule<(M, usize)>) -> (CachedModule<M>, usize) {
+    match res {
+        Ok(Ok((module, weight))) => (Ok(Ok(module)), weight),
+        Ok(Err(err)) => (Ok(Err(err)), 0),
+        Err(err) => (Err(err), 0),
+    }
+}
+
+#[cfg(not(feature = "no_cache"))]
+static MODULE_CACHE_REGISTRY: once_cell::sync::Lazy<ModuleCacheRegistry> =
+    once_cell::sync::Lazy::new(|| ModuleCacheRegistry::new(DEFAULT_MODULE_CACHE_CAPACITY));
+
+/// Returns the process-wide in-memory module caches, for inspecting their sizes.
+#[cfg(not(feature = "no_cache"))]
+pub fn registry() -> &'static ModuleCacheRegistry {
+    &MODULE_CACHE_REGISTRY
+}
+
+#[cfg(feature = "wasmer0_vm")]
+pub mod wasmer0_cache {
+    use super::*;
+    use near_vm_errors::CompilationError;
+    use wasmer_runtime::{compiler_for_backend, Backend};
+    use wasmer_runtime_core::cache::Artifact;
+    use wasmer_runtime_core::load_cache_with;
+
+    /// Compiles `code`, preparing it first unless it is `already_prepared`.
+    pub(crate) fn compile_module(
+        code: &[u8],
+        config: &VMConfig,
+        already_prepared: bool,
+    ) -> Result<wasmer_runtime::Module, CompilationError> {
+        let _span = tracing::debug_span!(target: "vm", "compile_module").entered();
+        let _timer = metrics::COMPILATION_TIME.with_label_values(&["wasmer0"]).start_timer();
+
+        let prepared_code = if already_prepared {
+            code.to_vec()
+        } else {
+            prepare::prepare_contract(code, config).map_err(CompilationError::PrepareError)?
+        };
+        wasmer_runtime::compile(&prepared_code).map_err(|err| match err {
+            wasmer_runtime::error::CompileError::ValidationError { .. } => {
+                CompilationError::WasmerCompileError { msg: err.to_string() }
+            }
+            // NOTE: Despite the `InternalError` name, this failure occurs if
+            // the input `code` is invalid wasm.
+            wasmer_runtime::error::CompileError::InternalError { .. } => {
+                CompilationError::WasmerCompileError { msg: err.to_string() }
+            }
+        })
+    }
+
+    pub(crate) fn compile_and_serialize_wasmer(
+        wasm_code: &[u8],
+        config: &VMConfig,
+        key: &CryptoHash,
+        cache: &dyn CompiledContractCache,
+        options: &CacheOptions,
+    ) -> Result<Result<wasmer_runtime::Module, CompilationError>, CacheError> {
+        let res =
+            compile_and_serialize_wasmer_sized(wasm_code, false, config, key, cache, options)?;
+        Ok(res.map(|(module, _size)| module))
+    }
+
+    /// Like [`compile_and_serialize_wasmer`], but also returns the size of the record written to
+    /// the cache. `wasm_code` is only prepared if it is not `already_prepared`.
+    pub(crate) fn compile_and_serialize_wasmer_sized(
+        wasm_code: &[u8],
+        already_prepared: bool,
+        config: &VMConfig,
+        key: &CryptoHash,
+        cache: &dyn CompiledContractCache,
+        options: &CacheOptions,
+    ) -> Result<Result<(wasmer_runtime::Module, usize), CompilationError>, CacheError> {
+        let _span = tracing::debug_span!(target: "vm", "compile_and_serialize_wasmer").entered();
+
+        let module = match compile_module(wasm_code, config, already_prepared) {
+            Ok(module) => module,
+            Err(err) => {
+                cache_error(&err, key, VMKind::Wasmer0, cache, options)?;
+                return Ok(Err(err));
+            }
+        };
+
+        let code = checked_artifact(module.cache().and_then(|it| it.serialize()), key)?;
+        let serialized =
+            CacheRecord::code_for_vm(code, options.artifact_compression, VMKind::Wasmer0)
+                .to_cached_bytes();
+        metrics::SERIALIZED_MODULE_SIZE
+            .with_label_values(&["wasmer0"])
+            .observe(serialized.len() as f64);
+        cache.put(key.as_ref(), &serialized).map_err(|_io_err| CacheError::WriteError)?;
+        index_vm_hash(key, VMKind::Wasmer0, options);
+        Ok(Ok((module, serialized.len())))
+    }
+
+    /// Deserializes contract or error from the binary data. Signature means that we could either
+    /// return module or cached error, which both considered to be `Ok()`, or encounter an error during
+    /// the deserialization process.
+    pub(crate) fn deserialize_wasmer(
+        serialized: &[u8],
+    ) -> Result<Result<wasmer_runtime::Module, CompilationError>, CacheError> {
+        let _span = tracing::debug_span!(target: "vm", "deserialize_wasmer").entered();
+        let _timer = metrics::DESERIALIZATION_TIME.with_label_values(&["wasmer0"]).start_timer();
+
+        let record = CacheRecord::from_bytes(serialized)?;
+        let serialized_artifact = match record.into_code_for(VMKind::Wasmer0)? {
+            Ok(code) => code,
+            Err(err) => return Ok(Err(err)),
+        };
+        let artifact = Artifact::deserialize(serialized_artifact.as_slice())
+            .map_err(|_e| CacheError::DeserializationError)?;
+        unsafe {
+            let compiler = compiler_for_backend(Backend::Singlepass).unwrap();
+            match load_cache_with(artifact, compiler.as_ref()) {
+                Ok(module) => Ok(Ok(module)),
+                Err(_) => Err(CacheError::DeserializationError),
+            }
+        }
+    }
+
+    /// Returns the module along with its weight for the in-memory cache, see
+    /// [`ModuleCacheRegistry::set_byte_budget`].
+    fn compile_module_cached_wasmer_impl(
+        key: CryptoHash,
+        wasm_code: &[u8],
+        config: &VMConfig,
+        cache: Option<&dyn CompiledContractCache>,
+        options: &CacheOptions,
+    ) -> Result<Result<(wasmer_runtime::Module, usize), CompilationError>, CacheError> {
+        match cache {
+            None => {
+                record_miss();
+                let res = compile_module(wasm_code, config, false);
+                Ok(res.map(|module| (module, wasm_code.len())))
+            }
+            Some(cache) => {
+                let serialized = cache_get(cache, &key).map_err(|_io_err| CacheError::ReadError)?;
+                match serialized {
+                    Some(serialized) => {
+                        let deserialized =
+                            check_record_len(serialized.len(), options.max_record_bytes)
+                                .and_then(|()| deserialize_wasmer(&serialized))
+                                .map(|res| res.map(|module| (module, serialized.len())));
+                        if deserialized.is_ok() {
+                            upgrade_outdated_record(&key, cache, options, &serialized);
+                        }
+                        or_recompile(deserialized, &key, cache, options, || {
+                            compile_and_serialize_wasmer_sized(
+                                wasm_code, false, config, &key, cache, options,
+                            )
+                        })
+                    }
+                    None => compile_and_serialize_wasmer_sized(
+                        wasm_code, false, config, &key, cache, options,
+                    ),
+                }
+            }
+        }
+    }
+
+    #[cfg(not(feature = "no_cache"))]
+    fn memcache_compile_module_cached_wasmer(
+        registry: &ModuleCacheRegistry,
+        key: CryptoHash,
+        code: &ContractCode,
+        config: &VMConfig,
+        cache: Option<&dyn CompiledContractCache>,
+        options: &CacheOptions,
+    ) -> Result<Result<wasmer_runtime::Module, CompilationError>, CacheError> {
+        if let Some(res) = registry.get(&registry.wasmer0, &key) {
+            record_memory_hit();
+            return res;
+        }
+        let mut compiled = false;
+        let res = registry.wasmer0_in_flight.run(key, || {
+            compiled = true;
+            let (res, weight) = split_weight(compile_module_cached_wasmer_impl(
+                key,
+                code.code(),
+                config,
+                cache,
+                options,
+            ));
+            registry.keep(&registry.wasmer0, VMKind::Wasmer0, key, &res, weight, options);
+            res
+        });
+        // Waiters only count as hits for outcomes which are kept in memory.
+        if !compiled && keep_in_memory(&res, options) {
+            record_memory_hit();
+        }
+        res
+    }
+
+    /// With `bypass_memcache` set, the in-memory module cache is neither consulted nor updated.
+    #[cfg_attr(feature = "no_cache", allow(unused_variables))]
+    pub(crate) fn compile_module_cached_wasmer0(
+        code: &ContractCode,
+        config: &VMConfig,
+        cache: Option<&dyn CompiledContractCache>,
+        options: &CacheOptions,
+        bypass_memcache: bool,
+    ) -> Result<Result<wasmer_runtime::Module, CompilationError>, CacheError> {
+        let key = get_contract_cache_key_with_options(code, VMKind::Wasmer0, config, options);
+        #[cfg(not(feature = "no_cache"))]
+        if !bypass_memcache {
+            return memcache_compile_module_cached_wasmer(
+                registry(),
+                key,
+                code,
+                config,
+                cache,
+                options,
+            );
+        }
+        split_weight(compile_module_cached_wasmer_impl(key, code.code(), config, cache, options)).0
+    }
+}
+
+#[cfg(feature = "wasmer2_vm")]
+pub mod wasmer2_cache {
+    use near_primitives::contract::ContractCode;
+
+    use super::*;
+
+    /// Compiles `code`, preparing it first unless it is `already_prepared`, once `permits` gives
+    /// it a turn.
+    fn compile_module_wasmer2(
+        code: &[u8],
+        config: &VMConfig,
+        store: &wasmer::Store,
+        already_prepared: bool,
+        permits: &Arc<CompileSemaphore>,
+    ) -> Result<wasmer::Module, CompilationError> {
+        let _permit = permits.acquire();
+        compile_module_wasmer2_unlimited(code, config, store, already_prepared)
+    }
+
+    /// Like [`compile_module_wasmer2`], but gives up with `ContractPrecompilatonError::Timeout`
+    /// if compiling takes longer than `timeout`, see [`compile_with_timeout`]. Waiting for a turn
+    /// to compile happens before the timeout starts.
+    pub(crate) fn compile_module_wasmer2_with_timeout(
+        code: &[u8],
+        config: &VMConfig,
+        store: &wasmer::Store,
+        already_prepared: bool,
+        permits: &Arc<CompileSemaphore>,
+        timeout: Duration,
+    ) -> Result<Result<wasmer::Module, CompilationError>, ContractPrecompilatonError> {
+        let (code, config, store) = (code.to_vec(), config.clone(), store.clone());
+        compile_with_timeout(permits, timeout, move || {
+            compile_module_wasmer2_unlimited(&code, &config, &store, already_prepared)
+        })
+    }
+
+    /// Like [`compile_module_wasmer2`], but doesn't wait for a turn to compile, which the caller
+    /// has to hold.
+    fn compile_module_wasmer2_unlimited(
+        code: &[u8],
+        config: &VMConfig,
+        store: &wasmer::Store,
+        already_prepared: bool,
+    ) -> Result<wasmer::Module, CompilationError> {
+        let _span = tracing::debug_span!(target: "vm", "compile_module_wasmer2").entered();
+        let _timer = metrics::COMPILATION_TIME.with_label_values(&["wasmer2"]).start_timer();
+
+        let prepared_code = if already_prepared {
+            code.to_vec()
+        } else {
+            prepare::prepare_contract(code, config).map_err(CompilationError::PrepareError)?
+        };
+        wasmer::Module::new(store, prepared_code).map_err(|err| match err {
+            wasmer::CompileError::Wasm(_) => {
+                CompilationError::WasmerCompileError { msg: err.to_string() }
+            }
+            wasmer::CompileError::Codegen(_) => {
+                CompilationError::WasmerCompileError { msg: err.to_string() }
+            }
+            wasmer::CompileError::Validate(_) => {
+                CompilationError::WasmerCompileError { msg: err.to_string() }
+            }
+            wasmer::CompileError::UnsupportedFeature(_) => {
+                CompilationError::WasmerCompileError { msg: err.to_string() }
+            }
+            wasmer::CompileError::UnsupportedTarget(_) => {
+                CompilationError::WasmerCompileError { msg: err.to_string() }
+            }
+            wasmer::CompileError::Resource(_) => {
+                CompilationError::WasmerCompileError { msg: err.to_string() }
+            }
+        })
+    }
+
+    pub(crate) fn compile_and_serialize_wasmer2(
+        wasm_code: &[u8],
+        key: &CryptoHash,
+        config: &VMConfig,
+        cache: &dyn CompiledContractCache,
+        options: &CacheOptions,
+        store: &wasmer::Store,
+    ) -> Result<Result<wasmer::Module, CompilationError>, CacheError> {
+        let res = compile_and_serialize_wasmer2_sized(
+            wasm_code, false, key, config, cache, options, store,
+        )?;
+        Ok(res.map(|(module, _size)| module))
+    }
+
+    /// Like [`compile_and_serialize_wasmer2`], but also returns the size of the record written to
+    /// the cache. `wasm_code` is only prepared if it is not `already_prepared`. Records larger
+    /// than [`CacheOptions::max_cache_artifact_bytes`] aren't written, but their size is still
+    /// returned.
+    pub(crate) fn compile_and_serialize_wasmer2_sized(
+        wasm_code: &[u8],
+        already_prepared: bool,
+        key: &CryptoHash,
+        config: &VMConfig,
+        cache: &dyn CompiledContractCache,
+        options: &CacheOptions,
+        store: &wasmer::Store,
+    ) -> Result<Result<(wasmer::Module, usize), CompilationError>, CacheError> {
+        let _span = tracing::debug_span!(target: "vm", "compile_and_serialize_wasmer2").entered();
+
+        let res = compile_module_wasmer2(
+            wasm_code,
+            config,
+            store,
+            already_prepared,
+            &options.compile_permits,
+        );
+        cache_compiled_wasmer2(res, key, cache, options)
+    }
+
+    /// Writes the outcome of compiling the contract to the cache under `key`: either the module,
+    /// whose record size is returned along with it, or the compilation error. Records over
+    /// [`CacheOptions::max_cache_artifact_bytes`] aren't written.
+    pub(crate) fn cache_compiled_wasmer2(
+        res: Result<wasmer::Module, CompilationError>,
+        key: &CryptoHash,
+        cache: &dyn CompiledContractCache,
+        options: &CacheOptions,
+    ) -> Result<Result<(wasmer::Module, usize), CompilationError>, CacheError> {
+        let module = match res {
+            Ok(module) => module,
+            Err(err) => {
+                cache_error(&err, key, VMKind::Wasmer2, cache, options)?;
+                return Ok(Err(err));
+            }
+        };
+
+        let code = checked_artifact(module.serialize(), key)?;
+        let vm_hash = wasmer2_store_vm_hash(module.store());
+        let serialized = CacheRecord::code_for_vm_hash(code, options.artifact_compression, vm_hash)
+            .to_cached_bytes();
+        metrics::SERIALIZED_MODULE_SIZE
+            .with_label_values(&["wasmer2"])
+            .observe(serialized.len() as f64);
+        if options.max_cache_artifact_bytes.map_or(false, |max| serialized.len() > max) {
+            tracing::debug!(
+                target: "vm",
+                "Not caching {}: its record of {} bytes is too large",
+                key,
+                serialized.len()
+            );
+            return Ok(Ok((module, serialized.len())));
+        }
+        // Another thread might have compiled the same contract concurrently, there is no need to
+        // write it twice. An expired error record doesn't count, it is what made us compile the
+        // contract again, so it has to be replaced.
+        let written = cache
+            .put_if_absent(key.as_ref(), &serialized)
+            .map_err(|_io_err| CacheError::WriteError)?;
+        if !written {
+            let existing = cache.get(key.as_ref()).map_err(|_io_err| CacheError::ReadError)?;
+            if existing.map_or(true, |it| CacheRecord::is_expired(&it)) {
+                cache.put(key.as_ref(), &serialized).map_err(|_io_err| CacheError::WriteError)?;
+            }
+        }
+        index_vm_hash(key, VMKind::Wasmer2, options);
+        Ok(Ok((module, serialized.len())))
+    }
+
+    /// Compiles the contract and returns the record the cache would store for it, without writing
+    /// anything to a cache.
+    pub(crate) fn compile_to_record_wasmer2(
+        code: &ContractCode,
+        config: &VMConfig,
+        options: &CacheOptions,
+        store: &wasmer::Store,
+    ) -> Result<Result<Vec<u8>, CompilationError>, CacheError> {
+        let permits = &options.compile_permits;
+        let module = match compile_module_wasmer2(code.code(), config, store, false, permits) {
+            Ok(module) => module,
+            Err(err) => return Ok(Err(err)),
+        };
+        let artifact = checked_artifact(module.serialize(), code.hash())?;
+        let vm_hash = wasmer2_store_vm_hash(store);
+        let record = CacheRecord::code_for_vm_hash(artifact, options.artifact_compression, vm_hash);
+        Ok(Ok(record.to_bytes()))
+    }
+
+    /// Checks that `serialized`, the record cached under `key`, holds what compiling `code` from
+    /// scratch produces, see [`CacheOptions::verify_cache_consistency`]. Records which can't be
+    /// decoded are left to the regular error handling and count as consistent.
+    pub(crate) fn verify_cached_artifact(
+        code: &ContractCode,
+        key: &CryptoHash,
+        config: &VMConfig,
+        store: &wasmer::Store,
+        permits: &Arc<CompileSemaphore>,
+        serialized: &[u8],
+    ) -> bool {
+        let cached = match CacheRecord::from_bytes(serialized).and_then(CacheRecord::into_code) {
+            Ok(cached) => cached,
+            Err(_) => return true,
+        };
+        let fresh = compile_module_wasmer2(code.code(), config, store, false, permits)
+            .map(|module| module.serialize().ok());
+        let consistent = match (&cached, &fresh) {
+            (Ok(cached), Ok(Some(fresh))) => cached == fresh,
+            (Err(cached), Err(fresh)) => cached == fresh,
+            _ => false,
+        };
+        if !consistent {
+            CACHE_COUNTERS.inconsistencies.fetch_add(1, Ordering::Relaxed);
+            tracing::error!(
+                target: "vm",
+                "Cached artifact {} differs from a fresh compilation of contract {}",
+                key,
+                code.hash()
+            );
+        }
+        consistent
+    }
+
+    pub(crate) fn deserialize_wasmer2(
+        serialized: &[u8],
+        store: &wasmer::Store,
+    ) -> Result<Result<wasmer::Module, CompilationError>, CacheError> {
+        let _span = tracing::debug_span!(target: "vm", "deserialize_wasmer2").entered();
+        let _timer = metrics::DESERIALIZATION_TIME.with_label_values(&["wasmer2"]).start_timer();
+
+        let record = CacheRecord::from_bytes(serialized)?;
+        let serialized_module = match record.into_code_for_vm_hash(wasmer2_store_vm_hash(store))? {
+            Ok(code) => code,
+            Err(err) => return Ok(Err(err)),
+        };
+        unsafe {
+            Ok(Ok(wasmer::Module::deserialize(store, serialized_module.as_slice())
+                .map_err(|_e| CacheError::DeserializationError)?))
+        }
+    }
+
+    /// Runs `compile`, reporting the contract's code hash and the time it took to `on_compile`.
+    fn observe_compilation<T>(
+        code: &ContractCode,
+        on_compile: Option<&dyn Fn(CryptoHash, Duration)>,
+        compile: impl FnOnce() -> T,
+    ) -> T {
+        let start = Instant::now();
+        let res = compile();
+        if let Some(on_compile) = on_compile {
+            on_compile(*code.hash(), start.elapsed());
+        }
+        res
+    }
+
+    /// Returns the module along with its weight for the in-memory cache, see
+    /// [`ModuleCacheRegistry::set_byte_budget`]. `on_deserialize` is called with the time it took
+    /// to deserialize the record found in `cache`, if any.
+    #[allow(clippy::too_many_arguments)]
+    fn compile_module_cached_wasmer2_impl(
+        key: CryptoHash,
+        code: &ContractCode,
+        config: &VMConfig,
+        cache: Option<&dyn CompiledContractCache>,
+        options: &CacheOptions,
+        store: &wasmer::Store,
+        on_compile: Option<&dyn Fn(CryptoHash, Duration)>,
+        on_deserialize: Option<&dyn Fn(Duration)>,
+    ) -> Result<Result<(wasmer::Module, usize), CompilationError>, CacheError> {
+        let compile_and_serialize = |cache: &dyn CompiledContractCache| {
+            if options.require_cached {
+                return Err(CacheError::MissingCachedArtifact);
+            }
+            observe_compilation(code, on_compile, || {
+                compile_and_serialize_wasmer2_sized(
+                    code.code(),
+                    false,
+                    &key,
+                    config,
+                    cache,
+                    options,
+                    store,
+                )
+            })
+        };
+        let compile_uncached = || {
+            let res = observe_compilation(code, on_compile, || {
+                compile_module_wasmer2(code.code(), config, store, false, &options.compile_permits)
+            });
+            Ok(res.map(|module| (module, code.code().len())))
+        };
+        match cache {
+            None if options.require_cached => {
+                record_miss();
+                Err(CacheError::MissingCachedArtifact)
+            }
+            None => {
+                record_miss();
+                compile_uncached()
+            }
+            Some(cache) => {
+                let serialized = match cache_get(cache, &key) {
+                    Ok(serialized) => serialized,
+                    Err(err)
+                        if !options.require_cached
+                            && options.on_read_error == ReadErrorPolicy::RecompileUncached =>
+                    {
+                        tracing::warn!(
+                            target: "vm",
+                            "Failed to read cached contract {}, compiling it uncached: {}",
+                            key,
+                            err
+                        );
+                        return compile_uncached();
+                    }
+                    Err(_io_err) => return Err(CacheError::ReadError),
+                };
+                match serialized {
+                    Some(serialized) => {
+                        if options.verify_cache_consistency
+                            && !verify_cached_artifact(
+                                code,
+                                &key,
+                                config,
+                                store,
+                                &options.compile_permits,
+                                &serialized,
+                            )
+                        {
+                            cache.remove(&key.0).map_err(|_io_err| CacheError::WriteError)?;
+                            return compile_and_serialize(cache);
+                        }
+                        let deserialized =
+                            check_record_len(serialized.len(), options.max_record_bytes)
+                                .and_then(|()| {
+                                    let start = Instant::now();
+                                    let res = deserialize_wasmer2(&serialized, store);
+                                    if let Some(on_deserialize) = on_deserialize {
+                                        on_deserialize(start.elapsed());
+                                    }
+                                    res
+                                })
+                                .map(|res| res.map(|module| (module, serialized.len())));
+                        if deserialized.is_ok() {
+                            upgrade_outdated_record(&key, cache, options, &serialized);
+                        }
+                        or_recompile(deserialized, &key, cache, options, || {
+                            compile_and_serialize(cache)
+                        })
+                    }
+                    None => compile_and_serialize(cache),
+                }
+            }
+        }
+    }
+
+    /// Looks the module up in `registry`, compiling it and keeping it there on a miss. Also returns
+    /// whether the module came from memory.
+    #[cfg(not(feature = "no_cache"))]
+    pub(crate) fn memcache_compile_module_cached_wasmer2(
+        registry: &ModuleCacheRegistry,
+        key: CryptoHash,
+        code: &ContractCode,
+        config: &VMConfig,
+        cache: Option<&dyn CompiledContractCache>,
+        options: &CacheOptions,
+        store: &wasmer::Store,
+        on_compile: Option<&dyn Fn(CryptoHash, Duration)>,
+    ) -> (Result<Result<wasmer::Module, CompilationError>, CacheError>, bool) {
+        if let Some(res) = registry.get(&registry.wasmer2, &key) {
+            record_memory_hit();
+            return (res, true);
+        }
+        let compile = || {
+            let (res, weight) = split_weight(compile_module_cached_wasmer2_impl(
+                key, code, config, cache, options, store, on_compile, None,
+            ));
+            registry.keep(&registry.wasmer2, VMKind::Wasmer2, key, &res, weight, options);
+            res
+        };
+        // Callers which require the artifact to be cached must not share the outcome with callers
+        // which would compile it.
+        if options.require_cached {
+            return (compile(), false);
+        }
+        let mut compiled = false;
+        let res = registry.wasmer2_in_flight.run(key, || {
+            compiled = true;
+            compile()
+        });
+        // Waiters only count as hits for outcomes which are kept in memory.
+        if !compiled && keep_in_memory(&res, options) {
+            record_memory_hit();
+        }
+        (res, !compiled)
+    }
+
+    /// With `bypass_memcache` set, the in-memory module cache is neither consulted nor updated.
+    ///
+    /// `on_compile` is called with the code hash and the elapsed time whenever the contract has
+    /// to be compiled, including writing the artifact to `cache` if there is one.
+    pub(crate) fn compile_module_cached_wasmer2(
+        code: &ContractCode,
+        config: &VMConfig,
+        cache: Option<&dyn CompiledContractCache>,
+        options: &CacheOptions,
+        store: &wasmer::Store,
+        bypass_memcache: bool,
+        on_compile: Option<&dyn Fn(CryptoHash, Duration)>,
+    ) -> Result<Result<wasmer::Module, CompilationError>, CacheError> {
+        get_module_cached_wasmer2(code, config, cache, options, store, bypass_memcache, on_compile)
+            .0
+    }
+
+    /// Where a module returned by [`compile_module_cached_wasmer2_with_origin`] came from.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum CacheOrigin {
+        /// The in-memory module cache, including a compilation of the same contract which was
+        /// already in progress on another thread.
+        MemCache,
+        /// The persistent cache passed in.
+        PersistentCache,
+        /// The contract was compiled.
+        FreshCompile,
+    }
+
+    /// Like [`compile_module_cached_wasmer2`], but also tells where the module came from.
+    pub fn compile_module_cached_wasmer2_with_origin(
+        code: &ContractCode,
+        config: &VMConfig,
+        cache: Option<&dyn CompiledContractCache>,
+        options: &CacheOptions,
+        store: &wasmer::Store,
+        bypass_memcache: bool,
+        on_compile: Option<&dyn Fn(CryptoHash, Duration)>,
+    ) -> Result<Result<(wasmer::Module, CacheOrigin), CompilationError>, CacheError> {
+        let (res, origin) = get_module_cached_wasmer2(
+            code,
+            config,
+            cache,
+            options,
+            store,
+            bypass_memcache,
+            on_compile,
+        );
+        res.map(|res| res.map(|module| (module, origin)))
+    }
+
+    #[cfg_attr(feature = "no_cache", allow(unused_variables))]
+    fn get_module_cached_wasmer2(
+        code: &ContractCode,
+        config: &VMConfig,
+        cache: Option<&dyn CompiledContractCache>,
+        options: &CacheOptions,
+        store: &wasmer::Store,
+        bypass_memcache: bool,
+        on_compile: Option<&dyn Fn(CryptoHash, Duration)>,
+    ) -> (Result<Result<wasmer::Module, CompilationError>, CacheError>, CacheOrigin) {
+        let key = get_contract_cache_key_with_options(code, VMKind::Wasmer2, config, options);
+        let compiled = std::cell::Cell::new(false);
+        let observe_compile = |hash: CryptoHash, elapsed: Duration| {
+            compiled.set(true);
+            if let Some(on_compile) = on_compile {
+                on_compile(hash, elapsed);
+            }
+        };
+        let origin = |from_memory: bool| match (from_memory, compiled.get()) {
+            (true, _) => CacheOrigin::MemCache,
+            (false, true) => CacheOrigin::FreshCompile,
+            (false, false) => CacheOrigin::PersistentCache,
+        };
+        #[cfg(not(feature = "no_cache"))]
+        if !bypass_memcache {
+            let (res, from_memory) = memcache_compile_module_cached_wasmer2(
+                registry(),
+                key,
+                code,
+                config,
+                cache,
+                options,
+                store,
+                Some(&observe_compile),
+            );
+            return (res, origin(from_memory));
+        }
+        let (res, _weight) = split_weight(compile_module_cached_wasmer2_impl(
+            key,
+            code,
+            config,
+            cache,
+            options,
+            store,
+            Some(&observe_compile),
+            None,
+        ));
+        (res, origin(false))
+    }
+
+    /// Like [`compile_module_cached_wasmer2`] with the in-memory cache bypassed, but also returns
+    /// how long deserializing the record found in `cache`, and compiling the contract, took, if
+    /// they happened.
+    pub(crate) fn compile_module_cached_wasmer2_timed(
+        code: &ContractCode,
+        config: &VMConfig,
+        cache: Option<&dyn CompiledContractCache>,
+        options: &CacheOptions,
+        store: &wasmer::Store,
+    ) -> (
+        Result<Result<wasmer::Module, CompilationError>, CacheError>,
+        Option<Duration>,
+        Option<Duration>,
+    ) {
+        let key = get_contract_cache_key_with_options(code, VMKind::Wasmer2, config, options);
+        let (deserialize, compile) = (std::cell::Cell::new(None), std::cell::Cell::new(None));
+        let on_deserialize = |elapsed: Duration| deserialize.set(Some(elapsed));
+        let on_compile = |_hash: CryptoHash, elapsed: Duration| compile.set(Some(elapsed));
+        let (res, _weight) = split_weight(compile_module_cached_wasmer2_impl(
+            key,
+            code,
+            config,
+            cache,
+            options,
+            store,
+            Some(&on_compile),
+            Some(&on_deserialize),
+        ));
+        (res, deserialize.get(), compile.get())
+    }
+
+    /// Like [`compile_module_cached_wasmer2`], but talks to an [`AsyncCompiledContractCache`].
+    /// Must be called within a tokio runtime.
+    ///
+    /// The record is fetched up front and then handled by exactly the same code as in the
+    /// synchronous case, writes included, which are only sent to `cache` afterwards. Deserializing
+    /// and compiling the contract run on the blocking thread pool of the runtime.
+    #[cfg(feature = "async_cache")]
+    pub async fn compile_module_cached_wasmer2_async(
+        code: &ContractCode,
+        config: &VMConfig,
+        cache: Option<&dyn AsyncCompiledContractCache>,
+        options: &CacheOptions,
+        store: &wasmer::Store,
+    ) -> Result<Result<wasmer::Module, CompilationError>, CacheError> {
+        let key = get_contract_cache_key_with_options(code, VMKind::Wasmer2, config, options);
+        // Don't go to the network for a module which is already in memory.
+        #[cfg(not(feature = "no_cache"))]
+        if let Some(res) = registry().get(&registry().wasmer2, &key) {
+            record_memory_hit();
+            return res;
+        }
+        let staged = match cache {
+            Some(cache) => Some(StagedCache {
+                key,
+                fetched: cache.get(&key.0).await,
+                writes: Mutex::new(Vec::new()),
+            }),
+            None => None,
+        };
+        let code = ContractCode::new(code.code().to_vec(), Some(*code.hash()));
+        let (config, options, store) = (config.clone(), options.clone(), store.clone());
+        let (res, staged) = spawn_blocking(move || {
+            let cache = staged.as_ref().map(|staged| staged as &dyn CompiledContractCache);
+            let res =
+                compile_module_cached_wasmer2(&code, &config, cache, &options, &store, false, None);
+            (res, staged)
+        })
+        .await;
+        if let (Some(cache), Some(staged)) = (cache, staged) {
+            staged.flush(cache).await.map_err(|_io_err| CacheError::WriteError)?;
+        }
+        res
+    }
+
+    /// Serves the single record fetched from an [`AsyncCompiledContractCache`] to the synchronous
+    /// code path, and keeps the writes it makes until they are flushed back.
+    #[cfg(feature = "async_cache")]
+    struct StagedCache {
+        key: CryptoHash,
+        /// The outcome of the fetch.
+        fetched: Result<Option<Vec<u8>>, std::io::Error>,
+        /// Writes in the order they were made, `None` standing for a removal.
+        writes: Mutex<Vec<(Vec<u8>, Option<Vec<u8>>)>>,
+    }
+
+    #[cfg(feature = "async_cache")]
+    impl StagedCache {
+        async fn flush(self, cache: &dyn AsyncCompiledContractCache) -> std::io::Result<()> {
+            for (key, value) in self.writes.into_inner().unwrap() {
+                match value {
+                    Some(value) => cache.put(&key, &value).await?,
+                    None => cache.remove(&key).await?,
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "async_cache")]
+    impl CompiledContractCache for StagedCache {
+        fn put(&self, key: &[u8], value: &[u8]) -> Result<(), std::io::Error> {
+            self.writes.lock().unwrap().push((key.to_vec(), Some(value.to_vec())));
+            Ok(())
+        }
+
+        fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error> {
+            let writes = self.writes.lock().unwrap();
+            if let Some((_, value)) = writes.iter().rev().find(|(it, _)| it.as_slice() == key) {
+                return Ok(value.clone());
+            }
+            if key != self.key.as_ref() {
+                return Ok(None);
+            }
+            match &self.fetched {
+                Ok(value) => Ok(value.clone()),
+                Err(err) => Err(std::io::Error::new(err.kind(), err.to_string())),
+            }
+        }
+
+        fn remove(&self, key: &[u8]) -> Result<(), std::io::Error> {
+            self.writes.lock().unwrap().push((key.to_vec(), None));
+            Ok(())
+        }
+    }
+}
+
+/// Loads a wasmer2 module from a record previously written to a [`CompiledContractCache`], for
+/// embedders which fetch the records themselves. A cached compilation error is returned as a
+/// `VMError`, like it would be when running the contract.
+///
+/// # Safety
+///
+/// The module is deserialized without being validated, so `serialized` must come from
+/// [`compile_to_serialized_wasmer2`] or a cache written by this crate, and must not have been
+/// tampered with. Records written before checksums and VM hashes were introduced are accepted
+/// unchecked.
+#[cfg(feature = "wasmer2_vm")]
+pub unsafe fn load_wasmer2_module_from_bytes(
+    serialized: &[u8],
+    store: &wasmer::Store,
+) -> Result<wasmer::Module, VMError> {
+    into_vm_result(wasmer2_cache::deserialize_wasmer2(serialized, store))
+}
+
+/// Compiles `code` with wasmer2 and returns the serialized module, in the same record format as
+/// stored in a [`CompiledContractCache`], for embedders which manage storage themselves. The
+/// record can be loaded back with [`load_wasmer2_module_from_bytes`]. The record is written
+/// according to `options`, like it would be by the cache, but without a creation time, so that
+/// compiling the same contract always returns the same bytes.
+#[cfg(feature = "wasmer2_vm")]
+pub fn compile_to_serialized_wasmer2(
+    code: &ContractCode,
+    config: &VMConfig,
+    options: &CacheOptions,
+    store: &wasmer::Store,
+) -> Result<Vec<u8>, VMError> {
+    into_vm_result(wasmer2_cache::compile_to_record_wasmer2(code, config, options, store))
+}
+
+/// Returns the wasmer2 module for `code` from the cache, failing with
+/// [`CacheError::MissingCachedArtifact`] instead of compiling it if it isn't cached, whether or
+/// not [`CacheOptions::require_cached`] is set. For deployments where contracts must only be
+/// compiled during a controlled precompilation phase, so that a cache miss doesn't stall block
+/// processing.
+#[cfg(feature = "wasmer2_vm")]
+pub fn compile_wasmer2_from_artifact_only(
+    code: &ContractCode,
+    config: &VMConfig,
+    cache: &dyn CompiledContractCache,
+    options: &CacheOptions,
+    store: &wasmer::Store,
+) -> Result<wasmer::Module, VMError> {
+    let options = CacheOptions { require_cached: true, ..options.clone() };
+    into_vm_result(wasmer2_cache::compile_module_cached_wasmer2(
+        code,
+        config,
+        Some(cache),
+        &options,
+        store,
+        false,
+        None,
+    ))
+}
+
+/// Loads the wasmer2 modules stored under `keys` in the persistent cache into the in-memory module
+/// cache, so that the first execution of frequently used contracts after a restart doesn't pay for
+/// deserialization. The modules are read with a single [`CompiledContractCache::get_many`] call.
+/// Keys which are missing or can't be loaded are skipped, and nothing is loaded if the read fails.
+///
+/// The keys are the ones derived with `options`, which the records are checked and kept in memory
+/// with like when running contracts. Returns the number of loaded modules: cached compilation
+/// errors may be kept as well, but don't count.
+#[cfg(all(feature = "wasmer2_vm", not(feature = "no_cache")))]
+pub fn warm_memcache(
+    keys: &[CryptoHash],
+    cache: &dyn CompiledContractCache,
+    options: &CacheOptions,
+    store: &wasmer::Store,
+) -> usize {
+    let raw_keys: Vec<Vec<u8>> = keys.iter().map(|key| key.0.to_vec()).collect();
+    let values = match cache.get_many(&raw_keys) {
+        Ok(values) => values,
+        Err(err) => {
+            tracing::debug!(target: "vm", "Failed to read modules for warming: {}", err);
+            return 0;
+        }
+    };
+    let mut warmed = 0;
+    for (key, serialized) in keys.iter().zip(values) {
+        let serialized = match serialized {
+            Some(serialized) => serialized,
+            None => continue,
+        };
+        let res = check_record_len(serialized.len(), options.max_record_bytes)
+            .and_then(|()| wasmer2_cache::deserialize_wasmer2(&serialized, store));
+        let res = match res {
+            Ok(res) => Ok(res),
+            Err(err) => {
+                tracing::debug!(target: "vm", "Failed to load {} for warming: {:?}", key, err);
+                continue;
+            }
+        };
+        let registry = registry();
+        let kept = registry.keep(
+            &registry.wasmer2,
+            VMKind::Wasmer2,
+            *key,
+            &res,
+            serialized.len(),
+            options,
+        );
+        if kept && matches!(res, Ok(Ok(_))) {
+            warmed += 1;
+        }
+    }
+    warmed
+}
+
+/// Like [`load_wasmer2_module_from_bytes`], but only accepts records whose checksum matches and
+/// which were produced by the current version of wasmer2, so that corrupted or truncated records
+/// are rejected before reaching wasmer. Records written before checksums and VM hashes were
+/// introduced are rejected with `CacheError::DeserializationError`, as is a cached compilation
+/// error.
+///
+/// The checksum only detects accidental corruption: the artifact contents are still trusted, so
+/// only records produced by this crate should be loaded.
+#[cfg(feature = "wasmer2_vm")]
+pub fn deserialize_wasmer2_checked(
+    serialized: &[u8],
+    store: &wasmer::Store,
+) -> Result<wasmer::Module, CacheError> {
+    if CacheRecord::split(serialized)?.checksum.is_none() {
+        return Err(CacheError::DeserializationError);
+    }
+    let record = CacheRecord::from_bytes(serialized)?;
+    if !matches!(record, CacheRecord::CodeForVm { .. }) {
+        return Err(CacheError::DeserializationError);
+    }
+    let artifact = match record.into_code_for_vm_hash(wasmer2_store_vm_hash(store))? {
+        Ok(artifact) => artifact,
+        Err(_) => return Err(CacheError::DeserializationError),
+    };
+    // Safe because the record was checksummed and tagged with the hash of the VM and target of
+    // `store` when it was written by this crate.
+    unsafe {
+        wasmer::Module::deserialize(store, artifact.as_slice())
+            .map_err(|_e| CacheError::DeserializationError)
+    }
+}
+
+#[cfg(feature = "wasmtime_vm")]
+pub mod wasmtime_cache {
+    use super::*;
+    use crate::errors::IntoVMError;
+
+    /// Compiles `code`, preparing it first unless it is `already_prepared`.
+    ///
+    /// Contracts rejected by preparation fail with a `CompilationError`, which gets cached. When
+    /// wasmtime itself fails to compile the prepared code, the error is reported as it always
+    /// was, as the outer `VMError`, and is never cached.
+    fn compile_module_wasmtime(
+        code: &[u8],
+        config: &VMConfig,
+        engine: &wasmtime::Engine,
+        already_prepared: bool,
+    ) -> Result<Result<wasmtime::Module, CompilationError>, VMError> {
+        let _span = tracing::debug_span!(target: "vm", "compile_module_wasmtime").entered();
+        let _timer = metrics::COMPILATION_TIME.with_label_values(&["wasmtime"]).start_timer();
+
+        let prepared_code = if already_prepared {
+            code.to_vec()
+        } else {
+            match prepare::prepare_contract(code, config) {
+                Ok(code) => code,
+                Err(err) => return Ok(Err(CompilationError::PrepareError(err))),
+            }
+        };
+        wasmtime::Module::new(engine, prepared_code).map(Ok).map_err(|err| err.into_vm_error())
+    }
+
+    pub(crate) fn compile_and_serialize_wasmtime(
+        wasm_code: &[u8],
+        key: &CryptoHash,
+        config: &VMConfig,
+        cache: &dyn CompiledContractCache,
+        options: &CacheOptions,
+        engine: &wasmtime::Engine,
+    ) -> Result<Result<wasmtime::Module, CompilationError>, VMError> {
+        let res = compile_and_serialize_wasmtime_impl(
+            wasm_code, false, key, config, cache, options, engine,
+        );
+        res.map(|res| res.map(|(module, _size)| module))
+    }
+
+    /// Like [`compile_and_serialize_wasmtime`], but `wasm_code` is only prepared if it is not
+    /// `already_prepared`. Also returns the size of the record written to `cache`.
+    pub(crate) fn compile_and_serialize_wasmtime_impl(
+        wasm_code: &[u8],
+        already_prepared: bool,
+        key: &CryptoHash,
+        config: &VMConfig,
+        cache: &dyn CompiledContractCache,
+        options: &CacheOptions,
+        engine: &wasmtime::Engine,
+    ) -> Result<Result<(wasmtime::Module, usize), CompilationError>, VMError> {
+        let _span = tracing::debug_span!(target: "vm", "compile_and_serialize_wasmtime").entered();
+
+        let module = match compile_module_wasmtime(wasm_code, config, engine, already_prepared)? {
+            Ok(module) => module,
+            Err(err) => {
+                cache_error(&err, key, VMKind::Wasmtime, cache, options)?;
+                return Ok(Err(err));
+            }
+        };
+
+        let code = checked_artifact(module.serialize(), key)?;
+        let serialized =
+            CacheRecord::code_for_vm(code, options.artifact_compression, VMKind::Wasmtime)
+                .to_cached_bytes();
+        metrics::SERIALIZED_MODULE_SIZE
+            .with_label_values(&["wasmtime"])
+            .observe(serialized.len() as f64);
+        cache.put(key.as_ref(), &serialized).map_err(|_io_err| CacheError::WriteError)?;
+        index_vm_hash(key, VMKind::Wasmtime, options);
+        Ok(Ok((module, serialized.len())))
+    }
+
+    pub(crate) fn deserialize_wasmtime(
+        serialized: &[u8],
+        engine: &wasmtime::Engine,
+    ) -> Result<Result<wasmtime::Module, CompilationError>, CacheError> {
+        let _span = tracing::debug_span!(target: "vm", "deserialize_wasmtime").entered();
+        let _timer = metrics::DESERIALIZATION_TIME.with_label_values(&["wasmtime"]).start_timer();
+
+        let record = CacheRecord::from_bytes(serialized)?;
+        let serialized_module = match record.into_code_for(VMKind::Wasmtime)? {
+            Ok(code) => code,
+            Err(err) => return Ok(Err(err)),
+        };
+        Ok(Ok(wasmtime::Module::deserialize(engine, serialized_module.as_slice())
+            .map_err(|_e| CacheError::DeserializationError)?))
+    }
+
+    pub(crate) fn compile_module_cached_wasmtime(
+        code: &ContractCode,
+        config: &VMConfig,
+        cache: Option<&dyn CompiledContractCache>,
+        options: &CacheOptions,
+        engine: &wasmtime::Engine,
+    ) -> Result<Result<wasmtime::Module, CompilationError>, VMError> {
+        let key = get_contract_cache_key_with_options(code, VMKind::Wasmtime, config, options);
+        match cache {
+            None => {
+                record_miss();
+                compile_module_wasmtime(code.code(), config, engine, false)
+            }
+            Some(cache) => {
+                let serialized = cache_get(cache, &key).map_err(|_io_err| CacheError::ReadError)?;
+                match serialized {
+                    Some(serialized) => {
+                        let deserialized =
+                            check_record_len(serialized.len(), options.max_record_bytes)
+                                .and_then(|()| deserialize_wasmtime(&serialized, engine));
+                        if deserialized.is_ok() {
+                            upgrade_outdated_record(&key, cache, options, &serialized);
+                        }
+                        or_recompile(deserialized, &key, cache, options, || {
+                            compile_and_serialize_wasmtime(
+                                code.code(),
+                                &key,
+                                config,
+                                cache,
+                                options,
+                                engine,
+                            )
+                        })
+                    }
+                    None => compile_and_serialize_wasmtime(
+                        code.code(),
+                        &key,
+                        config,
+                        cache,
+                        options,
+                        engine,
+                    ),
+                }
+            }
+        }
+    }
+}
+
+/// A compiled contract, ready to be instantiated by the VM it was compiled for.
+pub enum VMModule {
+    #[cfg(feature = "wasmer0_vm")]
+    Wasmer0(wasmer_runtime::Module),
+    #[cfg(feature = "wasmer2_vm")]
+    Wasmer2(wasmer::Module),
+    #[cfg(feature = "wasmtime_vm")]
+    Wasmtime(wasmtime::Module),
+}
+
+/// Returns the compiled module for `code`, for the VM used at `protocol_version`. The module comes
+/// from the in-memory or the persistent cache if possible, and is compiled otherwise.
+pub fn get_or_compile_module(
+    code: &ContractCode,
+    config: &VMConfig,
+    protocol_version: ProtocolVersion,
+    cache: Option<&dyn CompiledContractCache>,
+    options: &CacheOptions,
+) -> Result<VMModule, VMError> {
+    let vm_kind = VMKind::for_protocol_version(protocol_version);
+    match vm_kind {
+        #[cfg(feature = "wasmer0_vm")]
+        VMKind::Wasmer0 => {
+            let module =
+                wasmer0_cache::compile_module_cached_wasmer0(code, config, cache, options, false);
+            into_vm_result(module).map(VMModule::Wasmer0)
+        }
+        #[cfg(feature = "wasmer2_vm")]
+        VMKind::Wasmer2 => {
+            let store = default_wasmer2_store();
+            let module = wasmer2_cache::compile_module_cached_wasmer2(
+                code, config, cache, options, &store, false, None,
+            );
+            into_vm_result(module).map(VMModule::Wasmer2)
+        }
+        #[cfg(feature = "wasmtime_vm")]
+        VMKind::Wasmtime => {
+            let engine = default_wasmtime_engine();
+            let module = wasmtime_cache::compile_module_cached_wasmtime(
+                code, config, cache, options, &engine,
+            );
+            into_vm_result(module).map(VMModule::Wasmtime)
+        }
+        #[allow(unreachable_patterns)] // reachable when some of the VMs are disabled.
+        _ => {
+            let _ = (code, config, cache, options);
+            panic!("the {:?} runtime has not been enabled at compile time", vm_kind)
+        }
+    }
+}
+
+/// Where the time of a [`load_and_time`] call went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadTiming {
+    pub total: Duration,
+    /// Time spent deserializing the module, if it was found in the persistent cache. Reading the
+    /// record and hashing the key aren't included.
+    pub deserialize: Option<Duration>,
+    /// Time spent compiling the module and writing it to the cache, if it wasn't found there.
+    pub compile: Option<Duration>,
+}
+
+/// Like [`get_or_compile_module`], but also measures how long loading the module took, for
+/// benchmarks. The in-memory cache is bypassed, so that every call measures a load from the
+/// persistent cache or a compilation.
+///
+/// Only wasmer2 reports the breakdown into deserialization and compilation; for the other VMs,
+/// just the total is known.
+pub fn load_and_time(
+    code: &ContractCode,
+    config: &VMConfig,
+    protocol_version: ProtocolVersion,
+    cache: Option<&dyn CompiledContractCache>,
+    options: &CacheOptions,
+) -> Result<(VMModule, LoadTiming), VMError> {
+    let untimed = |total| LoadTiming { total, deserialize: None, compile: None };
+    let vm_kind = VMKind::for_protocol_version(protocol_version);
+    match vm_kind {
+        #[cfg(feature = "wasmer0_vm")]
+        VMKind::Wasmer0 => {
+            let start = Instant::now();
+            let module =
+                wasmer0_cache::compile_module_cached_wasmer0(code, config, cache, options, true);
+            let module = into_vm_result(module)?;
+            Ok((VMModule::Wasmer0(module), untimed(start.elapsed())))
+        }
+        #[cfg(feature = "wasmer2_vm")]
+        VMKind::Wasmer2 => {
+            let store = default_wasmer2_store();
+            let start = Instant::now();
+            let (res, deserialize, compile) = wasmer2_cache::compile_module_cached_wasmer2_timed(
+                code, config, cache, options, &store,
+            );
+            let total = start.elapsed();
+            let module = into_vm_result(res)?;
+            let timing = LoadTiming { total, deserialize, compile };
+            Ok((VMModule::Wasmer2(module), timing))
+        }
+        #[cfg(feature = "wasmtime_vm")]
+        VMKind::Wasmtime => {
+            let engine = default_wasmtime_engine();
+            let start = Instant::now();
+            let module = wasmtime_cache::compile_module_cached_wasmtime(
+                code, config, cache, options, &engine,
+            );
+            let module = into_vm_result(module)?;
+            Ok((VMModule::Wasmtime(module), untimed(start.elapsed())))
+        }
+        #[allow(unreachable_patterns)] // reachable when some of the VMs are disabled.
+        _ => {
+            let _ = (code, config, cache, options, untimed);
+            panic!("the {:?} runtime has not been enabled at compile time", vm_kind)
+        }
+    }
+}
+
+/// Precompiles the contract for `vm_kind` into `cache`, unless it is already there.
+///
+/// With `force` set, an existing entry is compiled again and overwritten regardless, e.g. to
+/// replace stale artifacts, and `Ok(ContractRecompiled)` is returned.
+pub fn precompile_contract_vm(
+    vm_kind: VMKind,
+    wasm_code: &ContractCode,
+    config: &VMConfig,
+    cache: Option<&dyn CompiledContractCache>,
+    force: bool,
+) -> Result<ContractPrecompilatonResult, ContractPrecompilatonError> {
+    let on_existing = if force { OnExistingEntry::Overwrite } else { OnExistingEntry::Keep };
+    let options = PrecompileOptions { on_existing, ..PrecompileOptions::default() };
+    precompile_contract_vm_with_options(vm_kind, wasm_code, config, cache, &options)
+}
+
+/// What [`precompile_contract_vm_with_options`] does with a contract which is already in the
+/// cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnExistingEntry {
+    /// Keep the entry, `Ok(ContractAlreadyInCache)` is returned.
+    Keep,
+    /// Keep the entry only if the artifact can still be loaded. Corrupted artifacts are compiled
+    /// again and overwritten, in which case `Ok(RepairedCache)` is returned.
+    KeepIfLoadable,
+    /// Compile the contract again and overwrite the entry regardless, e.g. to replace stale
+    /// artifacts, `Ok(ContractRecompiled)` is returned.
+    Overwrite,
+}
+
+impl Default for OnExistingEntry {
+    fn default() -> Self {
+        OnExistingEntry::Keep
+    }
+}
+
+/// Options of [`precompile_contract_vm_with_options`]. The defaults precompile the contract like
+/// [`precompile_contract_vm`] does without `force`.
+#[derive(Default)]
+pub struct PrecompileOptions<'a> {
+    /// How the artifact is keyed and written to the cache.
+    pub cache_options: CacheOptions,
+    pub on_existing: OnExistingEntry,
+    /// Creates the store wasmer2 contracts are compiled with instead of the default one, e.g. to
+    /// experiment with compiler tunables. The store configuration is not part of the cache key,
+    /// so the artifacts must be loaded with a compatible store.
+    #[cfg(feature = "wasmer2_vm")]
+    pub wasmer2_store: Option<&'a dyn Fn() -> wasmer::Store>,
+    /// Compile wasmer2 contracts for this target rather than for the host, e.g. so that a build
+    /// farm can warm the caches of validators with a different CPU. The artifact is cached under
+    /// [`get_contract_cache_key_for_target`], which is where validators running on hardware
+    /// matching the target look it up. Takes precedence over `wasmer2_store`.
+    #[cfg(feature = "wasmer2_vm")]
+    pub target: Option<wasmer::Target>,
+    /// The code was already prepared with [`prepare::prepare_contract`], e.g. to validate it, so
+    /// it isn't prepared a second time. The artifact is stored under the key of the original
+    /// contract, so its hash has to be passed to [`ContractCode::new`] along with the prepared
+    /// code. The caller is responsible for having prepared the code with the same config,
+    /// otherwise the artifact won't match what compiling the original contract would produce.
+    pub already_prepared: bool,
+    /// Called with the [`CompileProfile`] of the contract if it was compiled, e.g. to correlate
+    /// contract sizes with compilation costs.
+    pub on_profile: Option<&'a dyn Fn(&CompileProfile)>,
+}
+
+/// Like [`precompile_contract_vm`], but precompiles the contract according to `options` rather
+/// than the default ones.
+pub fn precompile_contract_vm_with_options(
+    vm_kind: VMKind,
+    wasm_code: &ContractCode,
+    config: &VMConfig,
+    cache: Option<&dyn CompiledContractCache>,
+    options: &PrecompileOptions,
+) -> Result<ContractPrecompilatonResult, ContractPrecompilatonError> {
+    let key =
+        get_contract_cache_key_with_options(wasm_code, vm_kind, config, &options.cache_options);
+    #[cfg(feature = "wasmer2_vm")]
+    let (key, mut wasmer2_store) = match (vm_kind, &options.target, options.wasmer2_store) {
+        (VMKind::Wasmer2, Some(target), _) => (
+            get_contract_cache_key_for_target(wasm_code, config, target),
+            Some(wasmer2_store_for_target(Some(target.clone()))),
+        ),
+        (VMKind::Wasmer2, None, Some(make_store)) => (key, Some(make_store())),
+        _ => (key, None),
+    };
+    #[cfg(not(feature = "wasmer2_vm"))]
+    let mut wasmer2_store = None;
+    let on_profile = match options.on_profile {
+        Some(it) => it,
+        None => {
+            let (res, _size) = precompile_code_impl(
+                vm_kind,
+                wasm_code.code(),
+                options.already_prepared,
+                key,
+                config,
+                cache,
+                &options.cache_options,
+                options.on_existing,
+                &mut wasmer2_store,
+            )?;
+            return Ok(res);
+        }
+    };
+    let (res, profile) = precompile_with_profile_impl(
+        vm_kind,
+        wasm_code,
+        options.already_prepared,
+        key,
+        config,
+        cache,
+        &options.cache_options,
+        options.on_existing,
+        &mut wasmer2_store,
+    )?;
+    if let Some(profile) = profile {
+        on_profile(&profile);
+    }
+    Ok(res)
+}
+
+/// Measurements of a contract compiled by [`precompile_contract_vm_with_options`], see
+/// [`PrecompileOptions::on_profile`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompileProfile {
+    pub input_wasm_bytes: usize,
+    /// Size of the code after [`prepare::prepare_contract`], which is what gets compiled.
+    pub prepared_bytes: usize,
+    /// Size of the record written to the cache, or which would have been written if it wasn't
+    /// too large, see [`ContractPrecompilatonResult::SkippedTooLarge`].
+    pub serialized_bytes: usize,
+    /// Time spent preparing, compiling and serializing the contract, and writing it to the cache.
+    pub compile_duration: Duration,
+}
+
+/// Like [`precompile_code_impl`], but also returns a [`CompileProfile`] if the contract was
+/// compiled.
+#[allow(clippy::too_many_arguments)]
+fn precompile_with_profile_impl(
     vm_kind: VMKind,
     wasm_code: &ContractCode,
+    already_prepared: bool,
+    key: CryptoHash,
+    config: &VMConfig,
+    cache: Option<&dyn CompiledContractCache>,
+    options: &CacheOptions,
+    on_existing: OnExistingEntry,
+    wasmer2_store: &mut Option<Wasmer2Store>,
+) -> Result<(ContractPrecompilatonResult, Option<CompileProfile>), ContractPrecompilatonError> {
+    let cache = match cache {
+        Some(cache)
+            if on_existing != OnExistingEntry::Keep
+                || !cache.contains_key(&key.0).unwrap_or(true) =>
+        {
+            cache
+        }
+        // Nothing is going to be compiled.
+        cache => {
+            let (res, _size) = precompile_code_impl(
+                vm_kind,
+                wasm_code.code(),
+                already_prepared,
+                key,
+                config,
+                cache,
+                options,
+                on_existing,
+                wasmer2_store,
+            )?;
+            return Ok((res, None));
+        }
+    };
+    #[cfg(feature = "wasmer2_vm")]
+    if vm_kind == VMKind::Wasmer2 {
+        // Creating the store isn't part of compiling the contract.
+        wasmer2_store.get_or_insert_with(default_wasmer2_store);
+    }
+    let start = Instant::now();
+    let prepared = if already_prepared {
+        Cow::Borrowed(wasm_code.code())
+    } else {
+        match prepare::prepare_contract(wasm_code.code(), config) {
+            Ok(prepared) => Cow::Owned(prepared),
+            // Let the regular path report, and cache, the error.
+            Err(_) => {
+                let (res, _size) = precompile_code_impl(
+                    vm_kind,
+                    wasm_code.code(),
+                    false,
+                    key,
+                    config,
+                    Some(cache),
+                    options,
+                    on_existing,
+                    wasmer2_store,
+                )?;
+                return Ok((res, None));
+            }
+        }
+    };
+    let (res, serialized_bytes) = precompile_code_impl(
+        vm_kind,
+        &prepared,
+        true,
+        key,
+        config,
+        Some(cache),
+        options,
+        on_existing,
+        wasmer2_store,
+    )?;
+    let compile_duration = start.elapsed();
+    let serialized_bytes = match serialized_bytes {
+        Some(it) => it,
+        // The cached entry was kept.
+        None => return Ok((res, None)),
+    };
+    let profile = CompileProfile {
+        input_wasm_bytes: wasm_code.code().len(),
+        prepared_bytes: prepared.len(),
+        serialized_bytes,
+        compile_duration,
+    };
+    Ok((res, Some(profile)))
+}
+
+/// Checks, without compiling anything or modifying the cache, whether [`precompile_contract_vm`]
+/// would return `Ok(ContractAlreadyInCache)` for the contract.
+pub fn precompile_would_skip(
+    wasm_code: &ContractCode,
+    vm_kind: VMKind,
     config: &VMConfig,
     cache: Option<&dyn CompiledContractCache>,
-) -> Result<Result<ContractPrecompilatonResult, CompilationError>, CacheError> {
+) -> bool {
     let cache = match cache {
-        None => return Ok(Ok(ContractPrecompilatonResult::CacheNotAvailable)),
+        None => return false,
         Some(it) => it,
     };
     let key = get_contract_cache_key(wasm_code, vm_kind, config);
-    // Check if we already cached with such a key.
-    match cache.get(&key.0).map_err(|_io_error| CacheError::ReadError)? {
-        // If so - do not override.
-        Some(_) => return Ok(Ok(ContractPrecompilatonResult::ContractAlreadyInCache)),
-        None => {}
+    cache.contains_key(&key.0).unwrap_or(false)
+}
+
+/// Checks whether the artifact stored under `key` can be loaded by `vm_kind`. A cached compilation
+/// error counts as loadable.
+fn cached_artifact_loads(
+    vm_kind: VMKind,
+    key: &CryptoHash,
+    cache: &dyn CompiledContractCache,
+    max_record_bytes: usize,
+    wasmer2_store: &mut Option<Wasmer2Store>,
+) -> Result<bool, CacheError> {
+    let serialized = match cache.get(&key.0).map_err(|_io_err| CacheError::ReadError)? {
+        Some(it) => it,
+        None => return Ok(false),
     };
+    if check_record_len(serialized.len(), max_record_bytes).is_err() {
+        return Ok(false);
+    }
     let res = match vm_kind {
-        VMKind::Wasmer0 => {
-            wasmer0_cache::compile_and_serialize_wasmer(wasm_code.code(), config, &key, cache)?
-                .map(|_module| ())
+        #[cfg(feature = "wasmer0_vm")]
+        VMKind::Wasmer0 => wasmer0_cache::deserialize_wasmer(&serialized).map(|_| ()),
+        #[cfg(feature = "wasmer2_vm")]
+        VMKind::Wasmer2 => {
+            let store = wasmer2_store.get_or_insert_with(default_wasmer2_store);
+            wasmer2_cache::deserialize_wasmer2(&serialized, store).map(|_| ())
+        }
+        #[cfg(feature = "wasmtime_vm")]
+        VMKind::Wasmtime => {
+            let engine = default_wasmtime_engine();
+            wasmtime_cache::deserialize_wasmtime(&serialized, &engine).map(|_| ())
+        }
+        #[allow(unreachable_patterns)] // reachable when some of the VMs are disabled.
+        _ => {
+            let _ = wasmer2_store;
+            panic!("the {:?} runtime has not been enabled at compile time", vm_kind)
+        }
+    };
+    match res {
+        Ok(()) => Ok(true),
+        Err(CacheError::DeserializationError | CacheError::ChecksumMismatch) => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Like [`precompile_contract_vm`], but creates the wasmer2 store only once and keeps it in
+/// `wasmer2_store`, so that it can be reused when precompiling several contracts. Contracts which
+/// are already in the cache are handled according to `on_existing`.
+fn precompile_contract_vm_impl(
+    vm_kind: VMKind,
+    wasm_code: &ContractCode,
+    config: &VMConfig,
+    cache: Option<&dyn CompiledContractCache>,
+    options: &CacheOptions,
+    on_existing: OnExistingEntry,
+    wasmer2_store: &mut Option<Wasmer2Store>,
+) -> Result<ContractPrecompilatonResult, ContractPrecompilatonError> {
+    let key = get_contract_cache_key_with_options(wasm_code, vm_kind, config, options);
+    let res = precompile_code_impl(
+        vm_kind,
+        wasm_code.code(),
+        false,
+        key,
+        config,
+        cache,
+        options,
+        on_existing,
+        wasmer2_store,
+    );
+    res.map(|(res, _size)| res)
+}
+
+/// Precompiles `code` into `cache` under `key`. Unless `already_prepared` is set, the code is
+/// prepared with [`prepare::prepare_contract`] first. Also returns the size of the serialized
+/// record if the contract was compiled.
+#[allow(clippy::too_many_arguments)]
+fn precompile_code_impl(
+    vm_kind: VMKind,
+    code: &[u8],
+    already_prepared: bool,
+    key: CryptoHash,
+    config: &VMConfig,
+    cache: Option<&dyn CompiledContractCache>,
+    options: &CacheOptions,
+    on_existing: OnExistingEntry,
+    wasmer2_store: &mut Option<Wasmer2Store>,
+) -> Result<(ContractPrecompilatonResult, Option<usize>), ContractPrecompilatonError> {
+    let cache = match cache {
+        None => return Ok((ContractPrecompilatonResult::CacheNotAvailable, None)),
+        Some(it) => it,
+    };
+    // Check if we already cached with such a key.
+    let present = cache.contains_key(&key.0).map_err(|_io_error| CacheError::ReadError)?;
+    if present {
+        let keep = match on_existing {
+            OnExistingEntry::Keep => true,
+            OnExistingEntry::KeepIfLoadable => cached_artifact_loads(
+                vm_kind,
+                &key,
+                cache,
+                options.max_record_bytes,
+                wasmer2_store,
+            )?,
+            OnExistingEntry::Overwrite => false,
+        };
+        if keep {
+            // If so - do not override.
+            return Ok((ContractPrecompilatonResult::ContractAlreadyInCache, None));
         }
+        // Drop the entry, so that it gets replaced.
+        cache.remove(&key.0).map_err(|_io_err| CacheError::WriteError)?;
+    }
+    let size = match vm_kind {
+        #[cfg(feature = "wasmer0_vm")]
+        VMKind::Wasmer0 => wasmer0_cache::compile_and_serialize_wasmer_sized(
+            code,
+            already_prepared,
+            config,
+            &key,
+            cache,
+            options,
+        )?
+        .map(|(_module, size)| size),
+        #[cfg(feature = "wasmer2_vm")]
         VMKind::Wasmer2 => {
-            let store = default_wasmer2_store();
-            wasmer2_cache::compile_and_serialize_wasmer2(
-                wasm_code.code(),
+            let store = wasmer2_store.get_or_insert_with(default_wasmer2_store);
+            let res = match options.compile_timeout {
+                None => wasmer2_cache::compile_and_serialize_wasmer2_sized(
+                    code,
+                    already_prepared,
+                    &key,
+                    config,
+                    cache,
+                    options,
+                    store,
+                )?,
+                // A timeout is returned before anything gets cached.
+                Some(timeout) => {
+                    let res = wasmer2_cache::compile_module_wasmer2_with_timeout(
+                        code,
+                        config,
+                        store,
+                        already_prepared,
+                        &options.compile_permits,
+                        timeout,
+                    )?;
+                    wasmer2_cache::cache_compiled_wasmer2(res, &key, cache, options)?
+                }
+            };
+            res.map(|(_module, size)| size)
+        }
+        #[cfg(feature = "wasmtime_vm")]
+        VMKind::Wasmtime => {
+            let engine = default_wasmtime_engine();
+            let res = wasmtime_cache::compile_and_serialize_wasmtime_impl(
+                code,
+                already_prepared,
                 &key,
                 config,
                 cache,
-                &store,
-            )?
-            .map(|_module| ())
+                options,
+                &engine,
+            );
+            match res {
+                Ok(res) => res.map(|(_module, size)| size),
+                Err(VMError::CacheError(err)) => return Err(err.into()),
+                // Running the contract reports this as a link error, but it means the contract
+                // can't be compiled all the same.
+                Err(err) => {
+                    let msg = err.to_string();
+                    return Err(ContractPrecompilatonError::Compilation(
+                        CompilationError::WasmerCompileError { msg },
+                    ));
+                }
+            }
         }
-        VMKind::Wasmtime => {
-            panic!("Not yet supported")
+        #[allow(unreachable_patterns)] // reachable when some of the VMs are disabled.
+        _ => {
+            let _ = (already_prepared, config, wasmer2_store);
+            panic!("the {:?} runtime has not been enabled at compile time", vm_kind)
+        }
+    };
+    let size = size.map_err(ContractPrecompilatonError::Compilation)?;
+    // Only wasmer2 leaves oversized records out of the cache, and reports sizes in the result.
+    let res = match vm_kind {
+        VMKind::Wasmer2 if options.max_cache_artifact_bytes.map_or(false, |max| size > max) => {
+            ContractPrecompilatonResult::SkippedTooLarge
         }
+        _ if present && on_existing == OnExistingEntry::Overwrite => {
+            ContractPrecompilatonResult::ContractRecompiled
+        }
+        _ if present => ContractPrecompilatonResult::RepairedCache,
+        VMKind::Wasmer2 => ContractPrecompilatonResult::ContractCompiledWithSize(size),
+        _ => ContractPrecompilatonResult::ContractCompiled,
     };
-    Ok(res.map(|()| ContractPrecompilatonResult::ContractCompiled))
+    Ok((res, Some(size)))
 }
 
 /// Precompiles contract for the current default VM, and stores result to the cache.
-/// Returns `Ok(true)` if compiled code was added to the cache, and `Ok(false)` if element
-/// is already in the cache, or if cache is `None`.
+/// Returns `Ok(ContractCompiled)` or `Ok(ContractCompiledWithSize)` if compiled code was added to
+/// the cache, and `Ok(ContractAlreadyInCache)` or `Ok(CacheNotAvailable)` if element is already
+/// in the cache, or if cache is `None`.
 pub fn precompile_contract(
     wasm_code: &ContractCode,
     config: &VMConfig,
     current_protocol_version: ProtocolVersion,
     cache: Option<&dyn CompiledContractCache>,
-) -> Result<Result<ContractPrecompilatonResult, CompilationError>, CacheError> {
+) -> Result<ContractPrecompilatonResult, ContractPrecompilatonError> {
+    let vm_kind = VMKind::for_protocol_version(current_protocol_version);
+    precompile_contract_vm(vm_kind, wasm_code, config, cache, false)
+}
+
+/// Aggregate measurements of precompiling a corpus of contracts, see [`precompile_corpus`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorpusReport {
+    pub total_time: Duration,
+    /// Contracts compiled and written to the cache.
+    pub compiled: usize,
+    pub already_cached: usize,
+    /// Contracts which failed to compile, or couldn't be cached.
+    pub failed: usize,
+    /// Total size of the records written for the compiled contracts.
+    pub serialized_bytes: usize,
+    /// Median and 99th percentile of [`CompileProfile::compile_duration`], `None` if nothing
+    /// was compiled.
+    pub p50_compile: Option<Duration>,
+    pub p99_compile: Option<Duration>,
+}
+
+/// Precompiles `codes` for the current default VM, like [`precompile_contracts`], and reports
+/// how it went, for cache benchmarks.
+pub fn precompile_corpus(
+    codes: &[ContractCode],
+    config: &VMConfig,
+    current_protocol_version: ProtocolVersion,
+    cache: Option<&dyn CompiledContractCache>,
+) -> CorpusReport {
+    let vm_kind = VMKind::for_protocol_version(current_protocol_version);
+    let mut wasmer2_store = None;
+    let mut report = CorpusReport {
+        total_time: Duration::ZERO,
+        compiled: 0,
+        already_cached: 0,
+        failed: 0,
+        serialized_bytes: 0,
+        p50_compile: None,
+        p99_compile: None,
+    };
+    let mut latencies = Vec::new();
+    let start = Instant::now();
+    for code in codes {
+        let key = get_contract_cache_key(code, vm_kind, config);
+        match precompile_with_profile_impl(
+            vm_kind,
+            code,
+            false,
+            key,
+            config,
+            cache,
+            &CacheOptions::default(),
+            OnExistingEntry::Keep,
+            &mut wasmer2_store,
+        ) {
+            Ok((_, Some(profile))) => {
+                report.compiled += 1;
+                report.serialized_bytes += profile.serialized_bytes;
+                latencies.push(profile.compile_duration);
+            }
+            Ok((ContractPrecompilatonResult::ContractAlreadyInCache, None)) => {
+                report.already_cached += 1
+            }
+            Ok((_, None)) => {}
+            Err(_) => report.failed += 1,
+        }
+    }
+    report.total_time = start.elapsed();
+    latencies.sort();
+    // Nearest-rank percentiles.
+    let percentile = |p: usize| {
+        let rank = (latencies.len() * p + 99) / 100;
+        latencies.get(rank.saturating_sub(1)).copied()
+    };
+    report.p50_compile = percentile(50);
+    report.p99_compile = percentile(99);
+    report
+}
+
+/// Precompiles several contracts for the current default VM, reusing the VM state between them.
+/// Returns the result for every contract, in the same order as `wasm_codes`. Without a cache,
+/// every result is `Ok(CacheNotAvailable)`.
+pub fn precompile_contracts(
+    wasm_codes: &[ContractCode],
+    config: &VMConfig,
+    current_protocol_version: ProtocolVersion,
+    cache: Option<&dyn CompiledContractCache>,
+) -> Vec<Result<ContractPrecompilatonResult, ContractPrecompilatonError>> {
+    let vm_kind = VMKind::for_protocol_version(current_protocol_version);
+    let mut wasmer2_store = None;
+    wasm_codes
+        .iter()
+        .map(|code| {
+            precompile_contract_vm_impl(
+                vm_kind,
+                code,
+                config,
+                cache,
+                &CacheOptions::default(),
+                OnExistingEntry::Keep,
+                &mut wasmer2_store,
+            )
+        })
+        .collect()
+}
+
+/// Like [`precompile_contracts`], but compiles the contracts on a pool of `num_threads` threads,
+/// or a single one if `num_threads` is zero. Every thread uses its own wasmer2 store, while the
+/// compiled artifacts all end up in the shared `cache`. Returns the result for every contract, in
+/// the same order as `wasm_codes`. A contract whose precompilation panicked gets
+/// `ContractPrecompilatonError::CompilationPanicked`, without affecting the others.
+pub fn precompile_contracts_parallel(
+    num_threads: usize,
+    wasm_codes: &[Arc<ContractCode>],
+    config: &VMConfig,
+    current_protocol_version: ProtocolVersion,
+    cache: Option<Arc<dyn CompiledContractCache>>,
+) -> Vec<Result<ContractPrecompilatonResult, ContractPrecompilatonError>> {
+    thread_local! {
+        static WASMER2_STORE: RefCell<Option<Wasmer2Store>> = RefCell::new(None);
+    }
+
     let vm_kind = VMKind::for_protocol_version(current_protocol_version);
-    precompile_contract_vm(vm_kind, wasm_code, config, cache)
+    // `ThreadPool::new` panics on zero threads.
+    let pool = ThreadPool::new(num_threads.max(1));
+    let (tx, rx) = channel();
+    for (index, code) in wasm_codes.iter().enumerate() {
+        let tx = tx.clone();
+        let code = Arc::clone(code);
+        let config = config.clone();
+        let cache = cache.clone();
+        // A panicking task drops its sender without sending a result.
+        pool.execute(move || {
+            let result = WASMER2_STORE.with(|store| {
+                precompile_contract_vm_impl(
+                    vm_kind,
+                    &code,
+                    &config,
+                    cache.as_deref(),
+                    &CacheOptions::default(),
+                    OnExistingEntry::Keep,
+                    &mut store.borrow_mut(),
+                )
+            });
+            // The receiver outlives the pool, so this can only fail if the caller panicked.
+            let _ = tx.send((index, result));
+        });
+    }
+    drop(tx);
+
+    let mut results: Vec<_> = wasm_codes.iter().map(|_| None).collect();
+    for (index, result) in rx {
+        results[index] = Some(result);
+    }
+    results
+        .into_iter()
+        .map(|result| result.unwrap_or(Err(ContractPrecompilatonError::CompilationPanicked)))
+        .collect()
 }
@@ -1,12 +1,74 @@
-use near_vm_errors::VMError;
+use near_vm_errors::{CacheError, CompilationError, VMError};
 
 pub trait IntoVMError {
     fn into_vm_error(self) -> VMError;
 }
 
-#[derive(Debug, PartialEq)]
+/// Outcome of a successful precompilation.
+#[derive(Debug, Clone, PartialEq)]
 pub enum ContractPrecompilatonResult {
+    /// The contract was compiled and written to the cache.
     ContractCompiled,
+    /// Like `ContractCompiled`, with the size in bytes of the record written to the cache. Only
+    /// reported by VMs which know it, currently wasmer2.
+    ContractCompiledWithSize(usize),
+    /// The cache already had the contract, nothing was compiled.
     ContractAlreadyInCache,
+    /// No cache was given, so nothing was compiled. Unlike every other variant, this says nothing
+    /// about whether the contract is cached: retrying with a cache may well compile it.
     CacheNotAvailable,
+    /// The cached artifact failed to load, so the contract was compiled again and overwritten.
+    RepairedCache,
+    /// The contract was already in the cache, but was compiled again and overwritten on request.
+    ContractRecompiled,
+    /// The contract was compiled, but not written to the cache because its record is larger than
+    /// [`crate::CacheOptions::max_cache_artifact_bytes`], so it is compiled again on every use
+    /// which misses the in-memory cache.
+    SkippedTooLarge,
+}
+
+impl ContractPrecompilatonResult {
+    /// Whether the contract is now in the cache. Only `CacheNotAvailable` and `SkippedTooLarge`
+    /// return `false`.
+    pub fn is_cache_effective(&self) -> bool {
+        !matches!(
+            self,
+            ContractPrecompilatonResult::CacheNotAvailable
+                | ContractPrecompilatonResult::SkippedTooLarge
+        )
+    }
+}
+
+/// Reason why [`crate::precompile_contract`] failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContractPrecompilatonError {
+    /// The contract is invalid. This is permanent, retrying won't help.
+    Compilation(CompilationError),
+    /// The persistent cache could not be read or written. This is likely transient.
+    CacheIo(CacheError),
+    /// The compiled module could not be serialized or a cached one could not be deserialized.
+    Serialization(CacheError),
+    /// Compiling the contract took longer than [`crate::CacheOptions::compile_timeout`]. Nothing
+    /// was cached, another attempt may well succeed.
+    Timeout { timeout_ms: u64 },
+    /// The thread compiling the contract panicked. Nothing was cached.
+    CompilationPanicked,
+}
+
+impl From<CacheError> for ContractPrecompilatonError {
+    fn from(err: CacheError) -> Self {
+        match err {
+            CacheError::ReadError | CacheError::WriteError | CacheError::MissingCachedArtifact => {
+                ContractPrecompilatonError::CacheIo(err)
+            }
+            CacheError::DeserializationError
+            | CacheError::ChecksumMismatch
+            | CacheError::SerializationError { .. }
+            | CacheError::UnsupportedRecordVersion { .. }
+            | CacheError::UnsupportedRecordFormat { .. }
+            | CacheError::RecordTooLarge { .. }
+            | CacheError::VmHashMismatch { .. }
+            | CacheError::CachedCompilationError => ContractPrecompilatonError::Serialization(err),
+        }
+    }
 }
@@ -5,6 +5,9 @@ mod errors;
 mod imports;
 #[cfg(feature = "wasmer0_vm")]
 mod memory;
+mod metrics;
+mod precompile_queue;
+#[cfg(all(feature = "wasmer0_vm", feature = "wasmer2_vm"))]
 mod preload;
 pub mod prepare;
 mod runner;
@@ -21,9 +24,44 @@ mod wasmtime_runner;
 pub use near_vm_errors::VMError;
 pub use near_vm_logic::with_ext_cost_counter;
 
+#[cfg(all(feature = "wasmer2_vm", not(feature = "no_cache")))]
+pub use cache::warm_memcache;
+#[cfg(all(feature = "wasmer2_vm", feature = "async_cache"))]
+pub use cache::wasmer2_cache::compile_module_cached_wasmer2_async;
+#[cfg(feature = "wasmer2_vm")]
+pub use cache::wasmer2_cache::{compile_module_cached_wasmer2_with_origin, CacheOrigin};
+pub use cache::{
+    available_vm_kinds, cache_keys_for_codes, cache_stats, clear_module_cache,
+    contract_cache_key_bytes, current_vm_hash, describe_contract_cache_key, export_cache,
+    extract_artifact, gc_stale_records, get_contract_cache_key, get_contract_cache_key_from_hash,
+    get_contract_cache_key_with_hasher, get_contract_cache_key_with_options,
+    get_contract_cache_key_with_vm_hash, get_namespaced_contract_cache_key, get_or_compile_module,
+    get_protocol_versioned_contract_cache_key, import_cache, install_artifact, load_and_time,
+    pin_module, precompile_contract, precompile_contract_vm, precompile_contract_vm_with_options,
+    precompile_contracts, precompile_contracts_parallel, precompile_corpus, precompile_would_skip,
+    purge_errors, record_age, set_module_cache_capacity, supported_record_versions, unpin_module,
+    vm_config_cache_component, CacheEvent, CacheEventKind, CacheKeyHasher, CacheOptions,
+    CacheStats, CircuitBreakerCache, CompactionReport, CompileProfile, CompileSemaphore,
+    CompressionAlgo, ContractCacheKeyDescription, CorpusReport, CryptoCacheKeyHasher,
+    FaultyMockCache, FsCompiledContractCache, InMemoryContractCache, LayeredCache, LoadTiming,
+    MirroringCache, MockCompiledContractCache, NegativeCachingWrapper, OnExistingEntry,
+    PrecompileOptions, ReadErrorPolicy, ReadOnlyCache, RecordingCache, SizeCappedCache, VMModule,
+    VmHashIndex, DEFAULT_MAX_RECORD_BYTES, DEFAULT_MODULE_CACHE_CAPACITY,
+};
+#[cfg(feature = "wasmer2_vm")]
+pub use cache::{
+    compile_to_serialized_wasmer2, compile_wasmer2_from_artifact_only, deserialize_wasmer2_checked,
+    get_contract_cache_key_for_target, load_wasmer2_module_from_bytes,
+};
+#[cfg(not(feature = "no_cache"))]
 pub use cache::{
-    get_contract_cache_key, precompile_contract, precompile_contract_vm, MockCompiledContractCache,
+    registry, restore_memcache, snapshot_memcache, MemcacheSnapshot, ModuleCacheRegistry,
 };
+#[cfg(feature = "async_cache")]
+pub use cache::{AsyncCacheAdapter, AsyncCompiledContractCache};
+pub use errors::{ContractPrecompilatonError, ContractPrecompilatonResult};
+pub use precompile_queue::{EnqueueError, PrecompileQueue};
+#[cfg(all(feature = "wasmer0_vm", feature = "wasmer2_vm"))]
 pub use preload::{ContractCallPrepareRequest, ContractCallPrepareResult, ContractCaller};
 pub use runner::{run, VM};
 
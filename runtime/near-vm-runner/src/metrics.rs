@@ -0,0 +1,38 @@
+use near_metrics::{try_create_histogram_vec, try_create_int_counter, HistogramVec, IntCounter};
+use once_cell::sync::Lazy;
+
+pub static COMPILATION_TIME: Lazy<HistogramVec> = Lazy::new(|| {
+    try_create_histogram_vec(
+        "near_vm_compilation_seconds",
+        "Time spent compiling contracts, including preparation",
+        &["vm_kind"],
+        None,
+    )
+    .unwrap()
+});
+pub static DESERIALIZATION_TIME: Lazy<HistogramVec> = Lazy::new(|| {
+    try_create_histogram_vec(
+        "near_vm_deserialization_seconds",
+        "Time spent loading compiled contracts from the persistent cache",
+        &["vm_kind"],
+        None,
+    )
+    .unwrap()
+});
+pub static SERIALIZED_MODULE_SIZE: Lazy<HistogramVec> = Lazy::new(|| {
+    try_create_histogram_vec(
+        "near_vm_serialized_module_bytes",
+        "Size of the records of compiled contracts written to the persistent cache",
+        &["vm_kind"],
+        // From 1 KiB to 256 MiB.
+        Some((10..=28).step_by(2).map(|exp| (1u64 << exp) as f64).collect()),
+    )
+    .unwrap()
+});
+pub static CACHE_CIRCUIT_BREAKER_TRIPS: Lazy<IntCounter> = Lazy::new(|| {
+    try_create_int_counter(
+        "near_vm_cache_circuit_breaker_trips_total",
+        "Number of times writes to the persistent cache were suspended after repeated failures",
+    )
+    .unwrap()
+});
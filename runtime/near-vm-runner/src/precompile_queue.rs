@@ -0,0 +1,113 @@
+use std::collections::HashSet;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use near_primitives::contract::ContractCode;
+use near_primitives::hash::CryptoHash;
+use near_primitives::types::CompiledContractCache;
+use near_vm_logic::{ProtocolVersion, VMConfig};
+
+use crate::cache::{get_contract_cache_key, precompile_contract};
+use crate::vm_kind::VMKind;
+
+struct PrecompileTask {
+    key: CryptoHash,
+    code: Arc<ContractCode>,
+    config: VMConfig,
+    protocol_version: ProtocolVersion,
+}
+
+impl PrecompileTask {
+    fn run(&self, cache: &dyn CompiledContractCache) {
+        // A panic must neither stop the worker nor leave the contract pending forever.
+        let res = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            precompile_contract(&self.code, &self.config, self.protocol_version, Some(cache))
+        }));
+        match res {
+            Ok(Ok(_)) => {}
+            Ok(Err(err)) => {
+                tracing::debug!(target: "vm", "Failed to precompile {}: {:?}", self.key, err)
+            }
+            Err(_) => tracing::warn!(target: "vm", "Precompilation of {} panicked", self.key),
+        }
+    }
+}
+
+/// Reason why [`PrecompileQueue::enqueue`] failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EnqueueError {
+    /// The worker thread is gone, so the contract would never be compiled.
+    WorkerStopped,
+}
+
+/// Precompiles contracts into a cache on a dedicated worker thread, so that deploying a contract
+/// doesn't have to wait for its compilation.
+pub struct PrecompileQueue {
+    tx: Option<Sender<PrecompileTask>>,
+    /// Keys of the contracts which are queued or being compiled.
+    pending: Arc<Mutex<HashSet<CryptoHash>>>,
+    /// Set on drop, so that the worker skips the contracts still queued.
+    cancelled: Arc<AtomicBool>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl PrecompileQueue {
+    pub fn new(cache: Arc<dyn CompiledContractCache>) -> PrecompileQueue {
+        let (tx, rx) = channel::<PrecompileTask>();
+        let pending = Arc::new(Mutex::new(HashSet::new()));
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let worker = std::thread::spawn({
+            let pending = Arc::clone(&pending);
+            let cancelled = Arc::clone(&cancelled);
+            move || {
+                for task in rx {
+                    if !cancelled.load(Ordering::Relaxed) {
+                        task.run(cache.as_ref());
+                    }
+                    pending.lock().unwrap().remove(&task.key);
+                }
+            }
+        });
+        PrecompileQueue { tx: Some(tx), pending, cancelled, worker: Some(worker) }
+    }
+
+    /// Queues the contract for precompilation. Returns `Ok(false)` if the same contract, with the
+    /// same config, is already queued.
+    pub fn enqueue(
+        &self,
+        code: Arc<ContractCode>,
+        config: &VMConfig,
+        protocol_version: ProtocolVersion,
+    ) -> Result<bool, EnqueueError> {
+        let vm_kind = VMKind::for_protocol_version(protocol_version);
+        let key = get_contract_cache_key(&code, vm_kind, config);
+        if !self.pending.lock().unwrap().insert(key) {
+            return Ok(false);
+        }
+        let task = PrecompileTask { key, code, config: config.clone(), protocol_version };
+        if self.tx.as_ref().unwrap().send(task).is_err() {
+            self.pending.lock().unwrap().remove(&key);
+            return Err(EnqueueError::WorkerStopped);
+        }
+        Ok(true)
+    }
+
+    /// Returns the number of contracts which are queued or being compiled.
+    pub fn pending_len(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+}
+
+impl Drop for PrecompileQueue {
+    /// Discards the queued contracts and waits for the one being compiled, if any.
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+        drop(self.tx.take());
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
@@ -10,20 +10,15 @@ use near_vm_errors::VMError;
 use near_vm_logic::types::PromiseResult;
 use near_vm_logic::{External, ProtocolVersion, VMConfig, VMContext, VMOutcome};
 
-use crate::cache::{self, into_vm_result};
+use crate::cache::VMModule::{self, Wasmer0, Wasmer2};
+use crate::cache::{self, into_vm_result, CacheOptions};
 use crate::memory::WasmerMemory;
-use crate::preload::VMModule::{Wasmer0, Wasmer2};
 use crate::vm_kind::VMKind;
 use crate::wasmer2_runner::{default_wasmer2_store, run_wasmer2_module, Wasmer2Memory};
 use crate::wasmer_runner::run_wasmer0_module;
 
 const SHARE_MEMORY_INSTANCE: bool = false;
 
-enum VMModule {
-    Wasmer0(wasmer_runtime::Module),
-    Wasmer2(wasmer::Module),
-}
-
 enum VMDataPrivate {
     Wasmer0(Option<WasmerMemory>),
     Wasmer2(Option<Wasmer2Memory>),
@@ -233,6 +228,8 @@ fn preload_in_thread(
                 &request.code,
                 &vm_config,
                 cache,
+                &CacheOptions::default(),
+                false,
             );
             into_vm_result(module).map(VMModule::Wasmer0)
         }
@@ -241,7 +238,10 @@ fn preload_in_thread(
                 &request.code,
                 &vm_config,
                 cache,
+                &CacheOptions::default(),
                 &store,
+                false,
+                None,
             );
             into_vm_result(module).map(VMModule::Wasmer2)
         }
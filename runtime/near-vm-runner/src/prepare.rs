@@ -5,7 +5,7 @@ use parity_wasm::builder;
 use parity_wasm::elements::{self, External, MemorySection, Type};
 use pwasm_utils::{self, rules};
 
-use near_vm_errors::PrepareError;
+use near_vm_errors::{CompilationError, FunctionCallError, PrepareError, VMError};
 use near_vm_logic::VMConfig;
 
 pub(crate) const WASM_FEATURES: wasmparser::WasmFeatures = wasmparser::WasmFeatures {
@@ -194,6 +194,16 @@ pub fn prepare_contract(original_code: &[u8], config: &VMConfig) -> Result<Vec<u
         .into_wasm_code()
 }
 
+/// Checks that `code` passes [`prepare_contract`], without going on to compile and cache it, so
+/// that malformed contracts can be rejected cheaply, e.g. before they are deployed.
+pub fn validate_prepared_contract(code: &[u8], config: &VMConfig) -> Result<(), VMError> {
+    prepare_contract(code, config).map(drop).map_err(|err| {
+        VMError::FunctionCallError(FunctionCallError::CompilationError(
+            CompilationError::PrepareError(err),
+        ))
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use assert_matches::assert_matches;
@@ -206,6 +216,21 @@ mod tests {
         prepare_contract(wasm.as_ref(), &config)
     }
 
+    #[test]
+    fn validate_prepared() {
+        let config = VMConfig::test();
+        let wasm = wat::parse_str(r#"(module (import "env" "memory" (memory 1 1)))"#).unwrap();
+        assert_matches!(validate_prepared_contract(&wasm, &config), Ok(()));
+
+        let err = validate_prepared_contract(&[0, 1, 2, 3], &config).unwrap_err();
+        assert_eq!(
+            err,
+            VMError::FunctionCallError(FunctionCallError::CompilationError(
+                CompilationError::PrepareError(PrepareError::Deserialization)
+            ))
+        );
+    }
+
     #[test]
     fn internal_memory_declaration() {
         let r = parse_and_prepare_wat(r#"(module (memory 1 1))"#);
@@ -1,4 +1,8 @@
+#[cfg(all(feature = "wasmer2_vm", not(feature = "no_cache")))]
+mod cache;
+mod cache_key;
 mod compile_errors;
+#[cfg(all(feature = "wasmer0_vm", feature = "wasmer2_vm"))]
 mod contract_preload;
 mod rs_contract;
 mod runtime_errors;
@@ -0,0 +1,3293 @@
+use assert_matches::assert_matches;
+use borsh::BorshSerialize;
+use near_primitives::contract::ContractCode;
+use near_primitives::hash::CryptoHash;
+use near_primitives::types::CompiledContractCache;
+use near_vm_errors::{CacheError, CompilationError, FunctionCallError, PrepareError, VMError};
+use near_vm_logic::{ProtocolVersion, VMConfig};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::cache::wasmer2_cache::{
+    compile_and_serialize_wasmer2, compile_and_serialize_wasmer2_sized,
+    compile_module_cached_wasmer2, compile_module_cached_wasmer2_with_origin, deserialize_wasmer2,
+    memcache_compile_module_cached_wasmer2, verify_cached_artifact, CacheOrigin,
+};
+use crate::cache::{
+    available_vm_kinds, cache_keys_for_codes, cache_stats, checked_artifact,
+    compile_to_serialized_wasmer2, compile_wasmer2_from_artifact_only, compile_with_timeout,
+    contract_cache_key_bytes, current_vm_hash, describe_contract_cache_key,
+    deserialize_wasmer2_checked, export_cache, extract_artifact, gc_stale_records,
+    get_contract_cache_key, get_contract_cache_key_for_target, get_contract_cache_key_from_hash,
+    get_contract_cache_key_with_hasher, get_contract_cache_key_with_options,
+    get_namespaced_contract_cache_key, get_or_compile_module,
+    get_protocol_versioned_contract_cache_key, import_cache, install_artifact, load_and_time,
+    load_wasmer2_module_from_bytes, precompile_contract, precompile_contract_vm,
+    precompile_contract_vm_with_options, precompile_contracts, precompile_contracts_parallel,
+    precompile_corpus, precompile_would_skip, purge_errors, record_age, registry,
+    supported_record_versions, vm_config_cache_component, warm_memcache, BorshCodec, CacheEvent,
+    CacheEventKind, CacheKeyHasher, CacheOptions, CacheRecord, CircuitBreakerCache,
+    CompactionReport, CompileProfile, CompileSemaphore, CompressionAlgo,
+    ContractCacheKeyDescription, CryptoCacheKeyHasher, FaultyMockCache, FsCompiledContractCache,
+    InFlight, InMemoryContractCache, LayeredCache, MirroringCache, MockCompiledContractCache,
+    ModuleCacheRegistry, NegativeCachingWrapper, OnExistingEntry, PrecompileOptions,
+    ReadErrorPolicy, ReadOnlyCache, RecordCodec, RecordingCache, SizeCappedCache, VMModule,
+    VmHashIndex, DEFAULT_MAX_RECORD_BYTES, DEFAULT_MODULE_CACHE_CAPACITY,
+};
+#[cfg(feature = "async_cache")]
+use crate::cache::{wasmer2_cache::compile_module_cached_wasmer2_async, AsyncCacheAdapter};
+use crate::errors::{ContractPrecompilatonError, ContractPrecompilatonResult};
+use crate::metrics;
+use crate::precompile_queue::PrecompileQueue;
+use crate::prepare;
+use crate::vm_kind::VMKind;
+use crate::wasmer2_runner::default_wasmer2_store;
+
+/// A cache whose every operation fails, as if the backing store was unavailable.
+struct FailingCache;
+
+impl CompiledContractCache for FailingCache {
+    fn put(&self, _key: &[u8], _value: &[u8]) -> Result<(), std::io::Error> {
+        Err(std::io::Error::new(std::io::ErrorKind::Other, "put failed"))
+    }
+
+    fn get(&self, _key: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error> {
+        Err(std::io::Error::new(std::io::ErrorKind::Other, "get failed"))
+    }
+}
+
+/// Returns a trivial contract which is distinct for every `seed`, and thus gets its own cache key.
+fn unique_contract(seed: u32) -> ContractCode {
+    let code = wat::parse_str(format!(
+        r#"
+            (module
+              (func (export "main") (drop (i32.const {})))
+            )"#,
+        seed
+    ))
+    .unwrap();
+    ContractCode::new(code, None)
+}
+
+/// Checks that a contract was compiled, whether or not its size was reported.
+fn assert_compiled(result: &Result<ContractPrecompilatonResult, ContractPrecompilatonError>) {
+    assert_matches!(
+        result,
+        Ok(ContractPrecompilatonResult::ContractCompiled
+            | ContractPrecompilatonResult::ContractCompiledWithSize(_))
+    );
+}
+
+#[test]
+fn test_module_cache_capacity() {
+    let capacity = 4;
+    let registry = ModuleCacheRegistry::new(capacity);
+
+    let config = VMConfig::test();
+    let store = default_wasmer2_store();
+    let codes: Vec<_> = (0..=capacity as u32).map(|seed| unique_contract(1000 + seed)).collect();
+    let keys: Vec<_> =
+        codes.iter().map(|code| get_contract_cache_key(code, VMKind::Wasmer2, &config)).collect();
+    for (code, key) in codes.iter().zip(&keys) {
+        let (res, from_memory) = memcache_compile_module_cached_wasmer2(
+            &registry,
+            *key,
+            code,
+            &config,
+            None,
+            &CacheOptions::default(),
+            &store,
+            None,
+        );
+        res.unwrap().unwrap();
+        assert!(!from_memory);
+    }
+    assert_eq!(registry.len(VMKind::Wasmer2), capacity);
+    assert!(!registry.contains(VMKind::Wasmer2, &keys[0]));
+    assert!(registry.contains(VMKind::Wasmer2, &keys[capacity]));
+
+    // Shrinking the capacity evicts the least recently used modules.
+    registry.set_budget(2);
+    assert_eq!(registry.len(VMKind::Wasmer2), 2);
+    assert!(!registry.contains(VMKind::Wasmer2, &keys[2]));
+    assert!(registry.contains(VMKind::Wasmer2, &keys[capacity]));
+}
+
+#[test]
+fn test_module_cache_keeps_compilation_errors() {
+    let registry = ModuleCacheRegistry::new(1);
+    registry.set_error_budget(1);
+    let config = VMConfig::test();
+    let store = default_wasmer2_store();
+    let compile = |code: &ContractCode, options: &CacheOptions| {
+        let key = get_contract_cache_key(code, VMKind::Wasmer2, &config);
+        memcache_compile_module_cached_wasmer2(
+            &registry, key, code, &config, None, options, &store, None,
+        )
+    };
+    let options = CacheOptions::default();
+    let invalid = ContractCode::new(vec![42; 100], None);
+    let (res, from_memory) = compile(&invalid, &options);
+    assert!(res.unwrap().is_err());
+    assert!(!from_memory);
+    let (res, from_memory) = compile(&invalid, &options);
+    assert!(res.unwrap().is_err());
+    assert!(from_memory);
+
+    // Errors don't take the place of modules, nor the other way around.
+    compile(&unique_contract(1100), &options).0.unwrap().unwrap();
+    assert_eq!(registry.len(VMKind::Wasmer2), 1);
+    assert_eq!(registry.error_len(), 1);
+    assert!(compile(&invalid, &options).1);
+
+    // Errors are evicted on their own budget.
+    let other_invalid = ContractCode::new(vec![44; 100], None);
+    assert!(!compile(&other_invalid, &options).1);
+    assert_eq!(registry.error_len(), 1);
+    assert_eq!(registry.len(VMKind::Wasmer2), 1);
+    assert!(!compile(&invalid, &options).1);
+
+    let options = CacheOptions { cache_compilation_errors: false, ..CacheOptions::default() };
+    registry.clear();
+    assert!(compile(&invalid, &options).0.unwrap().is_err());
+    assert_eq!(registry.error_len(), 0);
+}
+
+#[test]
+fn test_mock_cache_remove() {
+    let cache = MockCompiledContractCache::default();
+    cache.put(b"key", b"value").unwrap();
+    assert_eq!(cache.get(b"key").unwrap(), Some(b"value".to_vec()));
+    cache.remove(b"key").unwrap();
+    assert_eq!(cache.get(b"key").unwrap(), None);
+}
+
+#[test]
+fn test_mock_cache_contains_key() {
+    let cache = MockCompiledContractCache::default();
+    assert!(!cache.contains_key(b"key").unwrap());
+    cache.put(b"key", b"value").unwrap();
+    assert!(cache.contains_key(b"key").unwrap());
+}
+
+#[test]
+fn test_mock_cache_approximate_len() {
+    let cache = MockCompiledContractCache::default();
+    assert_eq!(cache.approximate_len(), Some(0));
+    for i in 0..5u8 {
+        cache.put(&[i], &[i]).unwrap();
+    }
+    cache.remove(&[0]).unwrap();
+    assert_eq!(cache.approximate_len(), Some(4));
+    assert_eq!(FailingCache.approximate_len(), None);
+}
+
+#[test]
+fn test_mock_cache_with_capacity() {
+    let capacity = 3;
+    let cache = MockCompiledContractCache::with_capacity(capacity);
+    for i in 0..=capacity as u8 {
+        cache.put(&[i], &[i]).unwrap();
+    }
+    assert_eq!(cache.len(), capacity);
+    assert_eq!(cache.get(&[0]).unwrap(), None);
+    for i in 1..=capacity as u8 {
+        assert_eq!(cache.get(&[i]).unwrap(), Some(vec![i]));
+    }
+
+    // Reading a key makes it the most recently used one, so the next oldest is evicted instead.
+    cache.get(&[1]).unwrap();
+    cache.put(&[42], &[42]).unwrap();
+    assert!(cache.contains_key(&[1]).unwrap());
+    assert!(!cache.contains_key(&[2]).unwrap());
+}
+
+#[test]
+fn test_mock_cache_byte_size() {
+    let cache = MockCompiledContractCache::with_capacity(2);
+    assert_eq!(cache.byte_size(), 0);
+    cache.put(&[0], &[0; 10]).unwrap();
+    cache.put(&[1], &[1; 20]).unwrap();
+    assert_eq!(cache.byte_size(), 30);
+
+    // Overwritten, removed and evicted values no longer count.
+    cache.put(&[0], &[0; 5]).unwrap();
+    assert_eq!(cache.byte_size(), 25);
+    cache.remove(&[1]).unwrap();
+    assert_eq!(cache.byte_size(), 5);
+    cache.put(&[2], &[2; 100]).unwrap();
+    cache.put(&[3], &[3; 1]).unwrap();
+    assert_eq!(cache.byte_size(), 101);
+}
+
+#[test]
+fn test_mock_cache_touch() {
+    let cache = MockCompiledContractCache::with_capacity(2);
+    cache.put(&[0], &[0]).unwrap();
+    cache.put(&[1], &[1]).unwrap();
+
+    // Touching the oldest key protects it from the next eviction.
+    cache.touch(&[0]).unwrap();
+    cache.put(&[2], &[2]).unwrap();
+    assert!(cache.contains_key(&[0]).unwrap());
+    assert!(!cache.contains_key(&[1]).unwrap());
+
+    // Touching an absent key has no effect.
+    cache.touch(&[1]).unwrap();
+    cache.put(&[3], &[3]).unwrap();
+    assert!(!cache.contains_key(&[0]).unwrap());
+    assert!(cache.contains_key(&[2]).unwrap());
+    assert_eq!(cache.len(), 2);
+}
+
+#[test]
+fn test_cache_stats() {
+    let config = VMConfig::test();
+    let store = default_wasmer2_store();
+    let cache = MockCompiledContractCache::default();
+    let code = unique_contract(2000);
+
+    // Other tests compile contracts concurrently, so only check that our own lookups are counted.
+    let before = cache_stats();
+    compile_module_cached_wasmer2(
+        &code,
+        &config,
+        Some(&cache),
+        &CacheOptions::default(),
+        &store,
+        false,
+        None,
+    )
+    .unwrap()
+    .unwrap();
+    let after_miss = cache_stats();
+    assert!(after_miss.misses > before.misses);
+    compile_module_cached_wasmer2(
+        &code,
+        &config,
+        Some(&cache),
+        &CacheOptions::default(),
+        &store,
+        false,
+        None,
+    )
+    .unwrap()
+    .unwrap();
+    let after_hit = cache_stats();
+    assert!(after_hit.hits > after_miss.hits);
+}
+
+#[test]
+fn test_cache_stats_by_layer() {
+    let config = VMConfig::test();
+    let store = default_wasmer2_store();
+    let cache = MockCompiledContractCache::default();
+    let code = unique_contract(36000);
+
+    let before = cache_stats();
+    compile_module_cached_wasmer2(
+        &code,
+        &config,
+        Some(&cache),
+        &CacheOptions::default(),
+        &store,
+        false,
+        None,
+    )
+    .unwrap()
+    .unwrap();
+    let after_miss = cache_stats();
+    assert!(after_miss.misses > before.misses);
+    for _ in 0..2 {
+        compile_module_cached_wasmer2(
+            &code,
+            &config,
+            Some(&cache),
+            &CacheOptions::default(),
+            &store,
+            false,
+            None,
+        )
+        .unwrap()
+        .unwrap();
+    }
+    let after_memory_hits = cache_stats();
+    assert!(after_memory_hits.memory_hits >= after_miss.memory_hits + 2);
+
+    // Bypassing the in-memory cache deserializes the module from the persistent one.
+    compile_module_cached_wasmer2(
+        &code,
+        &config,
+        Some(&cache),
+        &CacheOptions::default(),
+        &store,
+        true,
+        None,
+    )
+    .unwrap()
+    .unwrap();
+    let after_persistent_hit = cache_stats();
+    assert!(after_persistent_hit.persistent_hits > after_memory_hits.persistent_hits);
+    assert_eq!(
+        after_persistent_hit.hits,
+        after_persistent_hit.memory_hits + after_persistent_hit.persistent_hits
+    );
+}
+
+#[cfg(feature = "wasmtime_vm")]
+#[test]
+fn test_wasmtime_precompile_round_trip() {
+    use crate::cache::wasmtime_cache::compile_module_cached_wasmtime;
+    use crate::wasmtime_runner::default_wasmtime_engine;
+
+    let config = VMConfig::test();
+    let cache = MockCompiledContractCache::default();
+    let code = unique_contract(3000);
+
+    let result = precompile_contract_vm(VMKind::Wasmtime, &code, &config, Some(&cache), false);
+    assert_eq!(result, Ok(ContractPrecompilatonResult::ContractCompiled));
+    assert_eq!(cache.len(), 1);
+
+    let engine = default_wasmtime_engine();
+    let module = compile_module_cached_wasmtime(
+        &code,
+        &config,
+        Some(&cache),
+        &CacheOptions::default(),
+        &engine,
+    )
+    .unwrap();
+    assert!(module.unwrap().get_export("main").is_some());
+    assert_eq!(cache.len(), 1);
+}
+
+#[test]
+fn test_compressed_artifact() {
+    let config = VMConfig::test();
+    let store = default_wasmer2_store();
+    let code = ContractCode::new(near_test_contracts::rs_contract().to_vec(), None);
+    let key = get_contract_cache_key(&code, VMKind::Wasmer2, &config);
+
+    let cache = MockCompiledContractCache::default();
+    compile_and_serialize_wasmer2(
+        code.code(),
+        &key,
+        &config,
+        &cache,
+        &CacheOptions::default(),
+        &store,
+    )
+    .unwrap()
+    .unwrap();
+    let plain = cache.get(&key.0).unwrap().unwrap();
+    let artifact = CacheRecord::from_bytes(&plain).unwrap().into_code().unwrap().unwrap();
+
+    let compressed = CacheRecord::code(artifact, Some(CompressionAlgo::Zstd)).to_bytes();
+    assert!(compressed.len() < plain.len());
+
+    let module = deserialize_wasmer2(&compressed, &store).unwrap().unwrap();
+    assert!(module.exports().count() > 0);
+    // Uncompressed records written before compression was enabled must still load.
+    let module = deserialize_wasmer2(&plain, &store).unwrap().unwrap();
+    assert!(module.exports().count() > 0);
+
+    // Caches opt into compression through their options.
+    let compressing = MockCompiledContractCache::default();
+    let options =
+        CacheOptions { artifact_compression: Some(CompressionAlgo::Zstd), ..Default::default() };
+    compile_and_serialize_wasmer2(code.code(), &key, &config, &compressing, &options, &store)
+        .unwrap()
+        .unwrap();
+    let record = compressing.get(&key.0).unwrap().unwrap();
+    assert!(record.len() < plain.len());
+    assert!(deserialize_wasmer2(&record, &store).unwrap().is_ok());
+}
+
+#[test]
+fn test_clear_module_cache() {
+    let registry = ModuleCacheRegistry::new(DEFAULT_MODULE_CACHE_CAPACITY);
+    let config = VMConfig::test();
+    let store = default_wasmer2_store();
+    let code = unique_contract(4000);
+    let key = get_contract_cache_key(&code, VMKind::Wasmer2, &config);
+    let compile = || {
+        let (res, from_memory) = memcache_compile_module_cached_wasmer2(
+            &registry,
+            key,
+            &code,
+            &config,
+            None,
+            &CacheOptions::default(),
+            &store,
+            None,
+        );
+        res.unwrap().unwrap();
+        from_memory
+    };
+
+    assert!(!compile());
+    assert!(compile());
+    registry.clear();
+    assert!(!registry.contains(VMKind::Wasmer2, &key));
+    assert!(!compile());
+    assert!(registry.contains(VMKind::Wasmer2, &key));
+}
+
+#[test]
+fn test_precompile_errors() {
+    let config = VMConfig::test();
+    let code = unique_contract(5000);
+    let result =
+        precompile_contract_vm(VMKind::Wasmer2, &code, &config, Some(&FailingCache), false);
+    assert_eq!(result, Err(ContractPrecompilatonError::CacheIo(CacheError::ReadError)));
+
+    let cache = MockCompiledContractCache::default();
+    let invalid_code = ContractCode::new(vec![42; 100], None);
+    let result =
+        precompile_contract_vm(VMKind::Wasmer2, &invalid_code, &config, Some(&cache), false);
+    assert_eq!(
+        result,
+        Err(ContractPrecompilatonError::Compilation(CompilationError::PrepareError(
+            PrepareError::Deserialization
+        )))
+    );
+}
+
+#[test]
+fn test_precompile_contracts() {
+    let config = VMConfig::test();
+    let cache = MockCompiledContractCache::default();
+    let codes: Vec<_> = (0..3).map(|seed| unique_contract(6000 + seed)).collect();
+
+    let results = precompile_contracts(&codes, &config, ProtocolVersion::MAX, Some(&cache));
+    assert_eq!(results.len(), 3);
+    results.iter().for_each(assert_compiled);
+    assert_eq!(cache.len(), 3);
+
+    let results = precompile_contracts(&codes[..1], &config, ProtocolVersion::MAX, Some(&cache));
+    assert_eq!(results, vec![Ok(ContractPrecompilatonResult::ContractAlreadyInCache)]);
+}
+
+#[test]
+fn test_precompile_contracts_parallel() {
+    let config = VMConfig::test();
+    let cache: Arc<dyn CompiledContractCache> = Arc::new(MockCompiledContractCache::default());
+    let mut codes: Vec<_> = (0..8).map(|seed| Arc::new(unique_contract(7000 + seed))).collect();
+    codes.push(Arc::new(ContractCode::new(vec![42; 100], None)));
+
+    let results = precompile_contracts_parallel(
+        4,
+        &codes,
+        &config,
+        ProtocolVersion::MAX,
+        Some(cache.clone()),
+    );
+    assert_eq!(results.len(), codes.len());
+    results[..8].iter().for_each(assert_compiled);
+    assert_matches!(results[8], Err(ContractPrecompilatonError::Compilation(_)));
+
+    let vm_kind = VMKind::for_protocol_version(ProtocolVersion::MAX);
+    for code in &codes {
+        let key = get_contract_cache_key(code, vm_kind, &config);
+        assert!(cache.contains_key(&key.0).unwrap());
+    }
+}
+
+#[test]
+fn test_precompile_contracts_parallel_no_threads() {
+    let config = VMConfig::test();
+    let cache: Arc<dyn CompiledContractCache> = Arc::new(MockCompiledContractCache::default());
+    let codes: Vec<_> = (0..2).map(|seed| Arc::new(unique_contract(7300 + seed))).collect();
+
+    let results =
+        precompile_contracts_parallel(0, &codes, &config, ProtocolVersion::MAX, Some(cache));
+    assert_eq!(results.len(), codes.len());
+    results.iter().for_each(assert_compiled);
+}
+
+#[test]
+fn test_precompile_contracts_parallel_panic() {
+    /// Panics when writing the record under `0`.
+    struct PanickingCache(Vec<u8>, MockCompiledContractCache);
+
+    impl CompiledContractCache for PanickingCache {
+        fn put(&self, key: &[u8], value: &[u8]) -> Result<(), std::io::Error> {
+            assert_ne!(key, &self.0[..], "broken record");
+            self.1.put(key, value)
+        }
+
+        fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error> {
+            self.1.get(key)
+        }
+    }
+
+    let config = VMConfig::test();
+    let codes: Vec<_> = (0..4).map(|seed| Arc::new(unique_contract(7200 + seed))).collect();
+    let vm_kind = VMKind::for_protocol_version(ProtocolVersion::MAX);
+    let key = get_contract_cache_key(&codes[1], vm_kind, &config);
+    let cache = Arc::new(PanickingCache(key.0.to_vec(), MockCompiledContractCache::default()));
+
+    let results =
+        precompile_contracts_parallel(2, &codes, &config, ProtocolVersion::MAX, Some(cache));
+    assert_eq!(results[1], Err(ContractPrecompilatonError::CompilationPanicked));
+    assert_compiled(&results[0]);
+    results[2..].iter().for_each(assert_compiled);
+}
+
+#[test]
+fn test_precompile_contracts_parallel_matches_sequential() {
+    let config = VMConfig::test();
+    // More contracts than threads, with invalid ones interleaved, so that results complete out of
+    // order and must be put back in input order.
+    let codes: Vec<_> = (0..12)
+        .map(|seed| {
+            if seed % 3 == 2 {
+                ContractCode::new(vec![seed as u8; 100], None)
+            } else {
+                unique_contract(7100 + seed)
+            }
+        })
+        .collect();
+
+    let cache = MockCompiledContractCache::default();
+    let sequential = precompile_contracts(&codes, &config, ProtocolVersion::MAX, Some(&cache));
+
+    let codes: Vec<_> = codes.into_iter().map(Arc::new).collect();
+    let parallel_cache = Arc::new(MockCompiledContractCache::default());
+    let parallel = precompile_contracts_parallel(
+        3,
+        &codes,
+        &config,
+        ProtocolVersion::MAX,
+        Some(parallel_cache.clone()),
+    );
+
+    assert_eq!(parallel, sequential);
+    assert_eq!(parallel_cache.len(), cache.len());
+    let vm_kind = VMKind::for_protocol_version(ProtocolVersion::MAX);
+    for code in &codes {
+        let key = get_contract_cache_key(code, vm_kind, &config);
+        assert_eq!(parallel_cache.get(&key.0).unwrap(), cache.get(&key.0).unwrap());
+    }
+}
+
+#[cfg(feature = "async_cache")]
+#[tokio::test]
+async fn test_async_cache_adapter() {
+    let config = VMConfig::test();
+    let store = default_wasmer2_store();
+    let mock = Arc::new(MockCompiledContractCache::default());
+    let cache = AsyncCacheAdapter::new(mock.clone());
+    let code = unique_contract(8000);
+
+    let module = compile_module_cached_wasmer2_async(
+        &code,
+        &config,
+        Some(&cache),
+        &CacheOptions::default(),
+        &store,
+    )
+    .await
+    .unwrap();
+    assert!(module.unwrap().exports().count() > 0);
+    assert_eq!(mock.len(), 1);
+
+    // The artifact written through the adapter is readable by the synchronous code path.
+    let (_module, origin) = compile_module_cached_wasmer2_with_origin(
+        &code,
+        &config,
+        Some(&*mock),
+        &CacheOptions::default(),
+        &store,
+        true,
+        None,
+    )
+    .unwrap()
+    .unwrap();
+    assert_eq!(origin, CacheOrigin::PersistentCache);
+    assert_eq!(mock.len(), 1);
+}
+
+#[cfg(feature = "async_cache")]
+#[tokio::test]
+async fn test_async_cache_writes_match_sync() {
+    let config = VMConfig::test();
+    let store = default_wasmer2_store();
+    let code = unique_contract(8001);
+    let key = get_contract_cache_key(&code, VMKind::Wasmer2, &config);
+    let sync_cache = MockCompiledContractCache::default();
+    compile_module_cached_wasmer2(
+        &code,
+        &config,
+        Some(&sync_cache),
+        &CacheOptions::default(),
+        &store,
+        true,
+        None,
+    )
+    .unwrap()
+    .unwrap();
+
+    let mock = Arc::new(MockCompiledContractCache::default());
+    let cache = AsyncCacheAdapter::new(mock.clone());
+    compile_module_cached_wasmer2_async(
+        &code,
+        &config,
+        Some(&cache),
+        &CacheOptions::default(),
+        &store,
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(mock.get(&key.0).unwrap(), sync_cache.get(&key.0).unwrap());
+}
+
+#[test]
+fn test_expired_error_record() {
+    let config = VMConfig::test();
+    let store = default_wasmer2_store();
+    let cache = MockCompiledContractCache::default();
+    let code = unique_contract(9000);
+    let key = get_contract_cache_key(&code, VMKind::Wasmer2, &config);
+
+    let error = CompilationError::PrepareError(PrepareError::Deserialization);
+    let expired = CacheRecord::ErrorWithExpiry { error: error.clone(), expires_at_unix: 1 };
+    cache.put(&key.0, &expired.to_bytes()).unwrap();
+    let module = compile_module_cached_wasmer2(
+        &code,
+        &config,
+        Some(&cache),
+        &CacheOptions::default(),
+        &store,
+        false,
+        None,
+    )
+    .unwrap();
+    assert!(module.is_ok(), "expired error record should be recompiled");
+    let record = CacheRecord::from_bytes(&cache.get(&key.0).unwrap().unwrap()).unwrap();
+    assert!(matches!(record, CacheRecord::CodeForVm { .. }));
+
+    let code = unique_contract(9001);
+    let key = get_contract_cache_key(&code, VMKind::Wasmer2, &config);
+    let live = CacheRecord::ErrorWithExpiry { error: error.clone(), expires_at_unix: u64::MAX };
+    cache.put(&key.0, &live.to_bytes()).unwrap();
+    let module = compile_module_cached_wasmer2(
+        &code,
+        &config,
+        Some(&cache),
+        &CacheOptions::default(),
+        &store,
+        false,
+        None,
+    )
+    .unwrap();
+    assert_eq!(module.err(), Some(error));
+}
+
+#[test]
+fn test_describe_contract_cache_key() {
+    let code = unique_contract(10000);
+    let config = VMConfig::test();
+    let mut other_config = VMConfig::test();
+    other_config.limit_config.max_stack_height += 1;
+
+    let description = describe_contract_cache_key(&code, VMKind::Wasmer2, &config);
+    let other_description = describe_contract_cache_key(&code, VMKind::Wasmer2, &other_config);
+    assert_eq!(description.code_hash, *code.hash());
+    assert_eq!(description.code_hash, other_description.code_hash);
+    assert_eq!(description.vm_hash, other_description.vm_hash);
+    assert_ne!(description, other_description);
+    assert_ne!(
+        get_contract_cache_key(&code, VMKind::Wasmer2, &config),
+        get_contract_cache_key(&code, VMKind::Wasmer2, &other_config)
+    );
+}
+
+fn precompile_verified(
+    code: &ContractCode,
+    config: &VMConfig,
+    cache: &dyn CompiledContractCache,
+) -> Result<ContractPrecompilatonResult, ContractPrecompilatonError> {
+    let options =
+        PrecompileOptions { on_existing: OnExistingEntry::KeepIfLoadable, ..Default::default() };
+    precompile_contract_vm_with_options(VMKind::Wasmer2, code, config, Some(cache), &options)
+}
+
+#[test]
+fn test_precompile_contract_verified() {
+    let config = VMConfig::test();
+    let cache = MockCompiledContractCache::default();
+    let code = unique_contract(11000);
+    let key = get_contract_cache_key(&code, VMKind::Wasmer2, &config);
+
+    cache.put(&key.0, b"garbage").unwrap();
+    let result = precompile_contract_vm(VMKind::Wasmer2, &code, &config, Some(&cache), false);
+    assert_eq!(result, Ok(ContractPrecompilatonResult::ContractAlreadyInCache));
+
+    let result = precompile_verified(&code, &config, &cache);
+    assert_eq!(result, Ok(ContractPrecompilatonResult::RepairedCache));
+    let store = default_wasmer2_store();
+    deserialize_wasmer2(&cache.get(&key.0).unwrap().unwrap(), &store).unwrap().unwrap();
+
+    let result = precompile_verified(&code, &config, &cache);
+    assert_eq!(result, Ok(ContractPrecompilatonResult::ContractAlreadyInCache));
+}
+
+#[test]
+fn test_default_cache_key_hasher() {
+    let code = unique_contract(12000);
+    let config = VMConfig::test();
+    let description = describe_contract_cache_key(&code, VMKind::Wasmer2, &config);
+
+    // Borsh layout of `ContractCacheKey::Version4`, which must never change.
+    let mut serialized = vec![3u8];
+    serialized.extend_from_slice(&description.code_hash.0);
+    serialized.extend_from_slice(&description.vm_config_non_crypto_hash.to_le_bytes());
+    serialized.extend_from_slice(&VMKind::Wasmer2.try_to_vec().unwrap());
+    serialized.extend_from_slice(&description.vm_hash.to_le_bytes());
+    let expected = near_primitives::hash::hash(&serialized);
+
+    assert_eq!(get_contract_cache_key(&code, VMKind::Wasmer2, &config), expected);
+    assert_eq!(
+        get_contract_cache_key_with_hasher(&code, VMKind::Wasmer2, &config, &CryptoCacheKeyHasher),
+        expected
+    );
+}
+
+#[test]
+fn test_custom_cache_key_hasher() {
+    struct XorHasher;
+
+    impl CacheKeyHasher for XorHasher {
+        fn hash(&self, serialized_key: &[u8]) -> CryptoHash {
+            let mut res = [0u8; 32];
+            for (i, byte) in serialized_key.iter().enumerate() {
+                res[i % 32] ^= byte;
+            }
+            CryptoHash(res)
+        }
+    }
+
+    let code = unique_contract(12001);
+    let config = VMConfig::test();
+    let key = get_contract_cache_key_with_hasher(&code, VMKind::Wasmer2, &config, &XorHasher);
+    assert_ne!(key, get_contract_cache_key(&code, VMKind::Wasmer2, &config));
+    assert_eq!(
+        key,
+        get_contract_cache_key_with_hasher(&code, VMKind::Wasmer2, &config, &XorHasher)
+    );
+}
+
+#[test]
+fn test_compilation_metrics() {
+    let config = VMConfig::test();
+    let store = default_wasmer2_store();
+    let cache = MockCompiledContractCache::default();
+    let code = unique_contract(13000);
+    let compiled = metrics::COMPILATION_TIME.with_label_values(&["wasmer2"]);
+    let deserialized = metrics::DESERIALIZATION_TIME.with_label_values(&["wasmer2"]);
+
+    let before = compiled.get_sample_count();
+    let key = get_contract_cache_key(&code, VMKind::Wasmer2, &config);
+    compile_and_serialize_wasmer2(
+        code.code(),
+        &key,
+        &config,
+        &cache,
+        &CacheOptions::default(),
+        &store,
+    )
+    .unwrap()
+    .unwrap();
+    assert!(compiled.get_sample_count() > before);
+
+    let before = deserialized.get_sample_count();
+    deserialize_wasmer2(&cache.get(&key.0).unwrap().unwrap(), &store).unwrap().unwrap();
+    assert!(deserialized.get_sample_count() > before);
+}
+
+#[test]
+fn test_fs_cache() {
+    let dir = tempfile::tempdir().unwrap();
+    let cache = FsCompiledContractCache::open(dir.path().join("cache")).unwrap();
+    assert_eq!(cache.get(b"missing").unwrap(), None);
+    assert!(!cache.contains_key(b"missing").unwrap());
+
+    cache.put(b"key", b"value").unwrap();
+    assert_eq!(cache.get(b"key").unwrap(), Some(b"value".to_vec()));
+    cache.put(b"key", b"other value").unwrap();
+    assert_eq!(cache.get(b"key").unwrap(), Some(b"other value".to_vec()));
+    assert!(cache.contains_key(b"key").unwrap());
+
+    // Records survive reopening, and no temporary files are left behind.
+    let cache = FsCompiledContractCache::open(dir.path().join("cache")).unwrap();
+    assert_eq!(cache.get(b"key").unwrap(), Some(b"other value".to_vec()));
+    assert_eq!(std::fs::read_dir(dir.path().join("cache")).unwrap().count(), 1);
+
+    cache.remove(b"key").unwrap();
+    assert_eq!(cache.get(b"key").unwrap(), None);
+    cache.remove(b"key").unwrap();
+
+    // Failures other than a missing record aren't reported as one.
+    std::fs::remove_dir(dir.path().join("cache")).unwrap();
+    std::fs::write(dir.path().join("cache"), b"").unwrap();
+    assert!(cache.contains_key(b"key").is_err());
+}
+
+#[test]
+fn test_fs_cache_compact() {
+    let config = VMConfig::test();
+    let dir = tempfile::tempdir().unwrap();
+    let cache = FsCompiledContractCache::open(dir.path()).unwrap();
+    let code = unique_contract(68001);
+    let key = get_contract_cache_key(&code, VMKind::Wasmer2, &config);
+    assert_compiled(&precompile_contract_vm(VMKind::Wasmer2, &code, &config, Some(&cache), false));
+
+    let mut corrupt = cache.get(&key.0).unwrap().unwrap();
+    let last = corrupt.len() - 1;
+    corrupt[last] ^= 0xff;
+    cache.put(b"corrupt", &corrupt).unwrap();
+    let stray = dir.path().join("6b6579.1234.0.tmp");
+    std::fs::write(&stray, b"partial").unwrap();
+
+    let report = cache.compact(false).unwrap();
+    assert_eq!(report, CompactionReport { cleaned: 1, kept: 2, corrupt: 0 });
+    assert!(!stray.exists());
+
+    let report = cache.compact(true).unwrap();
+    assert_eq!(report, CompactionReport { cleaned: 0, kept: 1, corrupt: 1 });
+    assert_eq!(cache.get(b"corrupt").unwrap(), None);
+    let store = default_wasmer2_store();
+    let record = cache.get(&key.0).unwrap().unwrap();
+    assert!(deserialize_wasmer2(&record, &store).unwrap().is_ok());
+}
+
+#[test]
+fn test_precompile_would_skip() {
+    let config = VMConfig::test();
+    let cache = MockCompiledContractCache::default();
+    let code = unique_contract(14000);
+
+    assert!(!precompile_would_skip(&code, VMKind::Wasmer2, &config, None));
+    assert!(!precompile_would_skip(&code, VMKind::Wasmer2, &config, Some(&cache)));
+    assert_eq!(cache.len(), 0);
+    precompile_contract_vm(VMKind::Wasmer2, &code, &config, Some(&cache), false).unwrap();
+    assert!(precompile_would_skip(&code, VMKind::Wasmer2, &config, Some(&cache)));
+    assert!(!precompile_would_skip(&code, VMKind::Wasmer0, &config, Some(&cache)));
+}
+
+#[test]
+fn test_precompile_with_custom_store() {
+    let config = VMConfig::test();
+    let cache = MockCompiledContractCache::default();
+    let code = unique_contract(15000);
+    let stores_created = std::cell::Cell::new(0);
+    let make_store = || {
+        stores_created.set(stores_created.get() + 1);
+        default_wasmer2_store()
+    };
+
+    let options = PrecompileOptions { wasmer2_store: Some(&make_store), ..Default::default() };
+    let result = precompile_contract_vm_with_options(
+        VMKind::Wasmer2,
+        &code,
+        &config,
+        Some(&cache),
+        &options,
+    );
+    assert_matches!(result, Ok(ContractPrecompilatonResult::ContractCompiledWithSize(_)));
+    assert_eq!(stores_created.get(), 1);
+
+    let key = get_contract_cache_key(&code, VMKind::Wasmer2, &config);
+    let module = deserialize_wasmer2(&cache.get(&key.0).unwrap().unwrap(), &make_store());
+    assert!(module.unwrap().unwrap().exports().count() > 0);
+}
+
+#[test]
+fn test_mock_cache_keys() {
+    let config = VMConfig::test();
+    let cache = MockCompiledContractCache::default();
+    let codes = [unique_contract(16000), unique_contract(16001)];
+    for code in &codes {
+        precompile_contract_vm(VMKind::Wasmer2, code, &config, Some(&cache), false).unwrap();
+    }
+
+    let mut keys = cache.keys();
+    keys.sort();
+    let mut expected: Vec<_> = codes
+        .iter()
+        .map(|code| get_contract_cache_key(code, VMKind::Wasmer2, &config).0.to_vec())
+        .collect();
+    expected.sort();
+    assert_eq!(keys, expected);
+
+    for (key, value) in cache.entries() {
+        assert_eq!(cache.get(&key).unwrap(), Some(value));
+    }
+}
+
+#[test]
+fn test_namespaced_cache_key() {
+    let code = unique_contract(17000);
+    let config = VMConfig::test();
+    let key =
+        |namespace| get_namespaced_contract_cache_key(&code, VMKind::Wasmer2, &config, namespace);
+
+    // The default namespace keeps the keys of existing caches valid.
+    assert_eq!(key(""), get_contract_cache_key(&code, VMKind::Wasmer2, &config));
+    assert_ne!(key("mainnet"), key(""));
+    assert_ne!(key("mainnet"), key("shadow"));
+    assert_eq!(key("mainnet"), key("mainnet"));
+}
+
+#[test]
+fn test_recompile_on_deserialization_failure() {
+    let config = VMConfig::test();
+    let store = default_wasmer2_store();
+    let cache = MockCompiledContractCache::default();
+    let code = unique_contract(18000);
+    let key = get_contract_cache_key(&code, VMKind::Wasmer2, &config);
+    let bad_record = CacheRecord::Code(b"not a module".to_vec()).to_bytes();
+    cache.put(&key.0, &bad_record).unwrap();
+
+    let res = compile_module_cached_wasmer2(
+        &code,
+        &config,
+        Some(&cache),
+        &CacheOptions::default(),
+        &store,
+        false,
+        None,
+    );
+    assert_eq!(res.err(), Some(CacheError::DeserializationError));
+
+    let options =
+        CacheOptions { recompile_on_deserialization_failure: true, ..CacheOptions::default() };
+    let code = unique_contract(18001);
+    let key = get_contract_cache_key(&code, VMKind::Wasmer2, &config);
+    cache.put(&key.0, &bad_record).unwrap();
+    let res =
+        compile_module_cached_wasmer2(&code, &config, Some(&cache), &options, &store, false, None);
+    assert!(res.unwrap().unwrap().exports().count() > 0);
+    deserialize_wasmer2(&cache.get(&key.0).unwrap().unwrap(), &store).unwrap().unwrap();
+}
+
+#[test]
+fn test_put_if_absent() {
+    /// Counts the values actually written to the inner cache.
+    #[derive(Default)]
+    struct CountingCache {
+        inner: MockCompiledContractCache,
+        writes: std::sync::atomic::AtomicUsize,
+    }
+
+    impl CompiledContractCache for CountingCache {
+        fn put(&self, key: &[u8], value: &[u8]) -> Result<(), std::io::Error> {
+            self.writes.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            self.inner.put(key, value)
+        }
+
+        fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error> {
+            self.inner.get(key)
+        }
+
+        fn put_if_absent(&self, key: &[u8], value: &[u8]) -> Result<bool, std::io::Error> {
+            let inserted = self.inner.put_if_absent(key, value)?;
+            if inserted {
+                self.writes.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }
+            Ok(inserted)
+        }
+    }
+
+    let mock = MockCompiledContractCache::default();
+    assert!(mock.put_if_absent(b"key", b"first").unwrap());
+    assert!(!mock.put_if_absent(b"key", b"second").unwrap());
+    assert_eq!(mock.get(b"key").unwrap(), Some(b"first".to_vec()));
+
+    let config = VMConfig::test();
+    let cache = Arc::new(CountingCache::default());
+    let code = Arc::new(unique_contract(19000));
+    let key = get_contract_cache_key(&code, VMKind::Wasmer2, &config);
+    let threads: Vec<_> = (0..2)
+        .map(|_| {
+            let (cache, code, config) = (Arc::clone(&cache), Arc::clone(&code), config.clone());
+            std::thread::spawn(move || {
+                let store = default_wasmer2_store();
+                compile_and_serialize_wasmer2(
+                    code.code(),
+                    &key,
+                    &config,
+                    &*cache,
+                    &CacheOptions::default(),
+                    &store,
+                )
+                .unwrap()
+                .unwrap();
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(cache.writes.load(std::sync::atomic::Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_precompile_reports_size() {
+    let config = VMConfig::test();
+    let cache = MockCompiledContractCache::default();
+    let code = unique_contract(20000);
+
+    let result = precompile_contract_vm(VMKind::Wasmer2, &code, &config, Some(&cache), false);
+    let key = get_contract_cache_key(&code, VMKind::Wasmer2, &config);
+    let record = cache.get(&key.0).unwrap().unwrap();
+    assert_eq!(result, Ok(ContractPrecompilatonResult::ContractCompiledWithSize(record.len())));
+}
+
+#[test]
+fn test_load_wasmer2_module_from_bytes() {
+    let config = VMConfig::test();
+    let store = default_wasmer2_store();
+    let cache = MockCompiledContractCache::default();
+
+    let code = unique_contract(21000);
+    let key = get_contract_cache_key(&code, VMKind::Wasmer2, &config);
+    compile_and_serialize_wasmer2(
+        code.code(),
+        &key,
+        &config,
+        &cache,
+        &CacheOptions::default(),
+        &store,
+    )
+    .unwrap()
+    .unwrap();
+    let serialized = cache.get(&key.0).unwrap().unwrap();
+    let module = unsafe { load_wasmer2_module_from_bytes(&serialized, &store) };
+    assert!(module.unwrap().exports().count() > 0);
+
+    let invalid_code = ContractCode::new(vec![42; 100], None);
+    let key = get_contract_cache_key(&invalid_code, VMKind::Wasmer2, &config);
+    compile_and_serialize_wasmer2(
+        invalid_code.code(),
+        &key,
+        &config,
+        &cache,
+        &CacheOptions::default(),
+        &store,
+    )
+    .unwrap()
+    .unwrap_err();
+    let serialized = cache.get(&key.0).unwrap().unwrap();
+    let err = unsafe { load_wasmer2_module_from_bytes(&serialized, &store) };
+    assert_eq!(
+        err.unwrap_err(),
+        VMError::FunctionCallError(FunctionCallError::CompilationError(
+            CompilationError::PrepareError(PrepareError::Deserialization)
+        ))
+    );
+
+    let err = unsafe { load_wasmer2_module_from_bytes(b"garbage", &store) };
+    assert_eq!(err.unwrap_err(), VMError::CacheError(CacheError::DeserializationError));
+}
+
+#[test]
+fn test_cache_key_ignores_runtime_only_config() {
+    let code = unique_contract(22000);
+    let config = VMConfig::test();
+    let key = |config: &VMConfig| get_contract_cache_key(&code, VMKind::Wasmer2, config);
+
+    let mut runtime_only = config.clone();
+    runtime_only.regular_op_cost += 1;
+    runtime_only.ext_costs.base += 1;
+    runtime_only.limit_config.max_gas_burnt += 1;
+    assert_eq!(key(&config), key(&runtime_only));
+
+    let mut free = config.clone();
+    free.regular_op_cost = 0;
+    assert_ne!(key(&config), key(&free));
+    let mut instrumented = config.clone();
+    instrumented.grow_mem_cost += 1;
+    assert_ne!(key(&config), key(&instrumented));
+    let mut instrumented = config.clone();
+    instrumented.limit_config.max_stack_height += 1;
+    assert_ne!(key(&config), key(&instrumented));
+}
+
+#[test]
+fn test_precompile_queue() {
+    let config = VMConfig::test();
+    let mock = Arc::new(MockCompiledContractCache::default());
+    let queue = PrecompileQueue::new(mock.clone());
+    let code = Arc::new(unique_contract(23000));
+
+    assert!(queue.enqueue(code.clone(), &config, ProtocolVersion::MAX).unwrap());
+    // The same contract is not queued twice while it is pending.
+    queue.enqueue(code.clone(), &config, ProtocolVersion::MAX).unwrap();
+    assert!(queue.pending_len() <= 1);
+
+    let vm_kind = VMKind::for_protocol_version(ProtocolVersion::MAX);
+    let key = get_contract_cache_key(&code, vm_kind, &config);
+    let deadline = Instant::now() + std::time::Duration::from_secs(60);
+    while !mock.contains_key(&key.0).unwrap() {
+        assert!(Instant::now() < deadline, "contract was not precompiled in time");
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    drop(queue);
+    assert_eq!(mock.len(), 1);
+}
+
+#[test]
+fn test_precompile_queue_panic() {
+    /// Panics when writing the record under `0`.
+    struct PanickingCache(Vec<u8>, MockCompiledContractCache);
+
+    impl CompiledContractCache for PanickingCache {
+        fn put(&self, key: &[u8], value: &[u8]) -> Result<(), std::io::Error> {
+            assert_ne!(key, &self.0[..], "broken record");
+            self.1.put(key, value)
+        }
+
+        fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error> {
+            self.1.get(key)
+        }
+    }
+
+    let config = VMConfig::test();
+    let vm_kind = VMKind::for_protocol_version(ProtocolVersion::MAX);
+    let codes: Vec<_> = (0..2).map(|seed| Arc::new(unique_contract(23100 + seed))).collect();
+    let keys: Vec<_> =
+        codes.iter().map(|code| get_contract_cache_key(code, vm_kind, &config)).collect();
+    let cache = Arc::new(PanickingCache(keys[0].0.to_vec(), MockCompiledContractCache::default()));
+    let queue = PrecompileQueue::new(cache.clone());
+
+    // The worker survives the panic and moves on to the next contract.
+    for code in &codes {
+        assert!(queue.enqueue(code.clone(), &config, ProtocolVersion::MAX).unwrap());
+    }
+    let deadline = Instant::now() + std::time::Duration::from_secs(60);
+    while queue.pending_len() > 0 {
+        assert!(Instant::now() < deadline, "contracts were not precompiled in time");
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    assert!(!cache.1.contains_key(&keys[0].0).unwrap());
+    assert!(cache.1.contains_key(&keys[1].0).unwrap());
+}
+
+#[test]
+fn test_precompile_queue_drop() {
+    let config = VMConfig::test();
+    let mock = Arc::new(MockCompiledContractCache::default());
+    let queue = PrecompileQueue::new(mock.clone());
+    let count = 50;
+    for seed in 0..count {
+        let code = Arc::new(unique_contract(23200 + seed));
+        queue.enqueue(code, &config, ProtocolVersion::MAX).unwrap();
+    }
+    // Dropping the queue doesn't wait for the remaining contracts to be compiled.
+    drop(queue);
+    assert!(mock.len() < count as usize);
+}
+
+#[test]
+fn test_cache_record_versions() {
+    let store = default_wasmer2_store();
+    let error = CompilationError::PrepareError(PrepareError::Deserialization);
+    let record = CacheRecord::CompileModuleError(error.clone());
+
+    let mut serialized = record.to_bytes();
+    assert_matches!(deserialize_wasmer2(&serialized, &store), Ok(Err(e)) if e == error);
+    // Records written before versioning was introduced are still understood.
+    let legacy = record.try_to_vec().unwrap();
+    assert_matches!(deserialize_wasmer2(&legacy, &store), Ok(Err(e)) if e == error);
+
+    serialized[0] += 1;
+    let found = serialized[0];
+    assert_eq!(
+        deserialize_wasmer2(&serialized, &store).err(),
+        Some(CacheError::UnsupportedRecordVersion { found })
+    );
+}
+
+#[test]
+fn test_supported_record_versions() {
+    let versions = supported_record_versions();
+    assert!(!versions.is_empty());
+    let error = CompilationError::PrepareError(PrepareError::Deserialization);
+    let current = CacheRecord::CompileModuleError(error).to_bytes()[0];
+    assert!(versions.contains(&current));
+    assert!(!versions.contains(&(current + 1)));
+}
+
+#[test]
+fn test_cache_record_codecs() {
+    let error = CompilationError::PrepareError(PrepareError::Deserialization);
+    let record = CacheRecord::CompileModuleError(error.clone());
+
+    let serialized = record.to_bytes_with::<BorshCodec>(None);
+    assert_eq!(record.to_bytes()[13], BorshCodec::FORMAT);
+    assert_eq!(serialized[13], BorshCodec::FORMAT);
+    let decoded = CacheRecord::from_bytes(&serialized).unwrap().into_code();
+    assert_matches!(decoded, Ok(Err(e)) if e == error);
+
+    // Records from before codecs were introduced are read with borsh.
+    let borsh = record.try_to_vec().unwrap();
+    let mut checked = vec![0x81];
+    checked.extend_from_slice(&crc32fast::hash(&borsh).to_le_bytes());
+    checked.extend_from_slice(&borsh);
+    let decoded = CacheRecord::from_bytes(&checked).unwrap().into_code();
+    assert_matches!(decoded, Ok(Err(e)) if e == error);
+
+    // The format byte picks the codec, so an unknown one is rejected.
+    let mut unknown = serialized;
+    unknown[13] = 42;
+    let checksum = crc32fast::hash(&unknown[13..]);
+    unknown[1..5].copy_from_slice(&checksum.to_le_bytes());
+    assert_matches!(
+        CacheRecord::from_bytes(&unknown),
+        Err(CacheError::UnsupportedRecordFormat { found: 42 })
+    );
+}
+
+#[test]
+fn test_bypass_memcache() {
+    let code = unique_contract(24000);
+    let config = VMConfig::test();
+    let store = default_wasmer2_store();
+
+    let before = cache_stats();
+    compile_module_cached_wasmer2(
+        &code,
+        &config,
+        None,
+        &CacheOptions::default(),
+        &store,
+        true,
+        None,
+    )
+    .unwrap()
+    .unwrap();
+    compile_module_cached_wasmer2(
+        &code,
+        &config,
+        None,
+        &CacheOptions::default(),
+        &store,
+        true,
+        None,
+    )
+    .unwrap()
+    .unwrap();
+    let after = cache_stats();
+    assert!(after.misses >= before.misses + 2);
+
+    let key = get_contract_cache_key(&code, VMKind::Wasmer2, &config);
+    assert!(!registry().contains(VMKind::Wasmer2, &key));
+}
+
+#[test]
+fn test_wasmtime_version_matches_dependency() {
+    let manifest = include_str!("../../Cargo.toml");
+    let dependency = manifest.lines().find(|line| line.starts_with("wasmtime = ")).unwrap();
+    let version = crate::wasmtime_runner::WASMTIME_VERSION;
+    assert!(
+        dependency.contains(&format!("version = \"{}\"", version)),
+        "wasmtime {} is hashed into cache keys, but Cargo.toml has {}",
+        version,
+        dependency
+    );
+}
+
+#[test]
+fn test_current_vm_hash() {
+    assert_eq!(current_vm_hash(VMKind::Wasmer0), crate::wasmer_runner::wasmer0_vm_hash());
+    assert_eq!(current_vm_hash(VMKind::Wasmer2), crate::wasmer2_runner::wasmer2_vm_hash());
+    assert_eq!(current_vm_hash(VMKind::Wasmtime), crate::wasmtime_runner::wasmtime_vm_hash());
+
+    let config = VMConfig::test();
+    let code = unique_contract(25000);
+    let description = describe_contract_cache_key(&code, VMKind::Wasmer2, &config);
+    assert_eq!(description.vm_hash, current_vm_hash(VMKind::Wasmer2));
+}
+
+#[test]
+fn test_layered_cache() {
+    let fast = Arc::new(MockCompiledContractCache::default());
+    let slow = Arc::new(MockCompiledContractCache::default());
+    let layered = LayeredCache::new(fast.clone(), slow.clone());
+
+    // Writes go through to both tiers.
+    layered.put(b"written", b"value").unwrap();
+    assert_eq!(fast.get(b"written").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(slow.get(b"written").unwrap(), Some(b"value".to_vec()));
+
+    // A record only the slow tier has is found, and promoted into the fast tier.
+    slow.put(b"slow only", b"value").unwrap();
+    assert_eq!(fast.get(b"slow only").unwrap(), None);
+    assert!(layered.contains_key(b"slow only").unwrap());
+    assert_eq!(layered.get(b"slow only").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(fast.get(b"slow only").unwrap(), Some(b"value".to_vec()));
+
+    assert_eq!(layered.get(b"missing").unwrap(), None);
+    assert_eq!(fast.len(), 2);
+
+    layered.remove(b"written").unwrap();
+    assert!(!fast.contains_key(b"written").unwrap());
+    assert!(!slow.contains_key(b"written").unwrap());
+}
+
+#[test]
+fn test_export_import_cache() {
+    let config = VMConfig::test();
+    let cache = MockCompiledContractCache::default();
+    let codes = [unique_contract(27000), unique_contract(27001)];
+    let mut keys = Vec::new();
+    for code in &codes {
+        assert_compiled(&precompile_contract_vm(
+            VMKind::Wasmer2,
+            code,
+            &config,
+            Some(&cache),
+            false,
+        ));
+        keys.push(get_contract_cache_key(code, VMKind::Wasmer2, &config));
+    }
+
+    let mut archive = Vec::new();
+    export_cache(&cache, &keys, &mut archive).unwrap();
+
+    let imported = MockCompiledContractCache::default();
+    assert_eq!(import_cache(archive.as_slice(), &imported).unwrap(), 2);
+    for key in &keys {
+        assert_eq!(imported.get(&key.0).unwrap(), cache.get(&key.0).unwrap());
+    }
+    let result =
+        precompile_contract_vm(VMKind::Wasmer2, &codes[0], &config, Some(&imported), false);
+    assert_eq!(result, Ok(ContractPrecompilatonResult::ContractAlreadyInCache));
+
+    // A record length over the limit is refused before anything gets allocated for it.
+    let mut oversized = archive[..8 + 3 * 8 + 32].to_vec();
+    oversized.extend_from_slice(&u32::MAX.to_le_bytes());
+    let err =
+        import_cache(oversized.as_slice(), &MockCompiledContractCache::default()).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+    // Archives from a runtime with different VMs are refused.
+    archive[8] ^= 1;
+    let err = import_cache(archive.as_slice(), &MockCompiledContractCache::default()).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_module_cache_registry_budget() {
+    let registry = ModuleCacheRegistry::new(4);
+    let key = |seed: u8| CryptoHash([seed; 32]);
+
+    // Wasmer0 modules fill the whole budget while it is the only VM in use.
+    for seed in 0..4 {
+        registry.wasmer0.put(key(seed), Err(CacheError::ReadError), 0);
+        registry.enforce_budget(VMKind::Wasmer0);
+    }
+    assert_eq!(registry.len(VMKind::Wasmer0), 4);
+
+    // Once Wasmer2 is used, its modules push the Wasmer0 ones out, oldest first.
+    for seed in 10..13 {
+        registry.wasmer2.put(key(seed), Err(CacheError::ReadError), 0);
+        registry.enforce_budget(VMKind::Wasmer2);
+        assert!(registry.total_len() <= registry.budget());
+    }
+    assert_eq!(registry.len(VMKind::Wasmer0), 1);
+    assert_eq!(registry.len(VMKind::Wasmer2), 3);
+    assert!(registry.contains(VMKind::Wasmer0, &key(3)));
+    assert!(!registry.contains(VMKind::Wasmer0, &key(2)));
+
+    // Shrinking the budget evicts from both VMs.
+    registry.set_budget(2);
+    assert_eq!(registry.total_len(), 2);
+}
+
+#[test]
+fn test_module_cache_registry_on_evict() {
+    let registry = ModuleCacheRegistry::new(2);
+    let key = |seed: u8| CryptoHash([seed; 32]);
+    let evicted = Arc::new(std::sync::Mutex::new(Vec::new()));
+    registry.set_on_evict(Some(Box::new({
+        let evicted = Arc::clone(&evicted);
+        move |key: CryptoHash| evicted.lock().unwrap().push(key)
+    })));
+
+    for seed in 0..4 {
+        registry.wasmer2.put(key(seed), Err(CacheError::ReadError), 0);
+        registry.enforce_budget(VMKind::Wasmer2);
+    }
+    assert_eq!(*evicted.lock().unwrap(), vec![key(0), key(1)]);
+
+    // Without a hook, evictions go unreported.
+    registry.set_on_evict(None);
+    registry.set_budget(1);
+    assert_eq!(registry.total_len(), 1);
+    assert_eq!(evicted.lock().unwrap().len(), 2);
+}
+
+#[test]
+fn test_cache_record_checksum() {
+    let config = VMConfig::test();
+    let cache = MockCompiledContractCache::default();
+    let store = default_wasmer2_store();
+    let code = unique_contract(28000);
+    let key = get_contract_cache_key(&code, VMKind::Wasmer2, &config);
+    assert_compiled(&precompile_contract_vm(VMKind::Wasmer2, &code, &config, Some(&cache), false));
+
+    let mut serialized = cache.get(&key.0).unwrap().unwrap();
+    assert!(deserialize_wasmer2(&serialized, &store).unwrap().is_ok());
+    let last = serialized.len() - 1;
+    serialized[last] ^= 0xff;
+    assert_eq!(deserialize_wasmer2(&serialized, &store).err(), Some(CacheError::ChecksumMismatch));
+
+    // Corrupted artifacts are replaced by verified precompilation.
+    cache.put(&key.0, &serialized).unwrap();
+    let result = precompile_verified(&code, &config, &cache);
+    assert_eq!(result, Ok(ContractPrecompilatonResult::RepairedCache));
+    assert!(deserialize_wasmer2(&cache.get(&key.0).unwrap().unwrap(), &store).unwrap().is_ok());
+}
+
+#[test]
+fn test_compilation_hook() {
+    let config = VMConfig::test();
+    let cache = MockCompiledContractCache::default();
+    let store = default_wasmer2_store();
+    let code = unique_contract(29000);
+
+    let compilations = std::sync::Mutex::new(Vec::new());
+    let on_compile = |code_hash: CryptoHash, elapsed: std::time::Duration| {
+        compilations.lock().unwrap().push((code_hash, elapsed));
+    };
+    // The second call finds the artifact in the persistent cache.
+    for _ in 0..2 {
+        compile_module_cached_wasmer2(
+            &code,
+            &config,
+            Some(&cache),
+            &CacheOptions::default(),
+            &store,
+            true,
+            Some(&on_compile),
+        )
+        .unwrap()
+        .unwrap();
+    }
+    let compilations = compilations.into_inner().unwrap();
+    assert_eq!(compilations.len(), 1);
+    assert_eq!(compilations[0].0, *code.hash());
+}
+
+#[test]
+fn test_get_or_compile_module() {
+    let config = VMConfig::test();
+    let cache = MockCompiledContractCache::default();
+    let code = unique_contract(30000);
+    let protocol_version = ProtocolVersion::MAX;
+
+    let module = get_or_compile_module(
+        &code,
+        &config,
+        protocol_version,
+        Some(&cache),
+        &CacheOptions::default(),
+    )
+    .unwrap();
+    match (VMKind::for_protocol_version(protocol_version), module) {
+        (VMKind::Wasmer0, VMModule::Wasmer0(_))
+        | (VMKind::Wasmer2, VMModule::Wasmer2(_))
+        | (VMKind::Wasmtime, VMModule::Wasmtime(_)) => {}
+        _ => panic!("compiled for the wrong VM"),
+    }
+    assert_eq!(cache.len(), 1);
+}
+
+#[test]
+fn test_precompile_force() {
+    let config = VMConfig::test();
+    let cache = MockCompiledContractCache::default();
+    let code = unique_contract(31000);
+    let key = get_contract_cache_key(&code, VMKind::Wasmer2, &config);
+
+    let stale = CacheRecord::Code(b"stale".to_vec()).to_bytes();
+    cache.put(&key.0, &stale).unwrap();
+    let result = precompile_contract_vm(VMKind::Wasmer2, &code, &config, Some(&cache), false);
+    assert_eq!(result, Ok(ContractPrecompilatonResult::ContractAlreadyInCache));
+    assert_eq!(cache.get(&key.0).unwrap(), Some(stale.clone()));
+
+    let result = precompile_contract_vm(VMKind::Wasmer2, &code, &config, Some(&cache), true);
+    assert_eq!(result, Ok(ContractPrecompilatonResult::ContractRecompiled));
+    let fresh = cache.get(&key.0).unwrap().unwrap();
+    assert_ne!(fresh, stale);
+    let store = default_wasmer2_store();
+    assert!(deserialize_wasmer2(&fresh, &store).unwrap().is_ok());
+}
+
+/// Records the field names of every event with the `vm` target.
+struct EventFieldCollector {
+    events: Arc<std::sync::Mutex<Vec<Vec<&'static str>>>>,
+}
+
+impl tracing::Subscriber for EventFieldCollector {
+    fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, _span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+        tracing::span::Id::from_u64(1)
+    }
+
+    fn record(&self, _span: &tracing::span::Id, _values: &tracing::span::Record<'_>) {}
+
+    fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+
+    fn event(&self, event: &tracing::Event<'_>) {
+        if event.metadata().target() == "vm" {
+            let fields = event.fields().map(|field| field.name()).collect();
+            self.events.lock().unwrap().push(fields);
+        }
+    }
+
+    fn enter(&self, _span: &tracing::span::Id) {}
+
+    fn exit(&self, _span: &tracing::span::Id) {}
+}
+
+#[test]
+fn test_cache_key_logging() {
+    let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let collector = EventFieldCollector { events: Arc::clone(&events) };
+    let code = unique_contract(32000);
+    tracing::subscriber::with_default(collector, || {
+        get_contract_cache_key(&code, VMKind::Wasmer2, &VMConfig::test())
+    });
+
+    let events = events.lock().unwrap();
+    let expected = [
+        "code_hash",
+        "vm_config_non_crypto_hash",
+        "vm_kind",
+        "vm_hash",
+        "namespace",
+        "protocol_version",
+    ];
+    assert!(
+        events.iter().any(|fields| expected.iter().all(|name| fields.contains(name))),
+        "no event with all key components in {:?}",
+        events
+    );
+}
+
+#[test]
+fn test_module_cache_byte_budget() {
+    let registry = ModuleCacheRegistry::new(DEFAULT_MODULE_CACHE_CAPACITY);
+    let key = |seed: u8| CryptoHash([seed; 32]);
+    registry.set_byte_budget(Some(1000));
+    assert_eq!(registry.byte_budget(), Some(1000));
+
+    registry.wasmer0.put(key(0), Err(CacheError::ReadError), 400);
+    registry.enforce_budget(VMKind::Wasmer0);
+    for seed in 1..4 {
+        registry.wasmer2.put(key(seed), Err(CacheError::ReadError), 400);
+        registry.enforce_budget(VMKind::Wasmer2);
+        assert!(registry.total_weight() <= 1000);
+    }
+    // Far fewer modules than the count limit are kept, the other VM's module going first.
+    assert_eq!(registry.total_len(), 2);
+    assert!(!registry.contains(VMKind::Wasmer0, &key(0)));
+    assert!(!registry.contains(VMKind::Wasmer2, &key(1)));
+    assert!(registry.contains(VMKind::Wasmer2, &key(3)));
+
+    registry.set_byte_budget(None);
+    assert_eq!(registry.byte_budget(), None);
+}
+
+#[test]
+fn test_precompile_cache_effectiveness() {
+    let config = VMConfig::test();
+    let cache = MockCompiledContractCache::default();
+    let codes = [unique_contract(33000), unique_contract(33001)];
+
+    let without_cache = precompile_contracts(&codes, &config, ProtocolVersion::MAX, None);
+    let with_cache = precompile_contracts(&codes, &config, ProtocolVersion::MAX, Some(&cache));
+    for result in without_cache {
+        assert_eq!(result, Ok(ContractPrecompilatonResult::CacheNotAvailable));
+        assert!(!result.unwrap().is_cache_effective());
+    }
+    for result in with_cache {
+        assert_compiled(&result);
+        assert!(result.unwrap().is_cache_effective());
+    }
+    let result = precompile_contract_vm(VMKind::Wasmer2, &codes[0], &config, Some(&cache), false);
+    assert!(result.unwrap().is_cache_effective());
+}
+
+#[test]
+fn test_disable_caching_compilation_errors() {
+    let config = VMConfig::test();
+    let cache = MockCompiledContractCache::default();
+    let store = default_wasmer2_store();
+    // Invalid code, unique to this test.
+    let code = ContractCode::new(vec![43; 100], None);
+
+    let compilations = std::sync::atomic::AtomicUsize::new(0);
+    let on_compile = |_code_hash: CryptoHash, _elapsed: std::time::Duration| {
+        compilations.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    };
+    let options = CacheOptions { cache_compilation_errors: false, ..CacheOptions::default() };
+    for _ in 0..2 {
+        let res = compile_module_cached_wasmer2(
+            &code,
+            &config,
+            Some(&cache),
+            &options,
+            &store,
+            false,
+            Some(&on_compile),
+        );
+        assert!(res.unwrap().is_err());
+    }
+    assert_eq!(compilations.into_inner(), 2);
+    assert_eq!(cache.len(), 0);
+}
+
+#[test]
+fn test_deserialize_wasmer2_checked() {
+    let config = VMConfig::test();
+    let store = default_wasmer2_store();
+    let cache = MockCompiledContractCache::default();
+    let code = unique_contract(34000);
+    let key = get_contract_cache_key(&code, VMKind::Wasmer2, &config);
+    compile_and_serialize_wasmer2(
+        code.code(),
+        &key,
+        &config,
+        &cache,
+        &CacheOptions::default(),
+        &store,
+    )
+    .unwrap()
+    .unwrap();
+    let record = cache.get(&key.0).unwrap().unwrap();
+    assert!(deserialize_wasmer2_checked(&record, &store).unwrap().exports().count() > 0);
+
+    let artifact = CacheRecord::from_bytes(&record).unwrap().into_code().unwrap().unwrap();
+    for len in [0, 1, 21, 22, 31, 32, 40, record.len() / 2, record.len() - 1] {
+        assert_matches!(
+            deserialize_wasmer2_checked(&record[..len], &store),
+            Err(_),
+            "truncated record of {} bytes",
+            len
+        );
+    }
+    let mut corrupted = record.clone();
+    *corrupted.last_mut().unwrap() ^= 1;
+    assert_eq!(
+        deserialize_wasmer2_checked(&corrupted, &store).unwrap_err(),
+        CacheError::ChecksumMismatch
+    );
+
+    // Records without a VM hash, or for another version of wasmer2, aren't trusted.
+    let legacy = CacheRecord::code(artifact.clone(), None).to_bytes();
+    assert_eq!(
+        deserialize_wasmer2_checked(&legacy, &store).unwrap_err(),
+        CacheError::DeserializationError
+    );
+    let legacy = CacheRecord::Code(artifact.clone()).try_to_vec().unwrap();
+    assert_eq!(
+        deserialize_wasmer2_checked(&legacy, &store).unwrap_err(),
+        CacheError::DeserializationError
+    );
+    let expected = current_vm_hash(VMKind::Wasmer2);
+    let found = expected.wrapping_add(1);
+    let other_vm =
+        CacheRecord::CodeForVm { vm_hash: found, compression: None, data: artifact }.to_bytes();
+    assert_eq!(
+        deserialize_wasmer2_checked(&other_vm, &store).unwrap_err(),
+        CacheError::VmHashMismatch { expected, found }
+    );
+
+    let error = CompilationError::PrepareError(PrepareError::Deserialization);
+    let record = CacheRecord::CompileModuleError(error).to_bytes();
+    assert_eq!(
+        deserialize_wasmer2_checked(&record, &store).unwrap_err(),
+        CacheError::DeserializationError
+    );
+}
+
+#[test]
+fn test_negative_caching_wrapper() {
+    /// Counts the lookups reaching the inner cache.
+    #[derive(Default)]
+    struct CountingCache {
+        inner: MockCompiledContractCache,
+        reads: std::sync::atomic::AtomicUsize,
+    }
+
+    impl CompiledContractCache for CountingCache {
+        fn put(&self, key: &[u8], value: &[u8]) -> Result<(), std::io::Error> {
+            self.inner.put(key, value)
+        }
+
+        fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error> {
+            self.reads.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            self.inner.get(key)
+        }
+    }
+
+    let inner = Arc::new(CountingCache::default());
+    let reads = || inner.reads.load(std::sync::atomic::Ordering::SeqCst);
+    let cache = NegativeCachingWrapper::new(inner.clone(), std::time::Duration::from_secs(60));
+
+    for _ in 0..3 {
+        assert_eq!(cache.get(b"missing").unwrap(), None);
+    }
+    assert_eq!(reads(), 1);
+
+    // Writing the key invalidates the negative entry.
+    cache.put(b"missing", b"value").unwrap();
+    assert_eq!(cache.get(b"missing").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(reads(), 2);
+
+    // Once the TTL passes, misses reach the inner cache again.
+    let cache = NegativeCachingWrapper::new(inner.clone(), std::time::Duration::ZERO);
+    cache.get(b"other").unwrap();
+    cache.get(b"other").unwrap();
+    assert_eq!(reads(), 4);
+}
+
+#[test]
+fn test_contract_cache_key_bytes_golden() {
+    let description = ContractCacheKeyDescription {
+        code_hash: CryptoHash([7; 32]),
+        vm_config_non_crypto_hash: 0x0102_0304_0506_0708,
+        vm_kind: VMKind::Wasmer2,
+        vm_hash: 42,
+        namespace: String::new(),
+        protocol_version: None,
+        epoch: 0,
+    };
+    let mut expected = vec![3];
+    expected.extend_from_slice(&[7; 32]);
+    expected.extend_from_slice(&[8, 7, 6, 5, 4, 3, 2, 1]);
+    expected.push(2);
+    expected.extend_from_slice(&[42, 0, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(contract_cache_key_bytes(&description), expected);
+
+    let namespaced =
+        ContractCacheKeyDescription { namespace: "ns".to_string(), ..description.clone() };
+    let mut expected = vec![4, 2, 0, 0, 0, b'n', b's'];
+    expected.extend_from_slice(&[7; 32]);
+    expected.extend_from_slice(&[8, 7, 6, 5, 4, 3, 2, 1]);
+    expected.push(2);
+    expected.extend_from_slice(&[42, 0, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(contract_cache_key_bytes(&namespaced), expected);
+
+    let versioned = ContractCacheKeyDescription { protocol_version: Some(50), ..description };
+    let mut expected = vec![5, 0, 0, 0, 0, 50, 0, 0, 0];
+    expected.extend_from_slice(&[7; 32]);
+    expected.extend_from_slice(&[8, 7, 6, 5, 4, 3, 2, 1]);
+    expected.push(2);
+    expected.extend_from_slice(&[42, 0, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(contract_cache_key_bytes(&versioned), expected);
+
+    let epoch = ContractCacheKeyDescription { epoch: 1, ..versioned };
+    let mut expected = vec![6, 1, 0, 0, 0, 0, 0, 0, 0, 1, 50, 0, 0, 0];
+    expected.extend_from_slice(&[7; 32]);
+    expected.extend_from_slice(&[8, 7, 6, 5, 4, 3, 2, 1]);
+    expected.push(2);
+    expected.extend_from_slice(&[42, 0, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(contract_cache_key_bytes(&epoch), expected);
+
+    // These bytes are exactly what the cache key is derived from.
+    let config = VMConfig::test();
+    let code = unique_contract(35000);
+    let description = describe_contract_cache_key(&code, VMKind::Wasmer2, &config);
+    assert_eq!(
+        near_primitives::hash::hash(&contract_cache_key_bytes(&description)),
+        get_contract_cache_key(&code, VMKind::Wasmer2, &config)
+    );
+}
+
+#[test]
+fn test_vm_kind_cache_key_bytes_golden() {
+    // `VMKind` is borsh-serialized into the cache key by variant index, so new VMs must only ever
+    // be appended to the enum. Pin the byte of every existing variant.
+    for (vm_kind, byte) in [(VMKind::Wasmer0, 0), (VMKind::Wasmtime, 1), (VMKind::Wasmer2, 2)] {
+        let description = ContractCacheKeyDescription {
+            code_hash: CryptoHash([7; 32]),
+            vm_config_non_crypto_hash: 0x0102_0304_0506_0708,
+            vm_kind,
+            vm_hash: 42,
+            namespace: String::new(),
+            protocol_version: None,
+            epoch: 0,
+        };
+        let mut expected = vec![3];
+        expected.extend_from_slice(&[7; 32]);
+        expected.extend_from_slice(&[8, 7, 6, 5, 4, 3, 2, 1]);
+        expected.push(byte);
+        expected.extend_from_slice(&[42, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(contract_cache_key_bytes(&description), expected, "{:?}", vm_kind);
+    }
+}
+
+#[test]
+fn test_faulty_mock_cache() {
+    let config = VMConfig::test();
+    let store = default_wasmer2_store();
+    let code = unique_contract(37000);
+    let key = get_contract_cache_key(&code, VMKind::Wasmer2, &config);
+
+    // Failed writes of both modules and compilation errors surface as `WriteError`.
+    let cache = FaultyMockCache::new(0).with_put_failure_probability(1.0);
+    let res = compile_and_serialize_wasmer2(
+        code.code(),
+        &key,
+        &config,
+        &cache,
+        &CacheOptions::default(),
+        &store,
+    );
+    assert_matches!(res, Err(CacheError::WriteError));
+    let invalid_code = ContractCode::new(vec![37; 100], None);
+    let invalid_key = get_contract_cache_key(&invalid_code, VMKind::Wasmer2, &config);
+    let res = compile_and_serialize_wasmer2(
+        invalid_code.code(),
+        &invalid_key,
+        &config,
+        &cache,
+        &CacheOptions::default(),
+        &store,
+    );
+    assert_matches!(res, Err(CacheError::WriteError));
+    assert_eq!(cache.inner().len(), 0);
+
+    // Failed reads surface as `ReadError`.
+    let cache = FaultyMockCache::new(0).with_get_failure_probability(1.0);
+    let res = compile_module_cached_wasmer2(
+        &code,
+        &config,
+        Some(&cache),
+        &CacheOptions::default(),
+        &store,
+        true,
+        None,
+    );
+    assert_matches!(res, Err(CacheError::ReadError));
+    assert!(cache.get_many(&[b"key".to_vec()]).is_err());
+    assert!(cache.touch(b"key").is_err());
+
+    // Without faults, every operation reaches the wrapped cache.
+    let cache = FaultyMockCache::new(0);
+    cache.put(b"key", b"value").unwrap();
+    assert_eq!(
+        cache.get_many(&[b"key".to_vec(), b"other".to_vec()]).unwrap(),
+        vec![Some(b"value".to_vec()), None]
+    );
+    cache.touch(b"key").unwrap();
+    assert_eq!(cache.approximate_len(), Some(1));
+
+    // The same seed injects the same failures.
+    let outcomes = |seed| {
+        let cache = FaultyMockCache::new(seed).with_get_failure_probability(0.5);
+        (0..64).map(|_| cache.get(b"key").is_err()).collect::<Vec<_>>()
+    };
+    assert_eq!(outcomes(42), outcomes(42));
+    assert!(outcomes(42).contains(&true));
+    assert!(outcomes(42).contains(&false));
+
+    let latency = std::time::Duration::from_millis(10);
+    let cache = FaultyMockCache::new(0).with_latency(latency);
+    let start = Instant::now();
+    cache.put(b"key", b"value").unwrap();
+    assert!(start.elapsed() >= latency);
+}
+
+#[test]
+fn test_extract_install_artifact() {
+    let config = VMConfig::test();
+    let store = default_wasmer2_store();
+    let code = unique_contract(38000);
+    let key = get_contract_cache_key(&code, VMKind::Wasmer2, &config);
+    let cache = MockCompiledContractCache::default();
+    assert_eq!(extract_artifact(&cache, &key), Ok(None));
+
+    compile_and_serialize_wasmer2(
+        code.code(),
+        &key,
+        &config,
+        &cache,
+        &CacheOptions::default(),
+        &store,
+    )
+    .unwrap()
+    .unwrap();
+    let artifact = extract_artifact(&cache, &key).unwrap().unwrap();
+
+    let vm_hash = current_vm_hash(VMKind::Wasmer2);
+    let fresh = MockCompiledContractCache::default();
+    install_artifact(&fresh, &key, artifact.clone(), vm_hash, &CacheOptions::default()).unwrap();
+    assert_eq!(extract_artifact(&fresh, &key), Ok(Some(artifact.clone())));
+    let compilations = std::sync::atomic::AtomicUsize::new(0);
+    let on_compile = |_code_hash: CryptoHash, _elapsed: std::time::Duration| {
+        compilations.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    };
+    compile_module_cached_wasmer2(
+        &code,
+        &config,
+        Some(&fresh),
+        &CacheOptions::default(),
+        &store,
+        true,
+        Some(&on_compile),
+    )
+    .unwrap()
+    .unwrap();
+    assert_eq!(compilations.load(std::sync::atomic::Ordering::Relaxed), 0);
+
+    // An artifact from another VM version isn't loaded, but compiled again.
+    let foreign = MockCompiledContractCache::default();
+    let foreign_hash = vm_hash.wrapping_add(1);
+    install_artifact(&foreign, &key, artifact, foreign_hash, &CacheOptions::default()).unwrap();
+    compile_module_cached_wasmer2(
+        &code,
+        &config,
+        Some(&foreign),
+        &CacheOptions::default(),
+        &store,
+        true,
+        Some(&on_compile),
+    )
+    .unwrap()
+    .unwrap();
+    assert_eq!(compilations.into_inner(), 1);
+
+    let invalid_code = ContractCode::new(vec![38; 100], None);
+    let key = get_contract_cache_key(&invalid_code, VMKind::Wasmer2, &config);
+    compile_and_serialize_wasmer2(
+        invalid_code.code(),
+        &key,
+        &config,
+        &cache,
+        &CacheOptions::default(),
+        &store,
+    )
+    .unwrap()
+    .unwrap_err();
+    assert_eq!(extract_artifact(&cache, &key), Err(CacheError::CachedCompilationError));
+}
+
+#[test]
+fn test_protocol_versioned_cache_key() {
+    let config = VMConfig::test();
+    let code = unique_contract(39000);
+    let key = |protocol_version| {
+        get_protocol_versioned_contract_cache_key(&code, VMKind::Wasmer2, &config, protocol_version)
+    };
+    let versioned = key(50);
+    assert_eq!(versioned, key(50));
+    assert_ne!(versioned, key(49));
+    assert_ne!(versioned, get_contract_cache_key(&code, VMKind::Wasmer2, &config));
+}
+
+#[test]
+fn test_warm_memcache() {
+    let config = VMConfig::test();
+    let store = default_wasmer2_store();
+    let cache = MockCompiledContractCache::default();
+    let code = unique_contract(40000);
+    let key = get_contract_cache_key(&code, VMKind::Wasmer2, &config);
+    let missing_key = get_contract_cache_key(&unique_contract(40001), VMKind::Wasmer2, &config);
+    compile_and_serialize_wasmer2(
+        code.code(),
+        &key,
+        &config,
+        &cache,
+        &CacheOptions::default(),
+        &store,
+    )
+    .unwrap()
+    .unwrap();
+    assert!(!registry().contains(VMKind::Wasmer2, &key));
+    let invalid_code = ContractCode::new(vec![40; 100], None);
+    let error_key = get_contract_cache_key(&invalid_code, VMKind::Wasmer2, &config);
+    compile_and_serialize_wasmer2(
+        invalid_code.code(),
+        &error_key,
+        &config,
+        &cache,
+        &CacheOptions::default(),
+        &store,
+    )
+    .unwrap()
+    .unwrap_err();
+
+    // Records are checked against the limits of the options.
+    let options = CacheOptions { max_record_bytes: 1, ..CacheOptions::default() };
+    assert_eq!(warm_memcache(&[key], &cache, &options, &store), 0);
+    assert!(!registry().contains(VMKind::Wasmer2, &key));
+
+    // The cached compilation error isn't counted as a loaded module.
+    let keys = [key, missing_key, error_key];
+    assert_eq!(warm_memcache(&keys, &cache, &CacheOptions::default(), &store), 1);
+    assert!(registry().contains(VMKind::Wasmer2, &key));
+    assert!(!registry().contains(VMKind::Wasmer2, &missing_key));
+
+    let before = cache_stats();
+    compile_module_cached_wasmer2(
+        &code,
+        &config,
+        Some(&cache),
+        &CacheOptions::default(),
+        &store,
+        false,
+        None,
+    )
+    .unwrap()
+    .unwrap();
+    assert!(cache_stats().memory_hits > before.memory_hits);
+}
+
+#[test]
+fn test_gc_stale_records() {
+    let config = VMConfig::test();
+    let store = default_wasmer2_store();
+    let cache = MockCompiledContractCache::default();
+    let index = Arc::new(VmHashIndex::new(Arc::new(MockCompiledContractCache::default())));
+    let options = CacheOptions { vm_hash_index: Some(index.clone()), ..CacheOptions::default() };
+
+    // Compiled records are indexed with the current VM hash.
+    let code = unique_contract(41000);
+    let key = get_contract_cache_key(&code, VMKind::Wasmer2, &config);
+    compile_and_serialize_wasmer2(code.code(), &key, &config, &cache, &options, &store)
+        .unwrap()
+        .unwrap();
+    let vm_hash = current_vm_hash(VMKind::Wasmer2);
+    assert_eq!(index.vm_hash(&key).unwrap(), Some(vm_hash));
+
+    let stale_key = CryptoHash([1; 32]);
+    cache.put(&stale_key.0, b"stale").unwrap();
+    index.record(&stale_key, vm_hash.wrapping_add(1)).unwrap();
+    let unindexed_key = CryptoHash([2; 32]);
+    cache.put(&unindexed_key.0, b"unindexed").unwrap();
+
+    let keys = [key, stale_key, unindexed_key];
+    assert_eq!(gc_stale_records(&cache, &index, &keys, &[vm_hash]).unwrap(), 1);
+    assert!(cache.contains_key(&key.0).unwrap());
+    assert!(!cache.contains_key(&stale_key.0).unwrap());
+    assert!(cache.contains_key(&unindexed_key.0).unwrap());
+    assert_eq!(index.vm_hash(&stale_key).unwrap(), None);
+}
+
+#[test]
+fn test_compile_wasmer2_from_artifact_only() {
+    let config = VMConfig::test();
+    let store = default_wasmer2_store();
+    let cache = MockCompiledContractCache::default();
+    let code = unique_contract(42000);
+
+    let err = compile_wasmer2_from_artifact_only(
+        &code,
+        &config,
+        &cache,
+        &CacheOptions::default(),
+        &store,
+    )
+    .unwrap_err();
+    assert_eq!(err, VMError::CacheError(CacheError::MissingCachedArtifact));
+    assert_eq!(cache.len(), 0);
+
+    // The same goes for the regular entry point when the options require cached contracts.
+    let options = CacheOptions { require_cached: true, ..CacheOptions::default() };
+    let res =
+        compile_module_cached_wasmer2(&code, &config, Some(&cache), &options, &store, false, None);
+    assert_matches!(res, Err(CacheError::MissingCachedArtifact));
+    assert_eq!(cache.len(), 0);
+
+    precompile_contract_vm(VMKind::Wasmer2, &code, &config, Some(&cache), false).unwrap();
+    let module = compile_wasmer2_from_artifact_only(
+        &code,
+        &config,
+        &cache,
+        &CacheOptions::default(),
+        &store,
+    )
+    .unwrap();
+    assert!(module.exports().count() > 0);
+}
+
+#[test]
+fn test_cache_keys_for_codes() {
+    let config = VMConfig::test();
+    let codes: Vec<_> = (43000..43003).map(unique_contract).collect();
+    let keys = cache_keys_for_codes(&codes, VMKind::Wasmer2, &config);
+    let expected: Vec<_> =
+        codes.iter().map(|code| get_contract_cache_key(code, VMKind::Wasmer2, &config)).collect();
+    assert_eq!(keys, expected);
+    assert!(cache_keys_for_codes(&[], VMKind::Wasmer2, &config).is_empty());
+}
+
+#[test]
+fn test_record_too_large() {
+    /// Returns a record one byte over the default limit for every key.
+    struct OversizedCache;
+
+    impl CompiledContractCache for OversizedCache {
+        fn put(&self, _key: &[u8], _value: &[u8]) -> Result<(), std::io::Error> {
+            Ok(())
+        }
+
+        fn get(&self, _key: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error> {
+            // Zeroed allocations aren't backed by memory until written to.
+            Ok(Some(vec![0; DEFAULT_MAX_RECORD_BYTES + 1]))
+        }
+    }
+
+    let config = VMConfig::test();
+    let store = default_wasmer2_store();
+    let code = unique_contract(44000);
+    let res = compile_module_cached_wasmer2(
+        &code,
+        &config,
+        Some(&OversizedCache),
+        &CacheOptions::default(),
+        &store,
+        true,
+        None,
+    );
+    let len = DEFAULT_MAX_RECORD_BYTES as u64 + 1;
+    let max = DEFAULT_MAX_RECORD_BYTES as u64;
+    assert_eq!(res.err(), Some(CacheError::RecordTooLarge { len, max }));
+
+    // The limit is per call, a valid record is rejected by a lower one.
+    let cache = MockCompiledContractCache::default();
+    precompile_contract_vm(VMKind::Wasmer2, &code, &config, Some(&cache), false).unwrap();
+    let key = get_contract_cache_key(&code, VMKind::Wasmer2, &config);
+    let len = cache.get(&key.0).unwrap().unwrap().len() as u64;
+    let options = CacheOptions { max_record_bytes: 16, ..CacheOptions::default() };
+    let res =
+        compile_module_cached_wasmer2(&code, &config, Some(&cache), &options, &store, true, None);
+    assert_eq!(res.err(), Some(CacheError::RecordTooLarge { len, max: 16 }));
+    let res = compile_module_cached_wasmer2(
+        &code,
+        &config,
+        Some(&cache),
+        &CacheOptions::default(),
+        &store,
+        true,
+        None,
+    );
+    assert!(res.unwrap().is_ok());
+}
+
+#[test]
+fn test_in_memory_contract_cache() {
+    let config = VMConfig::test();
+    let cache = InMemoryContractCache::with_capacity(1);
+    let codes = [unique_contract(45000), unique_contract(45001)];
+    for code in &codes {
+        let result = precompile_contract(code, &config, ProtocolVersion::MAX, Some(&cache));
+        assert_compiled(&result);
+        let result = precompile_contract(code, &config, ProtocolVersion::MAX, Some(&cache));
+        assert_eq!(result, Ok(ContractPrecompilatonResult::ContractAlreadyInCache));
+    }
+    // The older contract was evicted.
+    assert_eq!(cache.len(), 1);
+}
+
+#[test]
+fn test_record_age() {
+    let config = VMConfig::test();
+    let store = default_wasmer2_store();
+    let cache = MockCompiledContractCache::default();
+    let code = unique_contract(46000);
+    let key = get_contract_cache_key(&code, VMKind::Wasmer2, &config);
+    assert_eq!(record_age(&cache, &key), None);
+
+    compile_and_serialize_wasmer2(
+        code.code(),
+        &key,
+        &config,
+        &cache,
+        &CacheOptions::default(),
+        &store,
+    )
+    .unwrap()
+    .unwrap();
+    assert!(record_age(&cache, &key).unwrap() <= std::time::Duration::from_secs(5));
+
+    // The creation time is only stored in cached records, and isn't covered by the checksum, so
+    // that compiling a contract always produces the same record.
+    let options = CacheOptions::default();
+    let serialized = compile_to_serialized_wasmer2(&code, &config, &options, &store).unwrap();
+    assert_eq!(
+        serialized,
+        compile_to_serialized_wasmer2(&code, &config, &options, &store).unwrap()
+    );
+    let cached = cache.get(&key.0).unwrap().unwrap();
+    assert_ne!(cached, serialized);
+    assert_eq!(cached[..5], serialized[..5]);
+    assert_eq!(cached[13..], serialized[13..]);
+    let fresh = MockCompiledContractCache::default();
+    fresh.put(&key.0, &serialized).unwrap();
+    assert_eq!(record_age(&fresh, &key), None);
+
+    // Records from before creation times were recorded have no age.
+    let legacy_key = CryptoHash([46; 32]);
+    let legacy = CacheRecord::Code(b"legacy".to_vec()).try_to_vec().unwrap();
+    cache.put(&legacy_key.0, &legacy).unwrap();
+    assert_eq!(record_age(&cache, &legacy_key), None);
+}
+
+#[test]
+fn test_precompile_prepared_contract() {
+    let config = VMConfig::test();
+    let store = default_wasmer2_store();
+    let code = unique_contract(47000);
+    let key = get_contract_cache_key(&code, VMKind::Wasmer2, &config);
+
+    let cache = MockCompiledContractCache::default();
+    precompile_contract_vm(VMKind::Wasmer2, &code, &config, Some(&cache), false).unwrap();
+    let prepared = prepare::prepare_contract(code.code(), &config).unwrap();
+    let prepared_code = ContractCode::new(prepared, Some(*code.hash()));
+    let options = PrecompileOptions { already_prepared: true, ..Default::default() };
+    let prepared_cache = MockCompiledContractCache::default();
+    let precompile_prepared = || {
+        precompile_contract_vm_with_options(
+            VMKind::Wasmer2,
+            &prepared_code,
+            &config,
+            Some(&prepared_cache),
+            &options,
+        )
+    };
+    let result = precompile_prepared();
+    assert_matches!(result, Ok(ContractPrecompilatonResult::ContractCompiledWithSize(_)));
+
+    let load = |cache: &MockCompiledContractCache| {
+        let serialized = cache.get(&key.0).unwrap().unwrap();
+        unsafe { load_wasmer2_module_from_bytes(&serialized, &store) }.unwrap()
+    };
+    let (module, prepared_module) = (load(&cache), load(&prepared_cache));
+    let exports = |module: &wasmer::Module| {
+        module.exports().map(|export| export.name().to_string()).collect::<Vec<_>>()
+    };
+    let imports = |module: &wasmer::Module| {
+        module.imports().map(|import| import.name().to_string()).collect::<Vec<_>>()
+    };
+    assert_eq!(exports(&module), exports(&prepared_module));
+    assert_eq!(imports(&module), imports(&prepared_module));
+
+    let result = precompile_prepared();
+    assert_eq!(result, Ok(ContractPrecompilatonResult::ContractAlreadyInCache));
+}
+
+#[test]
+fn test_mirroring_cache_mirror_failure() {
+    let primary = Arc::new(MockCompiledContractCache::default());
+    let mirroring = MirroringCache::new(primary.clone(), Arc::new(FailingCache));
+
+    // Failures of the mirror don't fail the operations.
+    mirroring.put(b"written", b"value").unwrap();
+    assert_eq!(primary.get(b"written").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(mirroring.get(b"written").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(mirroring.get(b"missing").unwrap(), None);
+    assert!(!mirroring.contains_key(b"missing").unwrap());
+
+    // Failures of the primary still do.
+    let mirror = Arc::new(MockCompiledContractCache::default());
+    let mirroring = MirroringCache::new(Arc::new(FailingCache), mirror.clone());
+    mirroring.put(b"written", b"value").unwrap_err();
+    assert_eq!(mirror.len(), 0);
+}
+
+#[test]
+fn test_mirroring_cache_fallback() {
+    let primary = Arc::new(MockCompiledContractCache::default());
+    let mirror = Arc::new(MockCompiledContractCache::default());
+    let mirroring = MirroringCache::new(primary.clone(), mirror.clone());
+
+    mirroring.put(b"written", b"value").unwrap();
+    assert_eq!(mirror.get(b"written").unwrap(), Some(b"value".to_vec()));
+
+    // A record only the mirror has is found, but not copied into the primary.
+    mirror.put(b"mirror only", b"value").unwrap();
+    assert!(mirroring.contains_key(b"mirror only").unwrap());
+    assert_eq!(mirroring.get(b"mirror only").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(primary.get(b"mirror only").unwrap(), None);
+
+    let mirroring = MirroringCache::new(primary, mirror).with_mirror_fallback(false);
+    assert!(!mirroring.contains_key(b"mirror only").unwrap());
+    assert_eq!(mirroring.get(b"mirror only").unwrap(), None);
+}
+
+#[test]
+fn test_vm_config_cache_component() {
+    let config = VMConfig::test();
+    let component = vm_config_cache_component(&config);
+    assert_eq!(component, config.codegen_non_crypto_hash());
+    let description =
+        describe_contract_cache_key(&unique_contract(48000), VMKind::Wasmer2, &config);
+    assert_eq!(component, description.vm_config_non_crypto_hash);
+
+    let mut changed = config;
+    changed.grow_mem_cost += 1;
+    assert_ne!(vm_config_cache_component(&changed), component);
+}
+
+#[test]
+fn test_purge_errors() {
+    let error = CompilationError::PrepareError(PrepareError::Deserialization);
+    let records = [
+        CacheRecord::code(vec![1, 2, 3], None).to_bytes(),
+        CacheRecord::code(vec![4, 5, 6], Some(CompressionAlgo::Zstd)).to_bytes(),
+        CacheRecord::CompileModuleError(error.clone()).to_bytes(),
+        CacheRecord::ErrorWithExpiry { error, expires_at_unix: u64::MAX }.to_bytes(),
+    ];
+    let keys: Vec<CryptoHash> =
+        (0..records.len()).map(|i| near_primitives::hash::hash(&[i as u8])).collect();
+    let populate = |cache: &MockCompiledContractCache| {
+        for (key, record) in keys.iter().zip(&records) {
+            cache.put(&key.0, record).unwrap();
+        }
+    };
+
+    let cache = MockCompiledContractCache::default();
+    populate(&cache);
+    assert_eq!(cache.purge_errors(), 2);
+    assert_eq!(cache.len(), 2);
+    assert!(cache.contains_key(&keys[0].0).unwrap());
+    assert!(cache.contains_key(&keys[1].0).unwrap());
+    assert_eq!(cache.purge_errors(), 0);
+
+    let cache = MockCompiledContractCache::default();
+    populate(&cache);
+    assert_eq!(purge_errors(&cache, &keys).unwrap(), 2);
+    assert_eq!(cache.get(&keys[0].0).unwrap().as_ref(), Some(&records[0]));
+    assert_eq!(cache.get(&keys[1].0).unwrap().as_ref(), Some(&records[1]));
+    assert!(!cache.contains_key(&keys[2].0).unwrap());
+    assert!(!cache.contains_key(&keys[3].0).unwrap());
+}
+
+#[test]
+fn test_compile_timeout() {
+    let semaphore = Arc::new(CompileSemaphore::new(1));
+    let timeout = |millis| std::time::Duration::from_millis(millis);
+    let (finished_tx, finished_rx) = std::sync::mpsc::channel();
+    let slow_compile = move || {
+        std::thread::sleep(timeout(500));
+        finished_tx.send(()).unwrap();
+    };
+    let res = compile_with_timeout(&semaphore, timeout(10), slow_compile);
+    assert_eq!(res, Err(ContractPrecompilatonError::Timeout { timeout_ms: 10 }));
+
+    // The abandoned compilation finishes in the background, and keeps its turn until then.
+    let res = compile_with_timeout(&semaphore, timeout(60_000), || 42);
+    assert_eq!(res, Ok(42));
+    assert_eq!(finished_rx.try_recv(), Ok(()));
+
+    // A panicking compilation is reported instead of taking the caller down with it.
+    let res = compile_with_timeout(&semaphore, timeout(60_000), || -> u32 {
+        panic!("compilation failed")
+    });
+    assert_eq!(res, Err(ContractPrecompilatonError::CompilationPanicked));
+    assert_eq!(compile_with_timeout(&semaphore, timeout(60_000), || 7), Ok(7));
+}
+
+#[test]
+fn test_compile_timeout_option() {
+    let config = VMConfig::test();
+    let code = ContractCode::new(near_test_contracts::rs_contract().to_vec(), None);
+    let key = get_contract_cache_key(&code, VMKind::Wasmer2, &config);
+    let cache = MockCompiledContractCache::default();
+    let precompile = |timeout| {
+        let cache_options = CacheOptions { compile_timeout: Some(timeout), ..Default::default() };
+        let options = PrecompileOptions { cache_options, ..Default::default() };
+        precompile_contract_vm_with_options(VMKind::Wasmer2, &code, &config, Some(&cache), &options)
+    };
+
+    let res = precompile(std::time::Duration::from_nanos(1));
+    assert_matches!(res, Err(ContractPrecompilatonError::Timeout { .. }));
+    assert!(!cache.contains_key(&key.0).unwrap());
+
+    // The timeout belongs to the options it was set in, so it can differ between caches.
+    assert_compiled(&precompile(std::time::Duration::from_secs(600)));
+}
+
+#[test]
+fn test_max_cache_artifact_bytes() {
+    let config = VMConfig::test();
+    let store = default_wasmer2_store();
+    let cache = MockCompiledContractCache::default();
+    let code = unique_contract(68000);
+    let key = get_contract_cache_key(&code, VMKind::Wasmer2, &config);
+
+    let compile = |max| {
+        let options = CacheOptions { max_cache_artifact_bytes: max, ..CacheOptions::default() };
+        compile_and_serialize_wasmer2_sized(
+            code.code(),
+            false,
+            &key,
+            &config,
+            &cache,
+            &options,
+            &store,
+        )
+    };
+    // Oversized artifacts are still compiled and usable, just not stored.
+    let (module, size) = compile(Some(16)).unwrap().unwrap();
+    assert!(size > 16);
+    assert!(module.exports().any(|export| export.name() == "main"));
+    assert_eq!(cache.get(&key.0).unwrap(), None);
+
+    let cache_options =
+        CacheOptions { max_cache_artifact_bytes: Some(16), ..CacheOptions::default() };
+    let options = PrecompileOptions { cache_options, ..Default::default() };
+    let res = precompile_contract_vm_with_options(
+        VMKind::Wasmer2,
+        &code,
+        &config,
+        Some(&cache),
+        &options,
+    );
+    assert_eq!(res, Ok(ContractPrecompilatonResult::SkippedTooLarge));
+    assert!(!ContractPrecompilatonResult::SkippedTooLarge.is_cache_effective());
+    assert_eq!(cache.get(&key.0).unwrap(), None);
+
+    let (_module, stored_size) = compile(Some(size)).unwrap().unwrap();
+    assert_eq!(cache.get(&key.0).unwrap().map(|record| record.len()), Some(stored_size));
+}
+
+#[test]
+fn test_compile_to_serialized_wasmer2() {
+    let config = VMConfig::test();
+    let store = default_wasmer2_store();
+    let code = unique_contract(50000);
+
+    let serialized =
+        compile_to_serialized_wasmer2(&code, &config, &CacheOptions::default(), &store).unwrap();
+    let module = unsafe { load_wasmer2_module_from_bytes(&serialized, &store) }.unwrap();
+    assert_eq!(
+        module.exports().map(|export| export.name().to_string()).collect::<Vec<_>>(),
+        ["main"]
+    );
+
+    let invalid_code = ContractCode::new(vec![50; 100], None);
+    let err =
+        compile_to_serialized_wasmer2(&invalid_code, &config, &CacheOptions::default(), &store)
+            .unwrap_err();
+    assert_eq!(
+        err,
+        VMError::FunctionCallError(FunctionCallError::CompilationError(
+            CompilationError::PrepareError(PrepareError::Deserialization)
+        ))
+    );
+}
+
+#[test]
+fn test_verify_cache_consistency() {
+    let config = VMConfig::test();
+    let store = default_wasmer2_store();
+    let cache = MockCompiledContractCache::default();
+    let code = unique_contract(51000);
+    let key = get_contract_cache_key(&code, VMKind::Wasmer2, &config);
+    compile_and_serialize_wasmer2(
+        code.code(),
+        &key,
+        &config,
+        &cache,
+        &CacheOptions::default(),
+        &store,
+    )
+    .unwrap()
+    .unwrap();
+    let genuine = cache.get(&key.0).unwrap().unwrap();
+    assert!(verify_cached_artifact(&code, &key, &config, &store, &genuine));
+
+    // Store the artifact of another contract under the key, like a cache key bug would.
+    let other_code = unique_contract(51001);
+    let other_key = get_contract_cache_key(&other_code, VMKind::Wasmer2, &config);
+    compile_and_serialize_wasmer2(
+        other_code.code(),
+        &other_key,
+        &config,
+        &cache,
+        &CacheOptions::default(),
+        &store,
+    )
+    .unwrap()
+    .unwrap();
+    let diverged = cache.get(&other_key.0).unwrap().unwrap();
+    assert!(!verify_cached_artifact(&code, &key, &config, &store, &diverged));
+
+    cache.put(&key.0, &diverged).unwrap();
+    let before = cache_stats().inconsistencies;
+    let options = CacheOptions { verify_cache_consistency: true, ..CacheOptions::default() };
+    let (_module, origin) = compile_module_cached_wasmer2_with_origin(
+        &code,
+        &config,
+        Some(&cache),
+        &options,
+        &store,
+        true,
+        None,
+    )
+    .unwrap()
+    .unwrap();
+    // The diverged artifact is replaced with a fresh compilation.
+    assert_eq!(origin, CacheOrigin::FreshCompile);
+    assert!(cache_stats().inconsistencies > before);
+    let repaired = cache.get(&key.0).unwrap().unwrap();
+    assert!(verify_cached_artifact(&code, &key, &config, &store, &repaired));
+
+    // Consistent artifacts are served from the cache.
+    let (_module, origin) = compile_module_cached_wasmer2_with_origin(
+        &code,
+        &config,
+        Some(&cache),
+        &options,
+        &store,
+        true,
+        None,
+    )
+    .unwrap()
+    .unwrap();
+    assert_eq!(origin, CacheOrigin::PersistentCache);
+}
+
+#[test]
+fn test_pinned_module() {
+    let registry = ModuleCacheRegistry::new(4);
+    let key = |seed: u8| CryptoHash([seed; 32]);
+    registry.pin(key(0));
+    assert!(registry.is_pinned(VMKind::Wasmer0, &key(0)));
+    assert!(registry.is_pinned(VMKind::Wasmer2, &key(0)));
+    registry.wasmer2.put(key(0), Err(CacheError::ReadError), 0);
+
+    // The pinned module survives filling the cache beyond its budget, without taking up any of it.
+    for seed in 1..10 {
+        registry.wasmer2.put(key(seed), Err(CacheError::ReadError), 0);
+        registry.enforce_budget(VMKind::Wasmer2);
+    }
+    assert!(registry.contains(VMKind::Wasmer2, &key(0)));
+    assert_eq!(registry.len(VMKind::Wasmer2), 4);
+    assert!(!registry.contains(VMKind::Wasmer2, &key(5)));
+
+    registry.unpin(&key(0));
+    assert!(!registry.is_pinned(VMKind::Wasmer2, &key(0)));
+    assert_eq!(registry.len(VMKind::Wasmer2), 4);
+    assert!(!registry.contains(VMKind::Wasmer2, &key(6)));
+}
+
+#[test]
+fn test_serialized_module_size_metric() {
+    let config = VMConfig::test();
+    let store = default_wasmer2_store();
+    let cache = MockCompiledContractCache::default();
+    let sizes = metrics::SERIALIZED_MODULE_SIZE.with_label_values(&["wasmer2"]);
+    let small = unique_contract(52000);
+    let large = ContractCode::new(near_test_contracts::rs_contract().to_vec(), None);
+
+    let (count, sum) = (sizes.get_sample_count(), sizes.get_sample_sum());
+    let mut total = 0;
+    for code in [&small, &large] {
+        let key = get_contract_cache_key(code, VMKind::Wasmer2, &config);
+        compile_and_serialize_wasmer2(
+            code.code(),
+            &key,
+            &config,
+            &cache,
+            &CacheOptions::default(),
+            &store,
+        )
+        .unwrap()
+        .unwrap();
+        total += cache.get(&key.0).unwrap().unwrap().len();
+    }
+    assert!(sizes.get_sample_count() >= count + 2);
+    assert!(sizes.get_sample_sum() >= sum + total as f64);
+}
+
+#[test]
+fn test_read_only_cache() {
+    let inner = Arc::new(MockCompiledContractCache::default());
+    inner.put(b"stored", b"value").unwrap();
+    let cache = ReadOnlyCache::new(inner.clone());
+
+    assert_eq!(cache.get(b"stored").unwrap(), Some(b"value".to_vec()));
+    assert!(cache.contains_key(b"stored").unwrap());
+    let err = cache.put(b"written", b"value").unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+    cache.put_if_absent(b"written", b"value").unwrap_err();
+    assert!(!cache.put_if_absent(b"stored", b"other value").unwrap());
+    cache.remove(b"stored").unwrap_err();
+    assert_eq!(inner.len(), 1);
+
+    // Compiling a contract which isn't cached fails to store it.
+    let config = VMConfig::test();
+    let store = default_wasmer2_store();
+    let code = unique_contract(53000);
+    let key = get_contract_cache_key(&code, VMKind::Wasmer2, &config);
+    let res = compile_and_serialize_wasmer2(
+        code.code(),
+        &key,
+        &config,
+        &cache,
+        &CacheOptions::default(),
+        &store,
+    );
+    assert_matches!(res, Err(CacheError::WriteError));
+
+    let cache = ReadOnlyCache::new(inner.clone()).with_ignored_writes(true);
+    cache.put(b"written", b"value").unwrap();
+    compile_and_serialize_wasmer2(
+        code.code(),
+        &key,
+        &config,
+        &cache,
+        &CacheOptions::default(),
+        &store,
+    )
+    .unwrap()
+    .unwrap();
+    assert_eq!(inner.len(), 1);
+}
+
+#[test]
+fn test_get_many() {
+    let cache = MockCompiledContractCache::default();
+    cache.put(b"first", b"1").unwrap();
+    cache.put(b"second", b"2").unwrap();
+    let keys = [b"second".to_vec(), b"missing".to_vec(), b"first".to_vec()];
+    assert_eq!(cache.get_many(&keys).unwrap(), [Some(b"2".to_vec()), None, Some(b"1".to_vec())]);
+
+    // The default implementation, which looks the keys up one by one, agrees.
+    let layered =
+        LayeredCache::new(Arc::new(cache), Arc::new(MockCompiledContractCache::default()));
+    assert_eq!(layered.get_many(&keys).unwrap(), [Some(b"2".to_vec()), None, Some(b"1".to_vec())]);
+    assert!(FailingCache.get_many(&keys).is_err());
+}
+
+#[test]
+fn test_empty_artifact_rejected() {
+    let key = CryptoHash([55; 32]);
+    let serialize = |artifact: Vec<u8>| -> Result<Vec<u8>, std::io::Error> { Ok(artifact) };
+    assert_eq!(checked_artifact(serialize(vec![1, 2, 3]), &key), Ok(vec![1, 2, 3]));
+    assert_eq!(
+        checked_artifact(serialize(Vec::new()), &key),
+        Err(CacheError::SerializationError { hash: key.0 })
+    );
+    let failed: Result<Vec<u8>, std::io::Error> = Err(std::io::ErrorKind::Other.into());
+    assert_eq!(checked_artifact(failed, &key), Err(CacheError::SerializationError { hash: key.0 }));
+}
+
+#[test]
+fn test_available_vm_kinds() {
+    let available = available_vm_kinds();
+    let has = |vm_kind| available.iter().any(|(it, _name, _vm_hash)| *it == vm_kind);
+    assert_eq!(has(VMKind::Wasmer0), cfg!(feature = "wasmer0_vm"));
+    assert_eq!(has(VMKind::Wasmer2), cfg!(feature = "wasmer2_vm"));
+    assert_eq!(has(VMKind::Wasmtime), cfg!(feature = "wasmtime_vm"));
+    for (vm_kind, _name, vm_hash) in available {
+        assert_eq!(vm_hash, current_vm_hash(vm_kind));
+    }
+}
+
+#[test]
+fn test_upgrade_records_on_read() {
+    let config = VMConfig::test();
+    let store = default_wasmer2_store();
+    let code = unique_contract(56000);
+    let key = get_contract_cache_key(&code, VMKind::Wasmer2, &config);
+    let cache = MockCompiledContractCache::default();
+    compile_and_serialize_wasmer2(
+        code.code(),
+        &key,
+        &config,
+        &cache,
+        &CacheOptions::default(),
+        &store,
+    )
+    .unwrap()
+    .unwrap();
+    let artifact = extract_artifact(&cache, &key).unwrap().unwrap();
+
+    // A record written before records were versioned.
+    let legacy = CacheRecord::Code(artifact.clone()).try_to_vec().unwrap();
+    cache.put(&key.0, &legacy).unwrap();
+    assert_eq!(record_age(&cache, &key), None);
+
+    let options = CacheOptions { upgrade_records_on_read: true, ..CacheOptions::default() };
+    compile_module_cached_wasmer2(&code, &config, Some(&cache), &options, &store, true, None)
+        .unwrap()
+        .unwrap();
+    assert_ne!(cache.get(&key.0).unwrap().unwrap(), legacy);
+    assert!(record_age(&cache, &key).is_some());
+    assert_eq!(extract_artifact(&cache, &key), Ok(Some(artifact)));
+}
+
+#[test]
+fn test_in_flight_compiles_key_once() {
+    let in_flight = Arc::new(InFlight::<u32>::default());
+    let compilations = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let key = CryptoHash::hash_bytes(b"in flight");
+    let (started_tx, started_rx) = std::sync::mpsc::channel();
+    let leader = std::thread::spawn({
+        let in_flight = Arc::clone(&in_flight);
+        let compilations = Arc::clone(&compilations);
+        move || {
+            in_flight.run(key, || {
+                compilations.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                started_tx.send(()).unwrap();
+                std::thread::sleep(std::time::Duration::from_millis(200));
+                Ok(Ok(42))
+            })
+        }
+    });
+    started_rx.recv().unwrap();
+    let res = in_flight.run(key, || {
+        compilations.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        Ok(Ok(0))
+    });
+    assert_eq!(res, Ok(Ok(42)));
+    assert_eq!(leader.join().unwrap(), Ok(Ok(42)));
+    assert_eq!(compilations.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+    // Once finished, the key is compiled anew.
+    assert_eq!(in_flight.run(key, || Ok(Ok(7))), Ok(Ok(7)));
+}
+
+#[test]
+fn test_in_flight_compiles_keys_in_parallel() {
+    let in_flight = Arc::new(InFlight::<u32>::default());
+    let (a_tx, a_rx) = std::sync::mpsc::channel();
+    let (b_tx, b_rx) = std::sync::mpsc::channel::<()>();
+    let timeout = std::time::Duration::from_secs(10);
+    let other = std::thread::spawn({
+        let in_flight = Arc::clone(&in_flight);
+        move || {
+            in_flight.run(CryptoHash::hash_bytes(b"a"), || {
+                a_tx.send(()).unwrap();
+                b_rx.recv_timeout(timeout).unwrap();
+                Ok(Ok(1))
+            })
+        }
+    });
+    // Each compilation waits for the other one to start, which only finishes if they overlap.
+    let res = in_flight.run(CryptoHash::hash_bytes(b"b"), || {
+        a_rx.recv_timeout(timeout).unwrap();
+        b_tx.send(()).unwrap();
+        Ok(Ok(2))
+    });
+    assert_eq!(res, Ok(Ok(2)));
+    assert_eq!(other.join().unwrap(), Ok(Ok(1)));
+}
+
+#[test]
+fn test_compile_semaphore() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let semaphore = Arc::new(CompileSemaphore::new(2));
+    let running = Arc::new(AtomicUsize::new(0));
+    let max_running = Arc::new(AtomicUsize::new(0));
+    let compiles: Vec<_> = (68002..68008)
+        .map(|seed| {
+            let semaphore = Arc::clone(&semaphore);
+            let running = Arc::clone(&running);
+            let max_running = Arc::clone(&max_running);
+            std::thread::spawn(move || {
+                let config = VMConfig::test();
+                let store = default_wasmer2_store();
+                let code = unique_contract(seed);
+                let _permit = semaphore.acquire();
+                let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max_running.fetch_max(now_running, Ordering::SeqCst);
+                let res =
+                    compile_to_serialized_wasmer2(&code, &config, &CacheOptions::default(), &store);
+                std::thread::sleep(std::time::Duration::from_millis(50));
+                running.fetch_sub(1, Ordering::SeqCst);
+                res
+            })
+        })
+        .collect();
+    for compile in compiles {
+        assert!(compile.join().unwrap().is_ok());
+    }
+    assert_eq!(max_running.load(Ordering::SeqCst), 2);
+
+    // Raising the limit lets waiting compilations through.
+    let _first = semaphore.acquire();
+    let _second = semaphore.acquire();
+    let (tx, rx) = std::sync::mpsc::channel();
+    let waiter = std::thread::spawn({
+        let semaphore = Arc::clone(&semaphore);
+        move || {
+            let _permit = semaphore.acquire();
+            tx.send(()).unwrap();
+        }
+    });
+    assert!(rx.recv_timeout(std::time::Duration::from_millis(50)).is_err());
+    semaphore.set_limit(3);
+    rx.recv_timeout(std::time::Duration::from_secs(10)).unwrap();
+    waiter.join().unwrap();
+}
+
+#[test]
+fn test_compile_module_cached_wasmer2_with_origin() {
+    let config = VMConfig::test();
+    let store = default_wasmer2_store();
+    let code = unique_contract(57000);
+    let cache = MockCompiledContractCache::default();
+    let origin = |bypass_memcache| {
+        let res = compile_module_cached_wasmer2_with_origin(
+            &code,
+            &config,
+            Some(&cache),
+            &CacheOptions::default(),
+            &store,
+            bypass_memcache,
+            None,
+        );
+        res.unwrap().unwrap().1
+    };
+    assert_eq!(origin(true), CacheOrigin::FreshCompile);
+    assert_eq!(origin(false), CacheOrigin::PersistentCache);
+    assert_eq!(origin(false), CacheOrigin::MemCache);
+    assert_eq!(origin(true), CacheOrigin::PersistentCache);
+}
+
+#[test]
+fn test_read_error_policy() {
+    let config = VMConfig::test();
+    let store = default_wasmer2_store();
+    let code = unique_contract(58000);
+
+    let res = compile_module_cached_wasmer2(
+        &code,
+        &config,
+        Some(&FailingCache),
+        &CacheOptions::default(),
+        &store,
+        true,
+        None,
+    );
+    assert_matches!(res, Err(CacheError::ReadError));
+
+    // `FailingCache` also fails writes, so the module being returned shows it wasn't cached.
+    let options = CacheOptions {
+        on_read_error: ReadErrorPolicy::RecompileUncached,
+        ..CacheOptions::default()
+    };
+    let res = compile_module_cached_wasmer2(
+        &code,
+        &config,
+        Some(&FailingCache),
+        &options,
+        &store,
+        true,
+        None,
+    );
+    res.unwrap().unwrap();
+}
+
+#[test]
+fn test_cache_epoch() {
+    let config = VMConfig::test();
+    let code = unique_contract(59000);
+    let description = describe_contract_cache_key(&code, VMKind::Wasmer2, &config);
+    assert_eq!(description.epoch, 0);
+    let key = |epoch| {
+        let description = ContractCacheKeyDescription { epoch, ..description.clone() };
+        near_primitives::hash::hash(&contract_cache_key_bytes(&description))
+    };
+    assert_eq!(key(0), get_contract_cache_key(&code, VMKind::Wasmer2, &config));
+    assert_ne!(key(1), key(0));
+    assert_ne!(key(2), key(1));
+
+    let options = CacheOptions { epoch: 1, ..CacheOptions::default() };
+    assert_eq!(
+        get_contract_cache_key_with_options(&code, VMKind::Wasmer2, &config, &options),
+        key(1)
+    );
+}
+
+#[test]
+fn test_cache_options_key_and_error_ttl() {
+    let config = VMConfig::test();
+    let store = default_wasmer2_store();
+    let cache = MockCompiledContractCache::default();
+    let code = ContractCode::new(vec![59; 100], None);
+    let options = CacheOptions {
+        namespace: "shadow".to_string(),
+        error_record_ttl: Some(std::time::Duration::from_secs(60)),
+        ..CacheOptions::default()
+    };
+    let res =
+        compile_module_cached_wasmer2(&code, &config, Some(&cache), &options, &store, true, None);
+    assert!(res.unwrap().is_err());
+
+    // The error is cached in the namespace of the options, and expires.
+    let key = get_contract_cache_key_with_options(&code, VMKind::Wasmer2, &config, &options);
+    assert_eq!(key, get_namespaced_contract_cache_key(&code, VMKind::Wasmer2, &config, "shadow"));
+    let record = CacheRecord::from_bytes(&cache.get(&key.0).unwrap().unwrap()).unwrap();
+    assert_matches!(record, CacheRecord::ErrorWithExpiry { .. });
+    let default_key = get_contract_cache_key(&code, VMKind::Wasmer2, &config);
+    assert_eq!(cache.get(&default_key.0).unwrap(), None);
+}
+
+#[test]
+fn test_vm_hash_mismatch() {
+    let config = VMConfig::test();
+    let store = default_wasmer2_store();
+    let code = unique_contract(60000);
+    let key = get_contract_cache_key(&code, VMKind::Wasmer2, &config);
+    let cache = MockCompiledContractCache::default();
+    compile_and_serialize_wasmer2(
+        code.code(),
+        &key,
+        &config,
+        &cache,
+        &CacheOptions::default(),
+        &store,
+    )
+    .unwrap()
+    .unwrap();
+    let artifact = extract_artifact(&cache, &key).unwrap().unwrap();
+
+    let expected = current_vm_hash(VMKind::Wasmer2);
+    let found = expected.wrapping_add(1);
+    let poisoned =
+        CacheRecord::CodeForVm { vm_hash: found, compression: None, data: artifact }.to_bytes();
+    assert_eq!(
+        deserialize_wasmer2(&poisoned, &store).err(),
+        Some(CacheError::VmHashMismatch { expected, found })
+    );
+
+    // The record is replaced with one for the current VM.
+    cache.put(&key.0, &poisoned).unwrap();
+    let res = compile_module_cached_wasmer2(
+        &code,
+        &config,
+        Some(&cache),
+        &CacheOptions::default(),
+        &store,
+        true,
+        None,
+    );
+    res.unwrap().unwrap();
+    let record = CacheRecord::from_bytes(&cache.get(&key.0).unwrap().unwrap()).unwrap();
+    assert_matches!(record.into_code_for(VMKind::Wasmer2), Ok(Ok(_)));
+}
+
+#[test]
+fn test_circuit_breaker_cache() {
+    struct CountingFailingCache(std::sync::atomic::AtomicUsize);
+
+    impl CompiledContractCache for CountingFailingCache {
+        fn put(&self, key: &[u8], value: &[u8]) -> Result<(), std::io::Error> {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            FailingCache.put(key, value)
+        }
+
+        fn get(&self, _key: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error> {
+            Ok(None)
+        }
+    }
+
+    let inner = Arc::new(CountingFailingCache(Default::default()));
+    let puts = || inner.0.load(std::sync::atomic::Ordering::SeqCst);
+    let cooldown = std::time::Duration::from_millis(200);
+    let cache =
+        CircuitBreakerCache::new(inner.clone(), 3, std::time::Duration::from_secs(60), cooldown);
+    let trips = metrics::CACHE_CIRCUIT_BREAKER_TRIPS.get();
+
+    for _ in 0..3 {
+        assert!(cache.put(b"key", b"value").is_err());
+    }
+    assert_eq!(puts(), 3);
+    assert!(cache.is_open());
+    assert!(metrics::CACHE_CIRCUIT_BREAKER_TRIPS.get() > trips);
+
+    // Writes fail without reaching the broken cache.
+    assert!(cache.put(b"key", b"value").is_err());
+    assert!(cache.put_if_absent(b"key", b"value").is_err());
+    assert_eq!(puts(), 3);
+    let config = VMConfig::test();
+    let store = default_wasmer2_store();
+    let code = unique_contract(62000);
+    let res = compile_module_cached_wasmer2(
+        &code,
+        &config,
+        Some(&cache),
+        &CacheOptions::default(),
+        &store,
+        true,
+        None,
+    );
+    assert_eq!(res.err(), Some(CacheError::WriteError));
+    assert_eq!(puts(), 3);
+
+    std::thread::sleep(cooldown);
+    assert!(!cache.is_open());
+    assert!(cache.put(b"key", b"value").is_err());
+    assert_eq!(puts(), 4);
+}
+
+#[test]
+fn test_precompile_with_profile() {
+    let config = VMConfig::test();
+    let code = ContractCode::new(near_test_contracts::rs_contract().to_vec(), None);
+    let key = get_contract_cache_key(&code, VMKind::Wasmer2, &config);
+    let cache = MockCompiledContractCache::default();
+    let profile = std::cell::RefCell::new(None);
+    let on_profile = |it: &CompileProfile| *profile.borrow_mut() = Some(it.clone());
+    let options = PrecompileOptions { on_profile: Some(&on_profile), ..Default::default() };
+    let result = precompile_contract_vm_with_options(
+        VMKind::Wasmer2,
+        &code,
+        &config,
+        Some(&cache),
+        &options,
+    );
+    assert_compiled(&result);
+    let compiled = profile.take().unwrap();
+    assert_eq!(compiled.input_wasm_bytes, code.code().len());
+    assert!(compiled.prepared_bytes > 0);
+    assert_eq!(compiled.serialized_bytes, cache.get(&key.0).unwrap().unwrap().len());
+    assert!(compiled.compile_duration > std::time::Duration::ZERO);
+
+    // Nothing is compiled on a cache hit.
+    let result = precompile_contract_vm_with_options(
+        VMKind::Wasmer2,
+        &code,
+        &config,
+        Some(&cache),
+        &options,
+    );
+    assert_eq!(result, Ok(ContractPrecompilatonResult::ContractAlreadyInCache));
+    assert_eq!(profile.take(), None);
+
+    // Records which are too large to be cached still have their size reported.
+    let cache = MockCompiledContractCache::default();
+    let cache_options =
+        CacheOptions { max_cache_artifact_bytes: Some(16), ..CacheOptions::default() };
+    let options = PrecompileOptions { cache_options, ..options };
+    let result = precompile_contract_vm_with_options(
+        VMKind::Wasmer2,
+        &code,
+        &config,
+        Some(&cache),
+        &options,
+    );
+    assert_eq!(result, Ok(ContractPrecompilatonResult::SkippedTooLarge));
+    assert_eq!(profile.take().unwrap().serialized_bytes, compiled.serialized_bytes);
+}
+
+#[test]
+fn test_memcache_snapshot() {
+    let registry = ModuleCacheRegistry::new(4);
+    let key = |seed: u8| CryptoHash([seed; 32]);
+    registry.wasmer0.put(key(0), Err(CacheError::ReadError), 10);
+    registry.wasmer2.put(key(1), Err(CacheError::WriteError), 20);
+    let snapshot = registry.snapshot();
+    assert_eq!(snapshot.keys(), vec![key(0), key(1)]);
+
+    registry.wasmer0.clear();
+    registry.wasmer2.clear();
+    registry.wasmer2.put(key(2), Err(CacheError::ReadError), 0);
+    registry.restore(&snapshot);
+    assert!(registry.contains(VMKind::Wasmer0, &key(0)));
+    assert_matches!(registry.wasmer2.get(&key(1)), Some(Err(CacheError::WriteError)));
+    assert!(!registry.contains(VMKind::Wasmer2, &key(2)));
+    assert_eq!(registry.total_weight(), 30);
+}
+
+#[test]
+fn test_contract_cache_key_for_target() {
+    let config = VMConfig::test();
+    let code = unique_contract(63000);
+    let triple: wasmer::Triple = "x86_64-unknown-linux-gnu".parse().unwrap();
+    let baseline = wasmer::Target::new(triple.clone(), wasmer::CpuFeature::set());
+    let mut features = wasmer::CpuFeature::set();
+    features.insert(wasmer::CpuFeature::AVX512DQ);
+    let avx512 = wasmer::Target::new(triple, features);
+
+    let key = get_contract_cache_key_for_target(&code, &config, &baseline);
+    assert_ne!(key, get_contract_cache_key_for_target(&code, &config, &avx512));
+    assert_ne!(key, get_contract_cache_key(&code, VMKind::Wasmer2, &config));
+
+    let host = wasmer::Target::default();
+    let cache = MockCompiledContractCache::default();
+    let options = PrecompileOptions { target: Some(host.clone()), ..Default::default() };
+    let result = precompile_contract_vm_with_options(
+        VMKind::Wasmer2,
+        &code,
+        &config,
+        Some(&cache),
+        &options,
+    );
+    assert_compiled(&result);
+    let key = get_contract_cache_key_for_target(&code, &config, &host);
+    assert!(cache.get(&key.0).unwrap().is_some());
+
+    // Artifacts compiled for the host's target are what the node runs contracts with.
+    assert_eq!(key, get_contract_cache_key(&code, VMKind::Wasmer2, &config));
+    let compilations = std::sync::atomic::AtomicUsize::new(0);
+    let on_compile = |_code_hash: CryptoHash, _elapsed: std::time::Duration| {
+        compilations.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    };
+    compile_module_cached_wasmer2(
+        &code,
+        &config,
+        Some(&cache),
+        &CacheOptions::default(),
+        &default_wasmer2_store(),
+        true,
+        Some(&on_compile),
+    )
+    .unwrap()
+    .unwrap();
+    assert_eq!(compilations.into_inner(), 0);
+}
+
+#[test]
+fn test_recording_cache() {
+    let cache = RecordingCache::new(Arc::new(MockCompiledContractCache::default()), 3);
+    assert_eq!(cache.get(b"key").unwrap(), None);
+    cache.put(b"key", b"value").unwrap();
+    assert_eq!(cache.get(b"key").unwrap(), Some(b"value".to_vec()));
+    let summary = |events: Vec<CacheEvent>| {
+        events.into_iter().map(|event| (event.key, event.kind, event.value_len)).collect::<Vec<_>>()
+    };
+    assert_eq!(
+        summary(cache.events()),
+        vec![
+            (b"key".to_vec(), CacheEventKind::Get { hit: false }, 0),
+            (b"key".to_vec(), CacheEventKind::Put, 5),
+            (b"key".to_vec(), CacheEventKind::Get { hit: true }, 5),
+        ]
+    );
+
+    // Only the last `capacity` events are kept.
+    cache.get(b"other").unwrap();
+    let events = summary(cache.events());
+    assert_eq!(events.len(), 3);
+    assert_eq!(events[2], (b"other".to_vec(), CacheEventKind::Get { hit: false }, 0));
+}
+
+#[test]
+fn test_size_capped_cache() {
+    let inner = Arc::new(MockCompiledContractCache::default());
+    let cache = SizeCappedCache::new(inner.clone(), 4);
+    let err = cache.put(b"big", b"value").unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    assert_eq!(
+        cache.put_if_absent(b"big", b"value").unwrap_err().kind(),
+        std::io::ErrorKind::InvalidInput
+    );
+    assert_eq!(inner.get(b"big").unwrap(), None);
+
+    cache.put(b"small", b"four").unwrap();
+    assert_eq!(cache.get(b"small").unwrap(), Some(b"four".to_vec()));
+    assert_eq!(inner.get(b"small").unwrap(), Some(b"four".to_vec()));
+}
+
+#[test]
+fn test_load_and_time() {
+    let config = VMConfig::test();
+    let code = unique_contract(64000);
+    let cache = MockCompiledContractCache::default();
+
+    let (module, cold) =
+        load_and_time(&code, &config, ProtocolVersion::MAX, Some(&cache), &CacheOptions::default())
+            .unwrap();
+    assert!(matches!(module, VMModule::Wasmer2(_)));
+    let compile = cold.compile.unwrap();
+    assert!(compile > std::time::Duration::ZERO && compile <= cold.total);
+    assert_eq!(cold.deserialize, None);
+
+    let (_module, warm) =
+        load_and_time(&code, &config, ProtocolVersion::MAX, Some(&cache), &CacheOptions::default())
+            .unwrap();
+    assert_eq!(warm.compile, None);
+    let deserialize = warm.deserialize.unwrap();
+    assert!(deserialize > std::time::Duration::ZERO && deserialize <= warm.total);
+}
+
+#[test]
+fn test_memcache_does_not_keep_errors() {
+    let config = VMConfig::test();
+    let store = default_wasmer2_store();
+    let code = unique_contract(65000);
+    let key = get_contract_cache_key(&code, VMKind::Wasmer2, &config);
+
+    let res = compile_module_cached_wasmer2(
+        &code,
+        &config,
+        Some(&FailingCache),
+        &CacheOptions::default(),
+        &store,
+        false,
+        None,
+    );
+    assert_matches!(res, Err(CacheError::ReadError));
+    assert!(!registry().contains(VMKind::Wasmer2, &key));
+
+    // Once the cache recovers, the contract is compiled rather than failing again.
+    let cache = MockCompiledContractCache::default();
+    let res = compile_module_cached_wasmer2(
+        &code,
+        &config,
+        Some(&cache),
+        &CacheOptions::default(),
+        &store,
+        false,
+        None,
+    );
+    res.unwrap().unwrap();
+    assert!(registry().contains(VMKind::Wasmer2, &key));
+}
+
+#[test]
+fn test_get_contract_cache_key_from_hash() {
+    let config = VMConfig::test();
+    let code = unique_contract(66000);
+    for vm_kind in [VMKind::Wasmer0, VMKind::Wasmer2, VMKind::Wasmtime] {
+        assert_eq!(
+            get_contract_cache_key_from_hash(*code.hash(), vm_kind, &config),
+            get_contract_cache_key(&code, vm_kind, &config)
+        );
+    }
+}
+
+#[test]
+fn test_precompile_corpus() {
+    let config = VMConfig::test();
+    let cache = MockCompiledContractCache::default();
+    let mut codes: Vec<_> = (67000..67003).map(unique_contract).collect();
+    codes.push(ContractCode::new(vec![44; 100], None));
+
+    let report = precompile_corpus(&codes, &config, ProtocolVersion::MAX, Some(&cache));
+    assert_eq!((report.compiled, report.already_cached, report.failed), (3, 0, 1));
+    let written: usize = codes[..3]
+        .iter()
+        .map(|code| {
+            let key = get_contract_cache_key(code, VMKind::Wasmer2, &config);
+            cache.get(&key.0).unwrap().unwrap().len()
+        })
+        .sum();
+    assert_eq!(report.serialized_bytes, written);
+    let (p50, p99) = (report.p50_compile.unwrap(), report.p99_compile.unwrap());
+    assert!(p50 <= p99 && p99 <= report.total_time);
+
+    let report = precompile_corpus(&codes[..3], &config, ProtocolVersion::MAX, Some(&cache));
+    assert_eq!((report.compiled, report.already_cached, report.failed), (0, 3, 0));
+    assert_eq!(report.serialized_bytes, 0);
+    assert_eq!(report.p50_compile, None);
+}
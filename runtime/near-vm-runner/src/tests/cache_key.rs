@@ -0,0 +1,36 @@
+use near_primitives::contract::ContractCode;
+use near_primitives::hash::hash;
+use near_vm_logic::VMConfig;
+
+use crate::cache::{
+    contract_cache_key_bytes, current_vm_hash, get_contract_cache_key,
+    get_contract_cache_key_with_vm_hash, vm_config_cache_component, ContractCacheKeyDescription,
+};
+use crate::vm_kind::VMKind;
+
+#[test]
+fn test_contract_cache_key_with_vm_hash() {
+    let config = VMConfig::test();
+    let code = ContractCode::new(vec![54, 0, 0, 0], None);
+    for vm_kind in [VMKind::Wasmer0, VMKind::Wasmer2, VMKind::Wasmtime] {
+        let vm_hash = 54000;
+        let description = ContractCacheKeyDescription {
+            code_hash: *code.hash(),
+            vm_config_non_crypto_hash: vm_config_cache_component(&config),
+            vm_kind,
+            vm_hash,
+            namespace: String::new(),
+            protocol_version: None,
+            epoch: 0,
+        };
+        let key = get_contract_cache_key_with_vm_hash(&code, vm_kind, &config, vm_hash);
+        assert_eq!(key, hash(&contract_cache_key_bytes(&description)));
+        assert_ne!(get_contract_cache_key_with_vm_hash(&code, vm_kind, &config, vm_hash + 1), key);
+
+        // The full path agrees, given the hash of the VM compiled into this build.
+        assert_eq!(
+            get_contract_cache_key_with_vm_hash(&code, vm_kind, &config, current_vm_hash(vm_kind)),
+            get_contract_cache_key(&code, vm_kind, &config)
+        );
+    }
+}
@@ -3,6 +3,7 @@ use std::sync::{Arc, Mutex};
 use std::thread::sleep;
 use std::time::Duration;
 
+use assert_matches::assert_matches;
 use near_primitives::contract::ContractCode;
 use near_primitives::runtime::fees::RuntimeFeesConfig;
 use near_primitives::types::CompiledContractCache;
@@ -198,19 +199,27 @@ fn test_precompile_vm(vm_kind: VMKind) {
     let code1 = ContractCode::new(near_test_contracts::rs_contract().to_vec(), None);
     let code2 = ContractCode::new(near_test_contracts::ts_contract().to_vec(), None);
 
-    let result = precompile_contract_vm(vm_kind, &code1, &vm_config, cache).unwrap();
-    assert_eq!(result, Result::Ok(ContractPrecompilatonResult::ContractCompiled));
+    let result = precompile_contract_vm(vm_kind, &code1, &vm_config, cache, false);
+    assert_matches!(
+        result,
+        Ok(ContractPrecompilatonResult::ContractCompiled
+            | ContractPrecompilatonResult::ContractCompiledWithSize(_))
+    );
     assert_eq!(mock_cache.len(), 1);
-    let result = precompile_contract_vm(vm_kind, &code1, &vm_config, cache).unwrap();
+    let result = precompile_contract_vm(vm_kind, &code1, &vm_config, cache, false);
     assert_eq!(result, Result::Ok(ContractPrecompilatonResult::ContractAlreadyInCache));
     assert_eq!(mock_cache.len(), 1);
-    let result = precompile_contract_vm(vm_kind, &code2, &vm_config, None).unwrap();
+    let result = precompile_contract_vm(vm_kind, &code2, &vm_config, None, false);
     assert_eq!(result, Result::Ok(ContractPrecompilatonResult::CacheNotAvailable));
     assert_eq!(mock_cache.len(), 1);
-    let result = precompile_contract_vm(vm_kind, &code2, &vm_config, cache).unwrap();
-    assert_eq!(result, Result::Ok(ContractPrecompilatonResult::ContractCompiled));
+    let result = precompile_contract_vm(vm_kind, &code2, &vm_config, cache, false);
+    assert_matches!(
+        result,
+        Ok(ContractPrecompilatonResult::ContractCompiled
+            | ContractPrecompilatonResult::ContractCompiledWithSize(_))
+    );
     assert_eq!(mock_cache.len(), 2);
-    let result = precompile_contract_vm(vm_kind, &code2, &vm_config, cache).unwrap();
+    let result = precompile_contract_vm(vm_kind, &code2, &vm_config, cache, false);
     assert_eq!(result, Result::Ok(ContractPrecompilatonResult::ContractAlreadyInCache));
     assert_eq!(mock_cache.len(), 2);
 }
@@ -221,4 +230,6 @@ pub fn test_precompile() {
     test_precompile_vm(VMKind::Wasmer0);
     #[cfg(feature = "wasmer2_vm")]
     test_precompile_vm(VMKind::Wasmer2);
+    #[cfg(feature = "wasmtime_vm")]
+    test_precompile_vm(VMKind::Wasmtime);
 }
@@ -3,9 +3,11 @@ use near_primitives::checked_feature;
 use near_vm_logic::ProtocolVersion;
 use std::hash::Hash;
 
-#[derive(Clone, Copy, Debug, Hash, BorshSerialize)]
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, BorshSerialize)]
 // Note, that VMKind is part of serialization protocol, so we cannor remove entries
 // from this list if particular VM reached publically visible networks.
+// New variants must be appended at the end: borsh encodes the variant index, so reordering would
+// change the cache keys of every existing entry (see `test_vm_kind_cache_key_bytes_golden`).
 //
 // Additionally, this is public only for the purposes of the standalone VM runner. This API should
 // otherwise be considered a private implementation detail of the `near-vm-runner` crate.
@@ -1,4 +1,4 @@
-use crate::cache::into_vm_result;
+use crate::cache::{into_vm_result, CacheOptions};
 use crate::errors::IntoVMError;
 use crate::prepare::WASM_FEATURES;
 use crate::{cache, imports};
@@ -16,7 +16,7 @@ use std::hash::{Hash, Hasher};
 use std::mem::size_of;
 use wasmer::{
     Bytes, ImportObject, Instance, InstantiationError, Memory, MemoryType, Module, Pages,
-    RuntimeError, Store,
+    RuntimeError, Store, Target,
 };
 
 use near_stable_hasher::StableHasher;
@@ -297,18 +297,47 @@ const WASMER2_CONFIG: Wasmer2Config = Wasmer2Config {
     compiler: WasmerCompiler::Singlepass,
 };
 
+/// Covers the host target, as the artifacts compiled by [`default_wasmer2_store`] only load on
+/// hardware matching it. This way, they share their keys with the artifacts compiled for the same
+/// target elsewhere, see [`wasmer2_target_vm_hash`].
 pub(crate) fn wasmer2_vm_hash() -> u64 {
-    WASMER2_CONFIG.config_hash()
+    static HOST_VM_HASH: once_cell::sync::Lazy<u64> =
+        once_cell::sync::Lazy::new(|| wasmer2_target_vm_hash(&Target::default()));
+    *HOST_VM_HASH
+}
+
+/// Like [`wasmer2_vm_hash`], but for artifacts compiled for `target` rather than for the host.
+pub(crate) fn wasmer2_target_vm_hash(target: &Target) -> u64 {
+    let mut s = StableHasher::new();
+    WASMER2_CONFIG.config_hash().hash(&mut s);
+    target.triple().to_string().hash(&mut s);
+    target.cpu_features().as_u64().hash(&mut s);
+    s.finish()
+}
+
+/// The [`wasmer2_target_vm_hash`] of the target `store` compiles for. Cached artifacts are tagged
+/// with it, as they only load with a store compiling for the same target.
+pub(crate) fn wasmer2_store_vm_hash(store: &Store) -> u64 {
+    wasmer2_target_vm_hash(store.engine().target())
 }
 
 pub(crate) fn default_wasmer2_store() -> Store {
+    wasmer2_store_for_target(None)
+}
+
+/// Returns a store compiling for `target`, or for the host if it is `None`. Modules compiled for
+/// another target can be serialized, but only loaded on hardware matching it.
+pub(crate) fn wasmer2_store_for_target(target: Option<Target>) -> Store {
     // We only support singlepass compiler at the moment.
     assert_eq!(WASMER2_CONFIG.compiler, WasmerCompiler::Singlepass);
     let compiler = Singlepass::new();
     // We only support universal engine at the moment.
     assert_eq!(WASMER2_CONFIG.engine, WasmerEngine::Universal);
-    let engine = wasmer::Universal::new(compiler).features(WASMER_FEATURES).engine();
-    Store::new(&engine)
+    let mut universal = wasmer::Universal::new(compiler).features(WASMER_FEATURES);
+    if let Some(target) = target {
+        universal = universal.target(target);
+    }
+    Store::new(&universal.engine())
 }
 
 pub(crate) fn run_wasmer2_module<'a>(
@@ -389,8 +418,15 @@ impl crate::runner::VM for Wasmer2VM {
         }
 
         let store = default_wasmer2_store();
-        let module =
-            cache::wasmer2_cache::compile_module_cached_wasmer2(code, wasm_config, cache, &store);
+        let module = cache::wasmer2_cache::compile_module_cached_wasmer2(
+            code,
+            wasm_config,
+            cache,
+            &CacheOptions::default(),
+            &store,
+            false,
+            None,
+        );
         let module = match into_vm_result(module) {
             Ok(it) => it,
             Err(err) => return (None, Some(err)),
@@ -449,6 +485,7 @@ impl crate::runner::VM for Wasmer2VM {
             code_hash,
             wasm_config,
             cache,
+            &CacheOptions::default(),
             &store,
         );
         into_vm_result(result).err()
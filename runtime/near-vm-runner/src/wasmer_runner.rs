@@ -1,4 +1,4 @@
-use crate::cache::into_vm_result;
+use crate::cache::{into_vm_result, CacheOptions};
 use crate::errors::IntoVMError;
 use crate::memory::WasmerMemory;
 use crate::prepare::WASM_FEATURES;
@@ -313,7 +313,13 @@ impl crate::runner::VM for Wasmer0VM {
         }
 
         // TODO: consider using get_module() here, once we'll go via deployment path.
-        let module = cache::wasmer0_cache::compile_module_cached_wasmer0(code, wasm_config, cache);
+        let module = cache::wasmer0_cache::compile_module_cached_wasmer0(
+            code,
+            wasm_config,
+            cache,
+            &CacheOptions::default(),
+            false,
+        );
         let module = match into_vm_result(module) {
             Ok(x) => x,
             Err(err) => return (None, Some(err)),
@@ -369,6 +375,7 @@ impl crate::runner::VM for Wasmer0VM {
             wasm_config,
             code_hash,
             cache,
+            &CacheOptions::default(),
         );
         into_vm_result(result).err()
     }
@@ -1,18 +1,19 @@
+use crate::cache::{into_vm_result, CacheOptions};
 use crate::errors::IntoVMError;
 use crate::prepare::WASM_FEATURES;
-use crate::{imports, prepare};
+use crate::{cache, imports};
 use near_primitives::config::VMConfig;
 use near_primitives::contract::ContractCode;
 use near_primitives::hash::CryptoHash;
 use near_primitives::runtime::fees::RuntimeFeesConfig;
 use near_primitives::types::CompiledContractCache;
 use near_primitives::version::ProtocolVersion;
-use near_vm_errors::{
-    CompilationError, FunctionCallError, MethodResolveError, VMError, VMLogicError, WasmTrap,
-};
+use near_stable_hasher::StableHasher;
+use near_vm_errors::{FunctionCallError, MethodResolveError, VMError, VMLogicError, WasmTrap};
 use near_vm_logic::types::PromiseResult;
 use near_vm_logic::{External, MemoryLike, VMContext, VMLogic, VMOutcome};
 use std::ffi::c_void;
+use std::hash::{Hash, Hasher};
 use std::str;
 use wasmtime::ExternType::Func;
 use wasmtime::{Engine, Limits, Linker, Memory, MemoryType, Module, Store, TrapCode};
@@ -159,9 +160,42 @@ pub(super) fn default_config() -> wasmtime::Config {
     config
 }
 
+pub(crate) fn default_wasmtime_engine() -> Engine {
+    let mut config = default_config();
+    get_engine(&mut config)
+}
+
+/// The version of the `wasmtime` dependency, checked against Cargo.toml by a test.
+pub(crate) const WASMTIME_VERSION: &str = "0.25.0";
+
+/// Everything compiled wasmtime artifacts depend on. They are cached under its hash, so that they
+/// are never loaded by another wasmtime version or with another configuration.
+#[derive(Hash)]
+struct WasmtimeConfig {
+    version: &'static str,
+    lightbeam: bool,
+    /// The wasm features enabled in [`default_config`].
+    features: [bool; 7],
+}
+
+const WASMTIME_CONFIG: WasmtimeConfig = WasmtimeConfig {
+    version: WASMTIME_VERSION,
+    lightbeam: cfg!(feature = "lightbeam"),
+    features: [
+        WASM_FEATURES.threads,
+        WASM_FEATURES.reference_types,
+        WASM_FEATURES.simd,
+        WASM_FEATURES.bulk_memory,
+        WASM_FEATURES.multi_value,
+        WASM_FEATURES.multi_memory,
+        WASM_FEATURES.module_linking,
+    ],
+};
+
 pub(crate) fn wasmtime_vm_hash() -> u64 {
-    // TODO: take into account compiler and engine used to compile the contract.
-    64
+    let mut s = StableHasher::new();
+    WASMTIME_CONFIG.hash(&mut s);
+    s.finish()
 }
 
 pub(crate) struct WasmtimeVM;
@@ -177,7 +211,7 @@ impl crate::runner::VM for WasmtimeVM {
         fees_config: &RuntimeFeesConfig,
         promise_results: &[PromiseResult],
         current_protocol_version: ProtocolVersion,
-        _cache: Option<&dyn CompiledContractCache>,
+        cache: Option<&dyn CompiledContractCache>,
     ) -> (Option<VMOutcome>, Option<VMError>) {
         let _span = tracing::debug_span!(
             target: "vm",
@@ -186,8 +220,7 @@ impl crate::runner::VM for WasmtimeVM {
             %method_name
         )
         .entered();
-        let mut config = default_config();
-        let engine = get_engine(&mut config);
+        let engine = default_wasmtime_engine();
         let store = Store::new(&engine);
         let mut memory = WasmtimeMemory::new(
             &store,
@@ -195,13 +228,16 @@ impl crate::runner::VM for WasmtimeVM {
             wasm_config.limit_config.max_memory_pages,
         )
         .unwrap();
-        let prepared_code = match prepare::prepare_contract(code.code(), wasm_config) {
-            Ok(code) => code,
-            Err(err) => return (None, Some(VMError::from(err))),
-        };
-        let module = match Module::new(&engine, prepared_code) {
-            Ok(module) => module,
-            Err(err) => return (None, Some(err.into_vm_error())),
+        let module = cache::wasmtime_cache::compile_module_cached_wasmtime(
+            code,
+            wasm_config,
+            cache,
+            &CacheOptions::default(),
+            &engine,
+        );
+        let module = match into_vm_result(module) {
+            Ok(it) => it,
+            Err(err) => return (None, Some(err)),
         };
         // Note that we don't clone the actual backing memory, just increase the RC.
         let memory_copy = memory.clone();
@@ -292,16 +328,21 @@ impl crate::runner::VM for WasmtimeVM {
 
     fn precompile(
         &self,
-        _code: &[u8],
-        _code_hash: &CryptoHash,
-        _wasm_config: &VMConfig,
-        _cache: &dyn CompiledContractCache,
+        code: &[u8],
+        code_hash: &CryptoHash,
+        wasm_config: &VMConfig,
+        cache: &dyn CompiledContractCache,
     ) -> Option<VMError> {
-        Some(VMError::FunctionCallError(FunctionCallError::CompilationError(
-            CompilationError::UnsupportedCompiler {
-                msg: "Precompilation not supported in Wasmtime yet".to_string(),
-            },
-        )))
+        let engine = default_wasmtime_engine();
+        let result = crate::cache::wasmtime_cache::compile_and_serialize_wasmtime(
+            code,
+            code_hash,
+            wasm_config,
+            cache,
+            &CacheOptions::default(),
+            &engine,
+        );
+        into_vm_result(result).err()
     }
 
     fn check_compile(&self, code: &Vec<u8>) -> bool {
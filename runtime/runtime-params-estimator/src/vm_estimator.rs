@@ -52,7 +52,7 @@ fn measure_contract(
     let runtime_config = config_store.get_config(PROTOCOL_VERSION).as_ref();
     let vm_config = runtime_config.wasm_config.clone();
     let start = GasCost::measure(gas_metric);
-    let result = precompile_contract_vm(vm_kind, contract, &vm_config, cache);
+    let result = precompile_contract_vm(vm_kind, contract, &vm_config, cache, false);
     let end = start.elapsed();
     assert!(result.is_ok(), "Compilation failed");
     end.to_gas()
@@ -263,7 +263,7 @@ fn test_many_contracts_call(gas_metric: GasMetric, vm_kind: VMKind) {
     let cache: Option<&dyn CompiledContractCache> = Some(cache_store.as_ref());
     let vm_config = VMConfig::test();
     for contract in &contracts {
-        let result = precompile_contract_vm(vm_kind, contract, &vm_config, cache);
+        let result = precompile_contract_vm(vm_kind, contract, &vm_config, cache, false);
         assert!(result.is_ok());
     }
     let mut fake_external = MockedExternal::new();
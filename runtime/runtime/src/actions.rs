@@ -219,6 +219,13 @@ pub(crate) fn action_function_call(
                 CacheError::SerializationError { hash: _hash } => "Cache serialization error",
                 CacheError::ReadError => "Cache read error",
                 CacheError::WriteError => "Cache write error",
+                CacheError::UnsupportedRecordVersion { .. } => "Cache record version unsupported",
+                CacheError::UnsupportedRecordFormat { .. } => "Cache record format unsupported",
+                CacheError::ChecksumMismatch => "Cache record checksum mismatch",
+                CacheError::MissingCachedArtifact => "Compiled contract missing from cache",
+                CacheError::RecordTooLarge { .. } => "Cache record too large",
+                CacheError::VmHashMismatch { .. } => "Cache record vm hash mismatch",
+                CacheError::CachedCompilationError => "Cache record holds a compilation error",
             };
             return Err(StorageError::StorageInconsistentState(message.to_string()).into());
         }
@@ -1,4 +1,5 @@
 use lru::LruCache;
+use std::collections::HashMap;
 use std::hash::Hash;
 use std::sync::Mutex;
 
@@ -41,6 +42,210 @@ where
     pub fn get(&self, key: &K) -> Option<V> {
         self.inner.lock().unwrap().get(key).cloned()
     }
+
+    /// Returns true if the key is present in the cache, without touching the LRU list.
+    pub fn contains(&self, key: &K) -> bool {
+        self.inner.lock().unwrap().contains(key)
+    }
+
+    /// Removes all elements from the cache.
+    pub fn clear(&self) {
+        self.inner.lock().unwrap().clear();
+    }
+
+    /// Changes the capacity of the cache. If the new capacity is smaller than the number of
+    /// elements, the least recently used elements are evicted.
+    pub fn resize(&self, cap: usize) {
+        self.inner.lock().unwrap().resize(cap);
+    }
+
+    /// Returns the number of elements in the cache.
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().len()
+    }
+
+    /// Returns true if the cache holds no elements.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes and returns the least recently used element, if any.
+    pub fn pop_lru(&self) -> Option<(K, V)> {
+        self.inner.lock().unwrap().pop_lru()
+    }
+}
+
+/// Like `SyncLruCache`, but bounded by the total weight of its elements, e.g. their size in bytes,
+/// rather than by their number. Every element is given a weight when it is inserted.
+///
+/// Keys can be pinned, so that their elements are never evicted. Pinned elements are kept apart
+/// and don't count toward the budget.
+pub struct ByteBudgetCache<K, V> {
+    inner: Mutex<ByteBudgetInner<K, V>>,
+}
+
+struct ByteBudgetInner<K, V> {
+    lru: LruCache<K, (V, usize)>,
+    /// Pinned keys, with their element if present.
+    pinned: HashMap<K, Option<(V, usize)>>,
+    /// Total weight of the elements in `lru`.
+    weight: usize,
+    budget: usize,
+}
+
+impl<K: Hash + Eq, V> ByteBudgetInner<K, V> {
+    fn pop_lru(&mut self) -> Option<(K, V)> {
+        let (key, (value, weight)) = self.lru.pop_lru()?;
+        self.weight -= weight;
+        Some((key, value))
+    }
+
+    fn evict_to_budget(&mut self) {
+        while self.weight > self.budget {
+            self.pop_lru();
+        }
+    }
+}
+
+impl<K, V> ByteBudgetCache<K, V>
+where
+    K: Hash + Eq,
+    V: Clone,
+{
+    /// Creates a new cache whose elements weigh at most `budget` in total.
+    pub fn new(budget: usize) -> Self {
+        Self {
+            inner: Mutex::new(ByteBudgetInner {
+                lru: LruCache::unbounded(),
+                pinned: HashMap::new(),
+                weight: 0,
+                budget,
+            }),
+        }
+    }
+
+    /// Puts a key-value pair of the given weight into the cache, replacing the previous value of
+    /// the key. The least recently used elements are evicted until the total weight fits into the
+    /// budget, which evicts the new element too if it is heavier than the whole budget, unless its
+    /// key is pinned.
+    pub fn put(&self, key: K, value: V, weight: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(pinned) = inner.pinned.get_mut(&key) {
+            *pinned = Some((value, weight));
+            return;
+        }
+        inner.weight += weight;
+        if let Some((_, old_weight)) = inner.lru.put(key, (value, weight)) {
+            inner.weight -= old_weight;
+        }
+        inner.evict_to_budget();
+    }
+
+    /// Returns the value of the key in the cache or None if it is not present in the cache.
+    /// Moves the key to the head of the LRU list if it exists.
+    pub fn get(&self, key: &K) -> Option<V> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(pinned) = inner.pinned.get(key) {
+            return pinned.as_ref().map(|(value, _weight)| value.clone());
+        }
+        inner.lru.get(key).map(|(value, _weight)| value.clone())
+    }
+
+    /// Returns true if the key is present in the cache, without touching the LRU list.
+    pub fn contains(&self, key: &K) -> bool {
+        let inner = self.inner.lock().unwrap();
+        matches!(inner.pinned.get(key), Some(Some(_))) || inner.lru.contains(key)
+    }
+
+    /// Pins the key, so that its element, whether already present or put later, is never evicted
+    /// and doesn't count toward the budget.
+    pub fn pin(&self, key: K) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.pinned.contains_key(&key) {
+            return;
+        }
+        let element = inner.lru.pop(&key);
+        if let Some((_, weight)) = &element {
+            inner.weight -= weight;
+        }
+        inner.pinned.insert(key, element);
+    }
+
+    /// Unpins the key. Its element, if present, becomes the most recently used one, and is
+    /// subject to eviction again.
+    pub fn unpin(&self, key: &K) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some((key, Some((value, weight)))) = inner.pinned.remove_entry(key) {
+            inner.weight += weight;
+            inner.lru.put(key, (value, weight));
+            inner.evict_to_budget();
+        }
+    }
+
+    /// Returns true if the key is pinned, whether or not its element is present.
+    pub fn is_pinned(&self, key: &K) -> bool {
+        self.inner.lock().unwrap().pinned.contains_key(key)
+    }
+
+    /// Removes and returns the least recently used unpinned element, if any.
+    pub fn pop_lru(&self) -> Option<(K, V)> {
+        self.inner.lock().unwrap().pop_lru()
+    }
+
+    /// Removes all elements from the cache, including pinned ones. The keys stay pinned.
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.lru.clear();
+        inner.pinned.values_mut().for_each(|element| *element = None);
+        inner.weight = 0;
+    }
+
+    /// Returns the number of unpinned elements in the cache, which are the ones bounded by the
+    /// budget.
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().lru.len()
+    }
+
+    /// Returns true if the cache holds no unpinned elements.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the total weight of the unpinned elements in the cache.
+    pub fn weight(&self) -> usize {
+        self.inner.lock().unwrap().weight
+    }
+
+    /// Changes the budget of the cache, evicting the least recently used elements if the current
+    /// ones don't fit into it.
+    pub fn set_budget(&self, budget: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.budget = budget;
+        inner.evict_to_budget();
+    }
+}
+
+impl<K, V> ByteBudgetCache<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    /// Returns all elements with their weights, pinned ones first, then the others from the least
+    /// to the most recently used, so that putting them into an empty cache in this order
+    /// reproduces the LRU list.
+    pub fn entries(&self) -> Vec<(K, V, usize)> {
+        let inner = self.inner.lock().unwrap();
+        let pinned = inner.pinned.iter().filter_map(|(key, element)| {
+            element.as_ref().map(|(value, weight)| (key.clone(), value.clone(), *weight))
+        });
+        let mut unpinned: Vec<_> = inner
+            .lru
+            .iter()
+            .map(|(key, (value, weight))| (key.clone(), value.clone(), *weight))
+            .collect();
+        unpinned.reverse();
+        pinned.chain(unpinned).collect()
+    }
 }
 
 #[cfg(test)]
@@ -56,4 +261,84 @@ mod tests {
         assert_eq!(cache.get(&123u64), Some(vec![123u64, 123]));
         assert_eq!(cache.get(&0u64), None);
     }
+
+    #[test]
+    fn test_resize() {
+        let cache = SyncLruCache::<u64, u64>::new(3);
+        for key in 0..3u64 {
+            cache.put(key, key);
+        }
+        cache.resize(2);
+        assert!(!cache.contains(&0u64));
+        assert!(cache.contains(&1u64));
+        assert!(cache.contains(&2u64));
+    }
+
+    #[test]
+    fn test_pop_lru() {
+        let cache = SyncLruCache::<u64, u64>::new(3);
+        for key in 0..3u64 {
+            cache.put(key, key);
+        }
+        cache.get(&0u64);
+        assert_eq!(cache.pop_lru(), Some((1, 1)));
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.is_empty());
+    }
+
+    #[test]
+    fn test_byte_budget() {
+        let cache = ByteBudgetCache::<u64, u64>::new(100);
+        cache.put(0, 0, 40);
+        cache.put(1, 1, 40);
+        cache.get(&0u64);
+        cache.put(2, 2, 40);
+        assert!(cache.contains(&0u64));
+        assert!(!cache.contains(&1u64));
+        assert_eq!(cache.weight(), 80);
+
+        // Replacing a value replaces its weight.
+        cache.put(2, 2, 10);
+        assert_eq!(cache.weight(), 50);
+
+        // Elements heavier than the budget are not kept.
+        cache.put(3, 3, 101);
+        assert!(cache.is_empty());
+        assert_eq!(cache.weight(), 0);
+    }
+
+    #[test]
+    fn test_byte_budget_pin() {
+        let cache = ByteBudgetCache::<u64, u64>::new(100);
+        cache.put(0, 0, 40);
+        cache.pin(0);
+        cache.pin(1);
+        assert_eq!(cache.weight(), 0);
+
+        // Pinned elements survive eviction, even if heavier than the budget.
+        cache.put(1, 1, 101);
+        for key in 2..10 {
+            cache.put(key, key, 40);
+        }
+        assert_eq!(cache.get(&0), Some(0));
+        assert_eq!(cache.get(&1), Some(1));
+        assert_eq!(cache.len(), 2);
+
+        // Unpinned elements count toward the budget again.
+        cache.unpin(&0);
+        assert!(!cache.is_pinned(&0));
+        assert!(!cache.contains(&8));
+        assert_eq!(cache.weight(), 80);
+    }
+
+    #[test]
+    fn test_byte_budget_entries() {
+        let cache = ByteBudgetCache::<u64, u64>::new(100);
+        cache.put(0, 0, 10);
+        cache.put(1, 1, 20);
+        cache.put(2, 2, 30);
+        cache.pin(2);
+        cache.get(&0);
+        assert_eq!(cache.entries(), vec![(2, 2, 30), (1, 1, 20), (0, 0, 10)]);
+    }
 }